@@ -70,6 +70,22 @@ pub fn clidef(version: &'static str, appname: &'static str) -> Command {
                         .help("Set output directory for the initramfs build")
                         .default_value("./build"),
                 )
+                .arg(
+                    Arg::new("modules-compression")
+                        .short('z')
+                        .long("modules-compression")
+                        .value_name("TYPE")
+                        .value_parser(["keep", "none", "zstd", "gzip"])
+                        .help("Recompress kernel modules, or decompress them with \"none\"")
+                        .default_value("keep"),
+                )
+                .arg(
+                    Arg::new("fstab")
+                        .short('t')
+                        .long("fstab")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Add the root and \"x-initrd.mount\" disks from /etc/fstab of the root filesystem"),
+                )
                 .arg(Arg::new("file").short('f').long("file").help("Output file.").default_value("./initramfs-microhop.zst")),
         )
         .disable_version_flag(true)
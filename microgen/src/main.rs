@@ -7,7 +7,8 @@ use clap::ArgMatches;
 use colored::Colorize;
 use kmoddep::{kerman::KernelInfo, modinfo::lsmod};
 use rdgen::IrfsGen;
-use std::{error::Error, io, path::PathBuf};
+use std::{error::Error, fs, io, path::PathBuf};
+use syslib::compress::Compression;
 
 static VERSION: &str = "0.1.0";
 static APPNAME: &str = "microgen";
@@ -71,7 +72,13 @@ fn run_new(params: &ArgMatches) -> Result<(), Box<dyn Error>> {
             }
         }
     } else if let Some(profile) = profile {
-        let cfg = profile::cfg::get_mh_config(Some(profile))?;
+        let root = PathBuf::from(params.get_one::<String>("root").unwrap());
+        let mut cfg = profile::cfg::get_mh_config_with_root(Some(profile), &root)?;
+        if params.get_flag("fstab") {
+            let fstab = root.join("etc/fstab");
+            cfg.add_fstab(&fs::read_to_string(&fstab)?, true)?;
+            println!("Added disks from {:?}", fstab);
+        }
         if let Ok(k_info) = k_info {
             let kfo: KernelInfo;
 
@@ -85,8 +92,10 @@ fn run_new(params: &ArgMatches) -> Result<(), Box<dyn Error>> {
             IrfsGen::generate(
                 &kfo,
                 cfg,
+                PathBuf::from(params.get_one::<String>("root").unwrap()),
                 PathBuf::from(params.get_one::<String>("output").unwrap()),
                 PathBuf::from(params.get_one::<String>("file").unwrap()),
+                Compression::from_name(params.get_one::<String>("modules-compression").unwrap()),
             )?;
         }
     } else {
@@ -1,5 +1,6 @@
+use colored::Colorize;
 use kmoddep::kerman::KernelInfo;
-use profile::cfg::MhConfig;
+use profile::cfg::{MhConfDisk, MhConfig, RESCUE_BUILTIN};
 use std::{
     collections::HashSet,
     env,
@@ -8,6 +9,10 @@ use std::{
     os::unix::fs::{symlink, PermissionsExt},
     path::{Path, PathBuf},
 };
+use syslib::{
+    compress::{self, Compression},
+    lvm, md,
+};
 
 use crate::rdpack;
 
@@ -29,6 +34,9 @@ pub struct IrfsGen {
     /// Profile (config)
     cfg: MhConfig,
 
+    /// Root filesystem of the target system
+    root: PathBuf,
+
     /// Destination where initramfs is going to be generated
     dst: PathBuf,
 
@@ -40,21 +48,41 @@ pub struct IrfsGen {
 
     /// Main modules
     _kmod_m: Vec<String>,
+
+    /// Compression of the kernel modules in the initramfs, or keep it as is
+    kmod_compression: Option<Compression>,
 }
 
 impl IrfsGen {
-    pub fn generate(kinfo: &KernelInfo, cfg: MhConfig, dst: PathBuf, fname: PathBuf) -> Result<(), Error> {
+    pub fn generate(
+        kinfo: &KernelInfo, cfg: MhConfig, root: PathBuf, dst: PathBuf, fname: PathBuf, kmod_compression: Option<Compression>,
+    ) -> Result<(), Error> {
         if !dst.exists() {
             fs::create_dir_all(&dst)?;
         } else {
             return Err(Error::new(InvalidData, format!("Given destination path {:?} already exists", dst)));
         }
 
-        let mut irfsg = IrfsGen { kinfo: kinfo.to_owned(), cfg, dst, dst_fn: fname, _kmod_d: vec![], _kmod_m: vec![] };
+        let mut irfsg = IrfsGen {
+            kinfo: kinfo.to_owned(),
+            cfg,
+            root,
+            dst,
+            dst_fn: fname,
+            _kmod_d: vec![],
+            _kmod_m: vec![],
+            kmod_compression,
+        };
 
         let kroot = irfsg.create_ramfs_dirs()?;
         irfsg.setup_microhop()?;
+        irfsg.setup_rescue()?;
+        irfsg.setup_crypt()?;
+        irfsg.copy_modprobe_d()?;
         irfsg.copy_kernel_modules(kroot.as_str())?;
+        irfsg.copy_hw_modules(kroot.as_str())?;
+        irfsg.copy_raid_modules(kroot.as_str())?;
+        irfsg.write_kmod_index(kroot.as_str())?;
         irfsg.write_boot_config()?;
         irfsg.pack()?;
 
@@ -78,6 +106,74 @@ impl IrfsGen {
         Ok(())
     }
 
+    /// Copy rescue shell from the target system, if configured
+    fn setup_rescue(&self) -> Result<(), Error> {
+        let Some(shell) = self.cfg.get_rescue().filter(|s| *s != RESCUE_BUILTIN) else {
+            return Ok(());
+        };
+
+        let src = self.root.join(shell.trim_start_matches('/'));
+        if !src.is_file() {
+            println!("Rescue shell {:?} was not found, the built-in rescue prompt will be used instead", src);
+            return Ok(());
+        }
+
+        let dst = self.dst.join(shell.trim_start_matches('/'));
+        fs::create_dir_all(dst.parent().unwrap())?;
+        fs::copy(src, &dst)?;
+        println!("Rescue shell {} is added. It should be statically linked (e.g. busybox)", shell);
+
+        Ok(())
+    }
+
+    /// Get keyfiles, which are embedded into the initramfs, i.e. not on a key device
+    fn get_keyfiles(&self) -> Vec<&str> {
+        self.cfg.get_crypt().values().filter(|c| c.get_keydevice().is_none()).filter_map(|c| c.get_keyfile()).collect()
+    }
+
+    /// Copy keyfiles of the encrypted devices from the target system.
+    /// Those not found are skipped, as the passphrase is asked then.
+    fn setup_crypt(&self) -> Result<(), Error> {
+        for kf in self.get_keyfiles() {
+            let src = self.root.join(kf.trim_start_matches('/'));
+            if !src.is_file() {
+                println!("Keyfile {:?} was not found, the passphrase will be asked instead", src);
+                continue;
+            }
+
+            let dst = self.dst.join(kf.trim_start_matches('/'));
+            fs::create_dir_all(dst.parent().unwrap())?;
+            fs::copy(src, &dst)?;
+            fs::set_permissions(&dst, fs::Permissions::from_mode(0o400))?;
+            println!(
+                "{} keyfile {} is embedded into the initramfs. Anyone who can read the initramfs can unlock the device!",
+                "Warning:".bright_yellow(),
+                kf
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Copy modprobe.d configuration from the target system
+    fn copy_modprobe_d(&self) -> Result<(), Error> {
+        for d in ["etc/modprobe.d", "usr/lib/modprobe.d", "lib/modprobe.d"] {
+            let src = self.root.join(d);
+            if !src.is_dir() || src.is_symlink() {
+                continue;
+            }
+
+            for e in fs::read_dir(&src)?.flatten() {
+                if e.path().is_file() && e.file_name().to_string_lossy().ends_with(".conf") {
+                    fs::create_dir_all(self.dst.join(d))?;
+                    fs::copy(e.path(), self.dst.join(d).join(e.file_name()))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Create directories for the ramfs.
     fn create_ramfs_dirs(&self) -> Result<String, Error> {
         let kroot = format!("lib/modules/{}", self.kinfo.get_kernel_path().as_path().file_name().unwrap().to_str().unwrap());
@@ -87,31 +183,176 @@ impl IrfsGen {
         Ok(kroot)
     }
 
+    /// Get modules, which are required by the configured storage, even if they are not listed in the profile
+    fn get_implied_modules(&self) -> Vec<String> {
+        let mut mods: Vec<&str> = Vec::default();
+        let devices = self
+            .cfg
+            .get_disks()
+            .unwrap_or_default()
+            .iter()
+            .map(|d| d.get_device().to_string())
+            .chain(self.cfg.get_crypt().values().map(|c| c.get_device().to_string()))
+            .chain(self.cfg.get_verity().values().flat_map(|v| [v.get_device().to_string(), v.get_hashdevice().to_string()]))
+            .collect::<Vec<String>>();
+
+        if devices.iter().any(|d| lvm::parse_lv_ref(d).is_some()) {
+            mods.push("dm_mod");
+        }
+
+        if !self.cfg.get_crypt().is_empty() {
+            mods.extend(["dm_mod", "dm_crypt", "xts"]);
+        }
+
+        if !self.cfg.get_raid().is_empty() {
+            mods.push("md_mod");
+        }
+
+        if !self.cfg.get_verity().is_empty() {
+            mods.extend(["dm_mod", "dm_verity"]);
+        }
+
+        if self.cfg.get_disks().unwrap_or_default().iter().any(|d| d.get_overlay().is_some()) {
+            mods.push("overlay");
+        }
+
+        if self.cfg.get_disks().unwrap_or_default().iter().any(|d| d.get_image().is_some()) {
+            mods.push("loop");
+        }
+
+        if self.cfg.get_disks().unwrap_or_default().iter().any(|d| d.is_nfs()) {
+            mods.extend(["nfs", "nfsv3", "nfsv4"]);
+        }
+
+        // Packet sockets of the DHCP client
+        if self.cfg.get_network().values().any(|n| n.is_dhcp()) {
+            mods.push("af_packet");
+        }
+
+        mods.into_iter().map(|m| m.to_string()).collect()
+    }
+
     /// This will find what modules are needed in the source kernel and will copy to the target only those
     fn copy_kernel_modules(&mut self, kroot: &str) -> Result<(), Error> {
-        // First get only main modules, and then get dependencies for them
+        // First get main modules, and then get dependencies for them.
+        // A main module can be also a dependency of another one.
         for (kmod, kmod_deps) in self.kinfo.get_deps_for(
             &self
                 .cfg
                 .get_modules()
-                .iter()
-                .filter(|e| !self.kinfo.is_dep(e))
-                .collect::<HashSet<_>>()
                 .into_iter()
-                .cloned()
+                .chain(self.get_implied_modules())
+                .collect::<HashSet<String>>()
+                .into_iter()
                 .collect::<Vec<String>>(),
         ) {
             self._copy_kmod(&kmod, kroot)?;
+            self._kmod_d.retain(|kd| kd != &kmod);
             self._kmod_m.push(kmod);
-            if !kmod_deps.is_empty() {
-                for kd in kmod_deps {
+            for kd in kmod_deps {
+                if !self._kmod_m.contains(&kd) && !self._kmod_d.contains(&kd) {
                     self._copy_kmod(kd.as_str(), kroot)?;
-                    if !self._kmod_m.contains(&kd) {
-                        self._kmod_d.push(kd);
-                    }
+                    self._kmod_d.push(kd);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy all drivers of the autodetected subsystems, if hardware autodetection is enabled.
+    /// They are not listed in the boot config, but only loaded if the hardware is present.
+    fn copy_hw_modules(&mut self, kroot: &str) -> Result<(), Error> {
+        if !self.cfg.get_autodetect() {
+            return Ok(());
+        }
+
+        let subsystems = self.cfg.get_autodetect_subsystems();
+        let hw_mods = self
+            .kinfo
+            .get_disk_modules()
+            .into_iter()
+            .filter(|m| subsystems.iter().any(|s| m.contains(&format!("drivers/{}/", s))))
+            .map(|m| Path::new(&m).file_name().unwrap().to_str().unwrap().split('.').next().unwrap().to_string())
+            .collect::<Vec<String>>();
+
+        for (kmod, kmod_deps) in self.kinfo.get_deps_for(&hw_mods) {
+            for km in [kmod].into_iter().chain(kmod_deps) {
+                if !self._kmod_m.contains(&km) && !self._kmod_d.contains(&km) {
+                    self._copy_kmod(&km, kroot)?;
+                    self._kmod_d.push(km);
+                }
+            }
+        }
+        println!("Added {} drivers for hardware autodetection", hw_mods.len());
+
+        Ok(())
+    }
+
+    /// Copy RAID personalities, if any arrays are configured.
+    /// Their levels are known only from the members at boot, so they are loaded only when needed.
+    fn copy_raid_modules(&mut self, kroot: &str) -> Result<(), Error> {
+        if self.cfg.get_raid().is_empty() {
+            return Ok(());
+        }
+
+        let personalities = md::MD_PERSONALITIES.iter().map(|m| m.to_string()).collect::<Vec<String>>();
+        for (kmod, kmod_deps) in self.kinfo.get_deps_for(&personalities) {
+            for km in [kmod].into_iter().chain(kmod_deps) {
+                if !self._kmod_m.contains(&km) && !self._kmod_d.contains(&km) {
+                    self._copy_kmod(&km, kroot)?;
+                    self._kmod_d.push(km);
                 }
             }
         }
+
+        Ok(())
+    }
+
+    /// Write modules index (modules.dep, modules.alias, modules.builtin) for the copied modules only,
+    /// so microhop can resolve modules by their exact names and aliases.
+    fn write_kmod_index(&self, kroot: &str) -> Result<(), Error> {
+        let ksrc = self.kinfo.get_kernel_path();
+        let kdst = self.dst.join(kroot);
+        let copied = self._kmod_m.iter().chain(self._kmod_d.iter()).collect::<HashSet<&String>>();
+        let modname = |p: &str| {
+            Path::new(p)
+                .file_name()
+                .unwrap_or_default()
+                .to_str()
+                .unwrap_or_default()
+                .split('.')
+                .next()
+                .unwrap_or_default()
+                .replace('-', "_")
+        };
+        let names = copied.iter().map(|p| modname(p)).collect::<HashSet<String>>();
+
+        // Dependencies of the copied modules
+        if let Ok(data) = fs::read_to_string(ksrc.join("modules.dep")) {
+            let mut fp = BufWriter::new(File::create(kdst.join("modules.dep"))?);
+            for (m, deps) in
+                data.lines().filter_map(|l| l.split_once(':')).filter(|(m, _)| copied.contains(&m.trim().to_string()))
+            {
+                let deps = deps.split_whitespace().map(|d| self.get_kmod_dst(d)).collect::<Vec<String>>();
+                writeln!(fp, "{}:{}", self.get_kmod_dst(m.trim()), deps.iter().map(|d| format!(" {}", d)).collect::<String>())?;
+            }
+            fp.flush()?;
+        }
+
+        // Aliases of the copied modules
+        if let Ok(data) = fs::read_to_string(ksrc.join("modules.alias")) {
+            let mut fp = BufWriter::new(File::create(kdst.join("modules.alias"))?);
+            for l in data.lines().filter(|l| l.split_whitespace().nth(2).is_some_and(|m| names.contains(&modname(m)))) {
+                writeln!(fp, "{}", l)?;
+            }
+            fp.flush()?;
+        }
+
+        // Built-in modules, so they are not reported as missing
+        if ksrc.join("modules.builtin").exists() {
+            fs::copy(ksrc.join("modules.builtin"), kdst.join("modules.builtin"))?;
+        }
+
         Ok(())
     }
 
@@ -121,11 +362,38 @@ impl IrfsGen {
         let mdst = self.dst.join(kroot).join(kmod);
 
         fs::create_dir_all(mdst.as_path().parent().unwrap())?;
-        fs::copy(msrc, mdst)?;
+        match self.kmod_compression {
+            Some(c) => {
+                compress::recompress(&msrc, &mdst, c)?;
+            }
+            None => {
+                fs::copy(msrc, mdst)?;
+            }
+        }
 
         Ok(())
     }
 
+    /// Get path of a kernel module in the initramfs, which changes if it is recompressed
+    fn get_kmod_dst(&self, kmod: &str) -> String {
+        match self.kmod_compression {
+            Some(c) => compress::with_compression(Path::new(kmod), c).to_string_lossy().to_string(),
+            None => kmod.to_string(),
+        }
+    }
+
+    /// Read the root hash from the output of "veritysetup format" or from its root hash file
+    fn read_roothash(p: &str) -> Result<String, Error> {
+        let data = fs::read_to_string(p)?;
+        let roothash = data.lines().find_map(|l| l.strip_prefix("Root hash:")).unwrap_or(data.as_str()).trim().to_lowercase();
+
+        if roothash.is_empty() || !roothash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::new(InvalidData, format!("No root hash found in {}", p)));
+        }
+
+        Ok(roothash)
+    }
+
     /// Write boot config
     fn write_boot_config(&self) -> Result<(), Error> {
         let f = File::create(self.dst.join("etc/microhop.conf"))?;
@@ -134,27 +402,125 @@ impl IrfsGen {
         // Blinkenlichten :)
         writeln!(fp, "{}\n", BLINKENLICHTEN)?;
 
-        // Write modules in the following order:
-        //   1. First dependencies
-        //   2. Main modules
+        // Write main modules only, dependencies are resolved by microhop from modules.dep
         writeln!(
             fp,
             "modules:\n{}\n",
-            self._kmod_d
+            self._kmod_m
                 .iter()
-                .chain(self._kmod_m.iter())
-                .map(|i| format!("  - {}", Path::new(i).file_stem().unwrap().to_str().unwrap().split('.').next().unwrap()))
+                .map(|i| Path::new(i).file_stem().unwrap().to_str().unwrap().split('.').next().unwrap())
+                .map(|m| match self.cfg.get_module_params(m) {
+                    Some(params) => format!("  - name: {}\n    params: {:?}", m, params),
+                    None => format!("  - {}", m),
+                })
                 .collect::<Vec<String>>()
                 .join("\n")
         )?;
 
         // Write disks configuration
+        // Several mounts of the same device are listed under it
         writeln!(fp, "disks:")?;
-        for d in self.cfg.get_disks()? {
-            writeln!(fp, "  {}: {},{},{}", d.get_device(), d.get_fstype(), d.get_mountpoint(), d.get_mode())?;
+        let disks = self.cfg.get_disks()?;
+        for mounts in disks.chunk_by(|a, b| a.get_device() == b.get_device()) {
+            let opts = |d: &MhConfDisk| format!("{},{},{}", d.get_fstype(), d.get_mountpoint(), d.get_mode());
+            if let [d] = mounts {
+                writeln!(fp, "  {}: {}", d.get_device(), opts(d))?;
+            } else {
+                writeln!(fp, "  {}:", mounts[0].get_device())?;
+                for d in mounts {
+                    writeln!(fp, "    - {}", opts(d))?;
+                }
+            }
         }
         writeln!(fp)?;
 
+        // Write encrypted devices
+        if !self.cfg.get_crypt().is_empty() {
+            writeln!(fp, "crypt:")?;
+            for (name, c) in self.cfg.get_crypt() {
+                writeln!(fp, "  {}:\n    device: {}", name, c.get_device())?;
+                if let Some(kf) = c.get_keyfile() {
+                    writeln!(fp, "    keyfile: {}", kf)?;
+                }
+                if let Some(kd) = c.get_keydevice() {
+                    writeln!(fp, "    keydevice: {}", kd)?;
+                }
+                if let Some(o) = c.get_keyoffset_as_num() {
+                    writeln!(fp, "    keyoffset: {}", o)?;
+                }
+                if let Some(s) = c.get_keysize() {
+                    writeln!(fp, "    keysize: {}", s)?;
+                }
+                if let Some(t) = c.get_keytimeout_as_secs() {
+                    writeln!(fp, "    keytimeout: {}", t)?;
+                }
+            }
+            writeln!(fp)?;
+        }
+
+        // Write software RAID arrays
+        if !self.cfg.get_raid().is_empty() {
+            writeln!(fp, "raid:")?;
+            for (name, r) in self.cfg.get_raid() {
+                writeln!(fp, "  {}:\n    uuid: {}", name, r.get_uuid())?;
+                if let Some(t) = r.get_degraded_as_secs() {
+                    writeln!(fp, "    degraded: {}", t)?;
+                }
+            }
+            writeln!(fp)?;
+        }
+
+        // Write verified devices. The root hash is embedded from the "veritysetup format" output, unless given.
+        if !self.cfg.get_verity().is_empty() {
+            writeln!(fp, "verity:")?;
+            for (name, v) in self.cfg.get_verity() {
+                writeln!(fp, "  {}:\n    device: {}", name, v.get_device())?;
+                if let Some(hd) = v.get_hashdevice_as_str() {
+                    writeln!(fp, "    hashdevice: {}", hd)?;
+                }
+                if let Some(o) = v.get_hashoffset_as_num() {
+                    writeln!(fp, "    hashoffset: {}", o)?;
+                }
+                match (v.get_roothash(), v.get_roothashfile()) {
+                    (Some(rh), _) => writeln!(fp, "    roothash: {:?}", rh)?,
+                    (None, Some(rhf)) => writeln!(fp, "    roothash: {:?}", Self::read_roothash(rhf)?)?,
+                    (None, None) => {
+                        println!("{}", format!("Root hash of {} should be given on the kernel command line", name).yellow())
+                    }
+                }
+                if let Some(salt) = v.get_salt() {
+                    writeln!(fp, "    salt: {:?}", salt)?;
+                }
+            }
+            writeln!(fp)?;
+        }
+
+        // Write A/B root slots
+        if let Some(slots) = self.cfg.get_slots() {
+            writeln!(fp, "slots:")?;
+            for slot in ["a", "b"] {
+                writeln!(fp, "  {}: {}", slot, slots.get_device(slot).unwrap_or_default())?;
+            }
+            writeln!(fp, "  statedevice: {}", slots.get_statedevice())?;
+            if let Some(sf) = slots.get_statefile() {
+                writeln!(fp, "  statefile: {}", sf)?;
+            }
+            writeln!(fp)?;
+        }
+
+        // Write network interfaces
+        if !self.cfg.get_network().is_empty() {
+            writeln!(fp, "network:")?;
+            for (iface, net) in self.cfg.get_network() {
+                writeln!(fp, "  \"{}\":", iface)?;
+                writeln!(fp, "    address: {}", net.get_address())?;
+                if let Some(gw) = net.get_gateway() {
+                    writeln!(fp, "    gateway: {}", gw)?;
+                }
+            }
+            writeln!(fp)?;
+        }
+
         // Transfer other options
         writeln!(fp, "init: {}", self.cfg.get_init_path())?;
         writeln!(fp, "sysroot: {}", self.cfg.get_sysroot_path())?;
@@ -163,6 +529,26 @@ impl IrfsGen {
             writeln!(fp, "log: {}", l)?;
         }
 
+        if let Some(t) = self.cfg.get_rootwait_as_secs() {
+            writeln!(fp, "rootwait: {}", t)?;
+        }
+
+        if let Some(t) = self.cfg.get_rootdelay_as_secs() {
+            writeln!(fp, "rootdelay: {}", t)?;
+        }
+
+        if let Some(r) = self.cfg.get_rescue() {
+            writeln!(fp, "rescue: {}", r)?;
+        }
+
+        if let Some(a) = self.cfg.get_autodetect_as_bool() {
+            writeln!(fp, "autodetect: {}", a)?;
+        }
+
+        if let Some(h) = self.cfg.get_nethandover_as_bool() {
+            writeln!(fp, "nethandover: {}", h)?;
+        }
+
         fp.flush()?;
         Ok(())
     }
@@ -174,7 +560,7 @@ impl IrfsGen {
 
         let out = self.dst_fn.as_os_str().to_str().unwrap();
         println!("Writing the initramfs to {:?}", out);
-        rdpack::pack(out)?;
+        rdpack::pack(out, &self.get_keyfiles())?;
 
         env::set_current_dir(here)?;
         fs::remove_dir_all(&self.dst)?;
@@ -3,7 +3,7 @@ use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::{
     fs::{self, File},
     io::{self, BufWriter, Cursor, Error, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     vec,
 };
 use walkdir::WalkDir;
@@ -13,12 +13,19 @@ use zstd::stream::write::Encoder;
 struct InitRamfsPacker {
     path: String,
     files: Vec<PathBuf>,
+
+    /// Files, which should be readable only by root
+    secrets: Vec<PathBuf>,
 }
 
 impl InitRamfsPacker {
-    fn new(p: &str) -> Self {
+    fn new(p: &str, secrets: &[&str]) -> Self {
         {
-            InitRamfsPacker { path: p.to_string(), files: vec![] }
+            InitRamfsPacker {
+                path: p.to_string(),
+                files: vec![],
+                secrets: secrets.iter().map(|s| Path::new(p).join(s.trim_start_matches('/'))).collect(),
+            }
         }
     }
 
@@ -33,12 +40,15 @@ impl InitRamfsPacker {
     }
 
     /// Build file metadata and information for the CPIO archive
-    fn file_loader(inode: u32, p: &str) -> io::Result<(NewcBuilder, Vec<u8>)> {
+    fn file_loader(inode: u32, p: &str, secret: bool) -> io::Result<(NewcBuilder, Vec<u8>)> {
         let pth = PathBuf::from(p);
         let mut data: Vec<u8> = vec![];
 
         let f_meta = fs::metadata(&pth).unwrap();
         let mut arc_meta = NewcBuilder::new(p).ino(inode).uid(0).gid(f_meta.gid()).mode(f_meta.permissions().mode());
+        if secret {
+            arc_meta = arc_meta.gid(0).mode(0o400);
+        }
 
         if pth.is_symlink() {
             data.extend(fs::read_link(pth).unwrap().to_str().unwrap().as_bytes());
@@ -61,13 +71,11 @@ impl InitRamfsPacker {
         let cur = Cursor::new(all_data);
         let mut out = BufWriter::new(cur);
 
-        let mut inode = 1;
-        for fp in &self.files {
-            let (bdr, data) = InitRamfsPacker::file_loader(inode, fp.to_str().unwrap()).unwrap();
+        for (inode, fp) in (1..).zip(self.files.iter()) {
+            let (bdr, data) = InitRamfsPacker::file_loader(inode, fp.to_str().unwrap(), self.secrets.contains(fp)).unwrap();
             let mut w = bdr.write(&mut out, data.len() as u32);
             w.write_all(&data)?;
             w.finish().unwrap();
-            inode += 1;
         }
 
         let out = trailer(out).unwrap();
@@ -79,7 +87,8 @@ impl InitRamfsPacker {
     }
 }
 
-/// Pack a content of a path into a CPIO archive
-pub fn pack(p: &str) -> Result<(), Error> {
-    InitRamfsPacker::new(".").pack(p)
+/// Pack a content of a path into a CPIO archive.
+/// Secrets are paths of the files, which should be readable only by root.
+pub fn pack(p: &str, secrets: &[&str]) -> Result<(), Error> {
+    InitRamfsPacker::new(".", secrets).pack(p)
 }
@@ -1,14 +1,43 @@
-use indexmap::IndexMap;
+use crate::fstab;
+use indexmap::{map::Entry, IndexMap};
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::File,
+    fs::{self, File},
     io::{BufReader, Error, ErrorKind},
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 /// Path to the config. It should be always only there.
 static CFG_PATH: &str = "/etc/microhop.conf";
 
+/// Default timeout in seconds to wait for the disks to appear
+const ROOTWAIT_DEFAULT: i64 = 10;
+
+/// Rescue mode with the built-in prompt instead of a shell
+pub static RESCUE_BUILTIN: &str = "builtin";
+
+/// Driver subsystems (in the kernel/drivers directory), which are loaded by the hardware autodetection
+pub const AUTODETECT_SUBSYSTEMS: &[&str] = &["block", "scsi", "ata", "nvme", "virtio", "usb/storage", "usb/host", "mmc"];
+
+/// Driver subsystem of the network interfaces, which is autodetected only if the network is configured
+pub static AUTODETECT_NET_SUBSYSTEM: &str = "net";
+
+/// Network configuration of any Ethernet interface, which gets connected first
+pub static NET_ANY: &str = "*";
+
+/// Address of the interface, which is obtained via DHCP
+pub static NET_DHCP: &str = "dhcp";
+
+/// Prefix of the Microhop-specific disk options, which are not passed to mount
+pub static MH_DISK_OPT_PREFIX: &str = "x-mh.";
+
+/// Filesystem types of the network disks, which are mounted by the in-kernel NFS client
+pub const NFS_FSTYPES: &[&str] = &["nfs", "nfs4"];
+
+/// Upper layer of the overlay root on tmpfs
+pub static OVERLAY_TMPFS: &str = "tmpfs";
+
 /// Disk description
 pub struct MhConfDisk {
     device: String,
@@ -42,16 +71,371 @@ impl MhConfDisk {
     pub fn get_mode(&self) -> &str {
         &self.mode
     }
+
+    /// Returns true if the disk is a network share "server:/export", mounted via NFS
+    pub fn is_nfs(&self) -> bool {
+        NFS_FSTYPES.contains(&self.fstype.as_str())
+    }
+
+    /// Get a Microhop-specific option `x-mh.<name>[=<value>]` of the mode.
+    /// Returns an empty value for an option without a value.
+    pub fn get_mh_option(&self, name: &str) -> Option<&str> {
+        self.mode.split(',').find_map(|o| {
+            let (k, v) = o.split_once('=').unwrap_or((o, ""));
+            (k.strip_prefix(MH_DISK_OPT_PREFIX)? == name).then_some(v)
+        })
+    }
+
+    /// Get path to the filesystem image (`x-mh.image=<path>`) on the disk, if the image is mounted instead of the disk
+    pub fn get_image(&self) -> Option<&str> {
+        self.get_mh_option("image").filter(|v| !v.is_empty())
+    }
+
+    /// Get the upper layer of the overlay (`x-mh.overlay[=<device>]`), if the disk is mounted as an overlay:
+    /// "tmpfs" or a device (UUID, label or path in /dev)
+    pub fn get_overlay(&self) -> Option<&str> {
+        self.get_mh_option("overlay").map(|v| if v.is_empty() { OVERLAY_TMPFS } else { v })
+    }
+}
+
+/// Kernel module, either just a name or a name with parameters:
+///
+///   - name: nvme_core
+///     params: "default_ps_max_latency_us=0"
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum MhConfModule {
+    Name(String),
+    Params { name: String, params: String },
+}
+
+impl MhConfModule {
+    /// Return module name
+    pub fn get_name(&self) -> &str {
+        match self {
+            MhConfModule::Name(name) | MhConfModule::Params { name, .. } => name,
+        }
+    }
+
+    /// Return module parameters
+    pub fn get_params(&self) -> Option<&str> {
+        match self {
+            MhConfModule::Name(_) => None,
+            MhConfModule::Params { params, .. } => Some(params),
+        }
+    }
+}
+
+/// Encrypted (LUKS2) device, which is mapped to /dev/mapper/<name>:
+///
+///   crypt:
+///     cryptroot:
+///       device: 0f7a8e3c-...
+///       keyfile: /etc/cryptroot.key
+///
+/// The keyfile is embedded into the initramfs, unless a key device is given:
+/// then it is a path on that device, or the device itself, if there is no keyfile.
+/// Without a key, the passphrase is asked on the console.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MhConfCrypt {
+    device: String,
+    keyfile: Option<String>,
+    keydevice: Option<String>,
+    keyoffset: Option<u64>,
+    keysize: Option<u64>,
+    keytimeout: Option<i64>,
+}
+
+impl MhConfCrypt {
+    /// Return the encrypted device: UUID, label or path in /dev
+    pub fn get_device(&self) -> &str {
+        &self.device
+    }
+
+    /// Return path to the keyfile, if any
+    pub fn get_keyfile(&self) -> Option<&str> {
+        self.keyfile.as_deref()
+    }
+
+    /// Return the device with the key (e.g. an USB stick): UUID, label or path in /dev
+    pub fn get_keydevice(&self) -> Option<&str> {
+        self.keydevice.as_deref()
+    }
+
+    /// Return offset of the key in the keyfile
+    pub fn get_keyoffset(&self) -> u64 {
+        self.keyoffset.unwrap_or_default()
+    }
+
+    pub fn get_keyoffset_as_num(&self) -> &Option<u64> {
+        &self.keyoffset
+    }
+
+    /// Return size of the key in the keyfile.
+    /// Returns `None` if the key is the whole keyfile.
+    pub fn get_keysize(&self) -> Option<u64> {
+        self.keysize
+    }
+
+    /// Get timeout to wait for the key device, before the passphrase is asked.
+    /// Returns `None` if it should be waited forever.
+    pub fn get_keytimeout(&self) -> Option<Duration> {
+        match self.keytimeout.unwrap_or(ROOTWAIT_DEFAULT) {
+            n if n < 0 => None,
+            n => Some(Duration::from_secs(n as u64)),
+        }
+    }
+
+    pub fn get_keytimeout_as_secs(&self) -> &Option<i64> {
+        &self.keytimeout
+    }
+}
+
+/// Software RAID (md) array, which is assembled as /dev/md/<name>:
+///
+///   raid:
+///     root:
+///       uuid: 5d3a6c7e:1f0b2a4d:9c8e7f6a:3b2c1d0e
+///       degraded: 30
+///
+/// The array is assembled degraded, if not all of its members appear within the given seconds.
+/// Waiting for the disks is extended to this timeout, if it is longer than `rootwait`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MhConfRaid {
+    uuid: String,
+    degraded: Option<i64>,
+}
+
+impl MhConfRaid {
+    /// Return UUID of the array, in any form (mdadm or blkid)
+    pub fn get_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    /// Return timeout, after which the array is assembled degraded.
+    /// Returns `None` if the array is never assembled degraded (default).
+    pub fn get_degraded(&self) -> Option<Duration> {
+        match self.degraded.unwrap_or(-1) {
+            n if n < 0 => None,
+            n => Some(Duration::from_secs(n as u64)),
+        }
+    }
+
+    pub fn get_degraded_as_secs(&self) -> &Option<i64> {
+        &self.degraded
+    }
+}
+
+/// Verified read-only (dm-verity) device, which is mapped to /dev/mapper/<name>:
+///
+///   verity:
+///     root:
+///       device: /dev/vda2
+///       hashdevice: /dev/vda3
+///       roothash: 4392712ba01368efdf14b05c76f9e4df0d53664630b5d48632ed17a137f39076
+///
+/// Without a hash device, the hash tree is appended to the data device at the hash offset (in bytes).
+/// The salt is given only for the hash trees without a superblock.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MhConfVerity {
+    device: String,
+    hashdevice: Option<String>,
+    hashoffset: Option<u64>,
+    roothash: Option<String>,
+    roothashfile: Option<String>,
+    salt: Option<String>,
+}
+
+impl MhConfVerity {
+    /// Return the data device: UUID, label or path in /dev
+    pub fn get_device(&self) -> &str {
+        &self.device
+    }
+
+    /// Return the device with the hash tree, which is the data device by default
+    pub fn get_hashdevice(&self) -> &str {
+        self.hashdevice.as_deref().unwrap_or(&self.device)
+    }
+
+    pub fn get_hashdevice_as_str(&self) -> &Option<String> {
+        &self.hashdevice
+    }
+
+    /// Return offset of the hash tree on the hash device in bytes
+    pub fn get_hashoffset(&self) -> u64 {
+        self.hashoffset.unwrap_or_default()
+    }
+
+    pub fn get_hashoffset_as_num(&self) -> &Option<u64> {
+        &self.hashoffset
+    }
+
+    /// Return the root hash as a hex string
+    pub fn get_roothash(&self) -> Option<&str> {
+        self.roothash.as_deref()
+    }
+
+    /// Set the root hash
+    pub fn set_roothash(&mut self, roothash: &str) {
+        self.roothash = Some(roothash.trim().to_lowercase());
+    }
+
+    /// Return path to the output of "veritysetup format" with the root hash, if any
+    pub fn get_roothashfile(&self) -> Option<&str> {
+        self.roothashfile.as_deref()
+    }
+
+    /// Return salt of the hash tree without a superblock as a hex string, "-" for none
+    pub fn get_salt(&self) -> Option<&str> {
+        self.salt.as_deref()
+    }
+}
+
+/// A/B root slots with their boot state:
+///
+///   slots:
+///     a: /dev/vda2
+///     b: /dev/vda3
+///     statedevice: ESP
+///     statefile: /microhop/slot.state
+///
+/// The state is a file on the state device, or an env block at the start of the device without a file.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MhConfSlots {
+    a: String,
+    b: String,
+    statedevice: String,
+    statefile: Option<String>,
+}
+
+impl MhConfSlots {
+    /// Return root device of the slot "a" or "b": UUID, label or path in /dev
+    pub fn get_device(&self, slot: &str) -> Option<&str> {
+        match slot {
+            "a" => Some(&self.a),
+            "b" => Some(&self.b),
+            _ => None,
+        }
+    }
+
+    /// Return the device with the boot state: UUID, label or path in /dev
+    pub fn get_statedevice(&self) -> &str {
+        &self.statedevice
+    }
+
+    /// Return path to the state file on the state device, if any
+    pub fn get_statefile(&self) -> Option<&str> {
+        self.statefile.as_deref()
+    }
+}
+
+/// Network interface:
+///
+///   network:
+///     eth0:
+///       address: 192.168.1.10/24
+///       gateway: 192.168.1.1
+///
+/// The address is either IPv4 with the netmask length or "dhcp". The interface name "*"
+/// stands for any Ethernet interface, which gets connected first.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MhConfNet {
+    address: String,
+    gateway: Option<String>,
+}
+
+impl MhConfNet {
+    pub fn new(address: &str, gateway: Option<&str>) -> Self {
+        MhConfNet { address: address.to_string(), gateway: gateway.map(|g| g.to_string()) }
+    }
+
+    /// Return the address with the netmask length, e.g. "192.168.1.10/24", or "dhcp"
+    pub fn get_address(&self) -> &str {
+        &self.address
+    }
+
+    /// Returns true if the address is obtained via DHCP
+    pub fn is_dhcp(&self) -> bool {
+        self.address == NET_DHCP
+    }
+
+    /// Return the default gateway, if any
+    pub fn get_gateway(&self) -> Option<&str> {
+        self.gateway.as_deref()
+    }
+}
+
+/// Mounts of a disk device: usually one, or several of the same device, e.g. btrfs subvolumes:
+///
+///   disks:
+///     LABEL=ROOT:
+///       - btrfs,/,rw,subvol=@
+///       - btrfs,/home,rw,subvol=@home
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum MhConfDiskMounts {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl MhConfDiskMounts {
+    /// Return options of all the mounts
+    pub fn get_all(&self) -> Vec<&str> {
+        match self {
+            MhConfDiskMounts::One(opts) => vec![opts],
+            MhConfDiskMounts::Many(opts) => opts.iter().map(|o| o.as_str()).collect(),
+        }
+    }
+
+    /// Add another mount of the same device
+    fn push(&mut self, opts: String) {
+        match self {
+            MhConfDiskMounts::One(first) => *self = MhConfDiskMounts::Many(vec![std::mem::take(first), opts]),
+            MhConfDiskMounts::Many(all) => all.push(opts),
+        }
+    }
+}
+
+/// Filesystem table, which adds the root and "x-initrd.mount" disks, not defined in "disks":
+///
+///   fstab: /etc/fstab
+///
+/// or its entries:
+///
+///   fstab:
+///     - UUID=24e1daee-e09b-4fd5-97f3-dde8aba6ad8a / ext4 defaults 0 1
+///     - LABEL=USR /usr ext4 ro,x-initrd.mount 0 2
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum MhConfFstab {
+    Path(String),
+    Entries(Vec<String>),
 }
 
 /// Main configuration struct
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct MhConfig {
-    modules: Vec<String>,
-    disks: IndexMap<String, String>,
+    modules: Vec<MhConfModule>,
+    #[serde(default)]
+    disks: IndexMap<String, MhConfDiskMounts>,
+    fstab: Option<MhConfFstab>,
+    #[serde(default)]
+    crypt: IndexMap<String, MhConfCrypt>,
+    #[serde(default)]
+    raid: IndexMap<String, MhConfRaid>,
+    #[serde(default)]
+    verity: IndexMap<String, MhConfVerity>,
+    slots: Option<MhConfSlots>,
+    #[serde(default)]
+    network: IndexMap<String, MhConfNet>,
+    nethandover: Option<bool>,
     init: Option<String>,
     sysroot: Option<String>,
     log: Option<String>,
+    rootwait: Option<i64>,
+    rootdelay: Option<u64>,
+    rescue: Option<String>,
+    autodetect: Option<bool>,
 }
 
 impl MhConfig {
@@ -59,14 +443,21 @@ impl MhConfig {
         MhConfig { ..Default::default() }
     }
 
-    /// Return list of modules
-    pub fn get_modules(&self) -> &[String] {
-        &self.modules
+    /// Return names of the modules
+    pub fn get_modules(&self) -> Vec<String> {
+        self.modules.iter().map(|m| m.get_name().to_string()).collect()
+    }
+
+    /// Return parameters of a module, if any
+    pub fn get_module_params(&self, name: &str) -> Option<&str> {
+        let name = name.replace('-', "_");
+        self.modules.iter().find(|m| m.get_name().replace('-', "_") == name).and_then(|m| m.get_params())
     }
 
-    /// Parse disk options, return default
+    /// Parse disk options, return default.
+    /// Everything after the mountpoint is kept as the mounting mode, e.g. "rw" or "ro,subvol=@".
     fn get_disk_opts(&self, opts: &str) -> Result<(String, String, String), Error> {
-        let t = opts.split(',').collect::<Vec<&str>>();
+        let t = opts.splitn(3, ',').collect::<Vec<&str>>();
         match t.len() {
             n if n > 1 && n < 3 => Ok((t[0].to_string(), t[1].to_string(), "rw".to_string())),
             3 => Ok((t[0].to_string(), t[1].to_string(), t[2].to_string())),
@@ -77,7 +468,7 @@ impl MhConfig {
     /// Return disk device description
     pub fn get_disks(&self) -> Result<Vec<MhConfDisk>, Error> {
         let mut d: Vec<MhConfDisk> = Vec::default();
-        for (dev, opt) in &self.disks {
+        for (dev, opt) in self.disks.iter().flat_map(|(dev, m)| m.get_all().into_iter().map(move |o| (dev, o))) {
             let (fstype, path, mode) = self.get_disk_opts(opt)?;
             d.push(MhConfDisk {
                 device: dev.to_string(),
@@ -90,11 +481,147 @@ impl MhConfig {
         Ok(d)
     }
 
+    /// Return the disk, which is mounted as the root filesystem
+    pub fn get_root_disk(&self) -> Result<Option<MhConfDisk>, Error> {
+        Ok(self.get_disks()?.into_iter().find(|d| d.get_mountpoint().trim_end_matches('/').is_empty()))
+    }
+
+    /// Set the disk, which is mounted as the root filesystem.
+    /// Any previously defined root disk is replaced.
+    pub fn set_root_disk(&mut self, device: &str, fstype: &str, mode: &str) -> Result<(), Error> {
+        self.set_disk(device, fstype, "/", mode)
+    }
+
+    /// Set a disk. Any previously defined disk with the same mountpoint is replaced.
+    /// The root disk always goes first. The same device can be mounted at several mountpoints,
+    /// e.g. btrfs subvolumes.
+    pub fn set_disk(&mut self, device: &str, fstype: &str, mountpoint: &str, mode: &str) -> Result<(), Error> {
+        let mpt = mountpoint.trim_end_matches('/');
+        let mut disks =
+            self.get_disks()?.into_iter().filter(|d| d.get_mountpoint().trim_end_matches('/') != mpt).collect::<Vec<_>>();
+        let disk = MhConfDisk {
+            device: device.to_string(),
+            fstype: fstype.to_string(),
+            path: mountpoint.to_string(),
+            mode: mode.to_string(),
+        };
+        if mpt.is_empty() {
+            disks.insert(0, disk);
+        } else {
+            disks.push(disk);
+        }
+
+        // Mounts are grouped by their devices in the order of their first appearance
+        self.disks.clear();
+        for d in disks {
+            let opts = format!("{},{},{}", d.fstype, d.path, d.mode);
+            match self.disks.entry(d.device) {
+                Entry::Vacant(e) => {
+                    e.insert(MhConfDiskMounts::One(opts));
+                }
+                Entry::Occupied(mut e) => e.get_mut().push(opts),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add disks from the filesystem table, unless their mountpoints are already defined.
+    /// Swap and "noauto" entries are skipped. If `initrd_only` is set, only the root
+    /// and the entries with "x-initrd.mount" option are added.
+    pub fn add_fstab(&mut self, data: &str, initrd_only: bool) -> Result<(), Error> {
+        for e in fstab::parse(data).into_iter().filter(|e| e.is_mountable() && (!initrd_only || e.is_initrd())) {
+            if e.get_fstype() == "auto" {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Filesystem type of {} should be given explicitly in the filesystem table", e.get_mountpoint()),
+                ));
+            }
+
+            let mpt = e.get_mountpoint().trim_end_matches('/');
+            if self.get_disks()?.iter().any(|d| d.get_mountpoint().trim_end_matches('/') == mpt) {
+                log::debug!("Disk at {} is already defined, skipping its filesystem table entry", e.get_mountpoint());
+                continue;
+            }
+
+            self.set_disk(&e.get_device(), e.get_fstype(), e.get_mountpoint(), &e.get_mode())?;
+        }
+
+        Ok(())
+    }
+
+    /// Add the root and "x-initrd.mount" disks from the "fstab" section or file of the profile.
+    /// The file is relative to the root filesystem.
+    fn load_fstab(&mut self, root: &Path) -> Result<(), Error> {
+        let data = match &self.fstab {
+            None => return Ok(()),
+            Some(MhConfFstab::Entries(entries)) => entries.join("\n"),
+            Some(MhConfFstab::Path(p)) => {
+                let p = root.join(p.trim_start_matches('/'));
+                fs::read_to_string(&p).map_err(|err| {
+                    Error::new(err.kind(), format!("Unable to read filesystem table at {}: {}", p.display(), err))
+                })?
+            }
+        };
+
+        self.add_fstab(&data, true)
+    }
+
+    /// Return encrypted devices by their mapper names
+    pub fn get_crypt(&self) -> &IndexMap<String, MhConfCrypt> {
+        &self.crypt
+    }
+
+    /// Return software RAID arrays by their names
+    pub fn get_raid(&self) -> &IndexMap<String, MhConfRaid> {
+        &self.raid
+    }
+
+    /// Return verified devices by their mapper names
+    pub fn get_verity(&self) -> &IndexMap<String, MhConfVerity> {
+        &self.verity
+    }
+
+    /// Return A/B root slots, if any
+    pub fn get_slots(&self) -> Option<&MhConfSlots> {
+        self.slots.as_ref()
+    }
+
+    /// Return network interfaces by their names
+    pub fn get_network(&self) -> &IndexMap<String, MhConfNet> {
+        &self.network
+    }
+
+    /// Set configuration of a network interface
+    pub fn set_network(&mut self, iface: &str, net: MhConfNet) {
+        self.network.insert(iface.to_string(), net);
+    }
+
+    /// Remove configuration of all network interfaces
+    pub fn clear_network(&mut self) {
+        self.network.clear();
+    }
+
+    /// Returns true if the network configuration should be kept for the system.
+    /// Otherwise it is torn down before the init starts.
+    pub fn get_nethandover(&self) -> bool {
+        self.nethandover.unwrap_or_default()
+    }
+
+    pub fn get_nethandover_as_bool(&self) -> &Option<bool> {
+        &self.nethandover
+    }
+
     /// Return path to the init app
     pub fn get_init_path(&self) -> String {
         self.init.to_owned().unwrap_or("/sbin/init".to_string())
     }
 
+    /// Set path to the init app
+    pub fn set_init_path(&mut self, init: &str) {
+        self.init = Some(init.to_string());
+    }
+
     /// Get log level
     pub fn get_log_level(&self) -> log::LevelFilter {
         if let Some(l) = &self.log {
@@ -116,18 +643,331 @@ impl MhConfig {
     pub fn get_sysroot_path(&self) -> String {
         self.sysroot.to_owned().unwrap_or("/sysroot".to_string())
     }
+
+    /// Get timeout to wait for the disks to appear.
+    /// Returns `None` if it should be waited forever.
+    pub fn get_rootwait(&self) -> Option<Duration> {
+        match self.rootwait.unwrap_or(ROOTWAIT_DEFAULT) {
+            n if n < 0 => None,
+            n => Some(Duration::from_secs(n as u64)),
+        }
+    }
+
+    pub fn get_rootwait_as_secs(&self) -> &Option<i64> {
+        &self.rootwait
+    }
+
+    /// Get delay before probing the disks
+    pub fn get_rootdelay(&self) -> Duration {
+        Duration::from_secs(self.rootdelay.unwrap_or_default())
+    }
+
+    pub fn get_rootdelay_as_secs(&self) -> &Option<u64> {
+        &self.rootdelay
+    }
+
+    /// Get path to the rescue shell or "builtin" for the built-in rescue prompt.
+    /// Returns `None` if the rescue mode is disabled.
+    pub fn get_rescue(&self) -> Option<&str> {
+        self.rescue.as_deref()
+    }
+
+    /// Returns true if drivers for the present hardware should be detected and loaded
+    pub fn get_autodetect(&self) -> bool {
+        self.autodetect.unwrap_or_default()
+    }
+
+    pub fn get_autodetect_as_bool(&self) -> &Option<bool> {
+        &self.autodetect
+    }
+
+    /// Return driver subsystems, which are loaded by the hardware autodetection.
+    /// Network drivers are only included if the network is configured.
+    pub fn get_autodetect_subsystems(&self) -> Vec<&str> {
+        let mut subsystems = AUTODETECT_SUBSYSTEMS.to_vec();
+        if !self.network.is_empty() {
+            subsystems.push(AUTODETECT_NET_SUBSYSTEM);
+        }
+
+        subsystems
+    }
+
+    /// Override one profile key from a key/value source, such as the kernel command line.
+    ///
+    /// Merge rules:
+    ///   - `init`, `sysroot`, `log`, `rootwait`, `rootdelay`, `autodetect` and `nethandover` replace the profile value
+    ///   - `modules` is a comma-separated list, appended to the profile modules, skipping those already listed (dashes and underscores are the same)
+    ///   - `disk` is `<device>:<fstype>,<mountpoint>[,<mode>]` and replaces a profile disk with the same mountpoint.
+    ///     A device, which is already mounted elsewhere, is refused
+    ///   - `rescue` without a value enables the built-in rescue prompt
+    ///   - `verity.roothash` replaces the root hash of the first verified device
+    pub fn set_override(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        if key == "rescue" {
+            self.rescue = Some(if value.is_empty() { RESCUE_BUILTIN } else { value }.to_string());
+            return Ok(());
+        }
+
+        if value.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("No value for the key \"{}\"", key)));
+        }
+
+        match key {
+            "init" => self.init = Some(value.to_string()),
+            "sysroot" => self.sysroot = Some(value.to_string()),
+            "log" => self.log = Some(value.to_string()),
+            "rootwait" => self.rootwait = Some(Self::parse_num(key, value)?),
+            "rootdelay" => self.rootdelay = Some(Self::parse_num(key, value)?),
+            "autodetect" => self.autodetect = Some(Self::parse_bool(key, value)?),
+            "nethandover" => self.nethandover = Some(Self::parse_bool(key, value)?),
+            "modules" => {
+                // Dashes and underscores are the same in the module names
+                for m in value.split(',').filter(|m| !m.is_empty()) {
+                    if !self.modules.iter().any(|pm| pm.get_name().replace('-', "_") == m.replace('-', "_")) {
+                        self.modules.push(MhConfModule::Name(m.to_string()));
+                    }
+                }
+            }
+            "disk" => {
+                // The device itself may contain colons, but the options never contain them before the first comma
+                let Some((dev, fstype, mpt)) =
+                    value.split_once(',').and_then(|(dt, mpt)| dt.rsplit_once(':').map(|(dev, fst)| (dev, fst, mpt)))
+                else {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("Disk definition is incorrect: {}", value)));
+                };
+                let (_, path, mode) = self.get_disk_opts(&format!("{},{}", fstype, mpt))?;
+                self.set_disk(dev, fstype, &path, &mode)?;
+            }
+            "verity.roothash" => {
+                let Some((_, v)) = self.verity.first_mut() else {
+                    return Err(Error::new(ErrorKind::InvalidInput, "No verified devices are configured"));
+                };
+                v.set_roothash(value);
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidInput, format!("Unknown key \"{}\"", key))),
+        }
+
+        Ok(())
+    }
+
+    /// Parse a numeric value of a key
+    fn parse_num<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, Error> {
+        value
+            .parse::<T>()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, format!("Value of \"{}\" is not a number: {}", key, value)))
+    }
+
+    /// Parse a boolean value of a key
+    fn parse_bool(key: &str, value: &str) -> Result<bool, Error> {
+        match value {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "0" | "false" | "no" | "off" => Ok(false),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("Value of \"{}\" is not a boolean: {}", key, value))),
+        }
+    }
+
+    /// Merge key/value overrides into the profile, as described in `set_override`.
+    /// All valid keys are applied, even if some of them are not.
+    pub fn merge<'a>(&mut self, kv: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<(), Error> {
+        let mut errs: Vec<String> = Vec::default();
+        for (k, v) in kv {
+            if let Err(err) = self.set_override(k, v) {
+                errs.push(err.to_string());
+            }
+        }
+
+        if !errs.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, errs.join("; ")));
+        }
+
+        Ok(())
+    }
 }
 
 /// Get the configuration
 pub fn get_mh_config(p: Option<&str>) -> Result<MhConfig, Error> {
+    get_mh_config_with_root(p, Path::new("/"))
+}
+
+/// Get the configuration of a root filesystem, e.g. an image being prepared.
+/// Files, referred in the configuration, such as the filesystem table, are taken from that root.
+pub fn get_mh_config_with_root(p: Option<&str>, root: &Path) -> Result<MhConfig, Error> {
     let p = Path::new(if let Some(p) = p { p } else { CFG_PATH });
 
     if !p.exists() {
         return Err(Error::new(ErrorKind::NotFound, format!("Configuration file at {} is missing", p.to_str().unwrap())));
     }
 
-    match serde_yaml::from_reader(BufReader::new(File::open(p)?)) {
-        Ok(cfg) => Ok(cfg),
-        Err(err) => Err(Error::new(std::io::ErrorKind::InvalidData, err)),
+    let mut cfg: MhConfig = match serde_yaml::from_reader(BufReader::new(File::open(p)?)) {
+        Ok(cfg) => cfg,
+        Err(err) => return Err(Error::new(std::io::ErrorKind::InvalidData, err)),
+    };
+    cfg.load_fstab(root)?;
+
+    Ok(cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(yaml: &str) -> MhConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn disks(cfg: &MhConfig) -> Vec<(String, String, String, String)> {
+        cfg.get_disks()
+            .unwrap()
+            .iter()
+            .map(|d| {
+                (d.get_device().to_string(), d.get_fstype().to_string(), d.get_mountpoint().to_string(), d.get_mode().to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn override_scalars() {
+        let mut c = cfg("modules: []\ninit: /sbin/init\nsysroot: /sysroot\nlog: quiet\nrootwait: 5\nrootdelay: 1\n");
+        c.merge([("init", "/bin/sh"), ("sysroot", "/mnt"), ("log", "debug"), ("rootwait", "-1"), ("rootdelay", "3")]).unwrap();
+
+        assert_eq!(c.get_init_path(), "/bin/sh");
+        assert_eq!(c.get_sysroot_path(), "/mnt");
+        assert_eq!(c.get_log_level_as_str(), &Some("debug".to_string()));
+        assert_eq!(c.get_rootwait_as_secs(), &Some(-1));
+        assert_eq!(c.get_rootwait(), None);
+        assert_eq!(c.get_rootdelay(), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn override_parse_errors() {
+        let mut c = MhConfig::new();
+        assert_eq!(c.set_override("rootwait", "soon").unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(c.set_override("rootdelay", "-1").unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(c.set_override("autodetect", "maybe").unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(c.set_override("init", "").unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(c.set_override("nosuchkey", "1").unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        c.set_override("autodetect", "off").unwrap();
+        assert!(!c.get_autodetect());
+        c.set_override("nethandover", "yes").unwrap();
+        assert!(c.get_nethandover());
+    }
+
+    #[test]
+    fn override_modules() {
+        let mut c = cfg("modules:\n  - dm-crypt\n  - name: virtio_blk\n    params: foo=1\n");
+        c.set_override("modules", "ext4,dm_crypt,,virtio-blk,ext4").unwrap();
+
+        assert_eq!(c.get_modules(), vec!["dm-crypt", "virtio_blk", "ext4"]);
+        assert_eq!(c.get_module_params("virtio-blk"), Some("foo=1"));
+    }
+
+    #[test]
+    fn override_disk() {
+        let mut c = cfg("modules: []\ndisks:\n  /dev/vda2: ext4,/home\n  /dev/vda1: xfs,/,ro\n");
+        c.set_override("disk", "server:/export:nfs,/data").unwrap();
+        c.set_override("disk", "LABEL=ROOT:btrfs,/,rw,subvol=@").unwrap();
+
+        assert_eq!(
+            disks(&c),
+            vec![
+                ("LABEL=ROOT".to_string(), "btrfs".to_string(), "/".to_string(), "rw,subvol=@".to_string()),
+                ("/dev/vda2".to_string(), "ext4".to_string(), "/home".to_string(), "rw".to_string()),
+                ("server:/export".to_string(), "nfs".to_string(), "/data".to_string(), "rw".to_string()),
+            ]
+        );
+
+        assert_eq!(c.set_override("disk", "/dev/vdb1").unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(c.set_override("disk", "/dev/vdb1:ext4").unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn override_disk_same_device() {
+        let mut c = cfg("modules: []\ndisks:\n  UUID=1234: btrfs,/,rw,subvol=@\n");
+        c.set_override("disk", "UUID=1234:btrfs,/home,rw,subvol=@home").unwrap();
+        assert_eq!(disks(&c).len(), 2);
+
+        // The same device at the same mountpoint is just replaced
+        c.set_override("disk", "UUID=1234:btrfs,/,ro,subvol=@").unwrap();
+        assert_eq!(
+            disks(&c),
+            vec![
+                ("UUID=1234".to_string(), "btrfs".to_string(), "/".to_string(), "ro,subvol=@".to_string()),
+                ("UUID=1234".to_string(), "btrfs".to_string(), "/home".to_string(), "rw,subvol=@home".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn disk_mounts() {
+        let mut c = cfg("modules: []\ndisks:\n  /dev/vdb1: ext4,/srv\n  LABEL=ROOT:\n    - btrfs,/home,rw,subvol=@home\n    - btrfs,/,ro,subvol=@\n");
+        assert_eq!(disks(&c).iter().map(|d| d.2.as_str()).collect::<Vec<&str>>(), vec!["/srv", "/home", "/"]);
+
+        // Root goes first, other mounts of its device follow
+        c.set_disk("/dev/vdb1", "ext4", "/srv", "ro").unwrap();
+        c.set_root_disk("LABEL=ROOT", "btrfs", "rw,subvol=@").unwrap();
+        assert_eq!(
+            disks(&c).iter().map(|d| (d.0.as_str(), d.2.as_str())).collect::<Vec<(&str, &str)>>(),
+            vec![("LABEL=ROOT", "/"), ("LABEL=ROOT", "/home"), ("/dev/vdb1", "/srv")]
+        );
+
+        // Single mount is kept as a string
+        c.set_disk("LABEL=ROOT", "btrfs", "/home", "rw").unwrap();
+        c.set_disk("/dev/vdb2", "xfs", "/home", "rw").unwrap();
+        assert!(matches!(c.disks["LABEL=ROOT"], MhConfDiskMounts::One(_)));
+        assert_eq!(disks(&c).len(), 3);
+    }
+
+    #[test]
+    fn fstab_subvolumes() {
+        let mut c = MhConfig::new();
+        c.add_fstab(
+            "UUID=abc / btrfs subvol=@ 0 0\nUUID=abc /usr btrfs subvol=@usr,x-initrd.mount 0 0\nUUID=abc /home btrfs subvol=@home 0 0\n",
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            disks(&c),
+            vec![
+                ("UUID=abc".to_string(), "btrfs".to_string(), "/".to_string(), "rw,subvol=@".to_string()),
+                ("UUID=abc".to_string(), "btrfs".to_string(), "/usr".to_string(), "rw,subvol=@usr,x-initrd.mount".to_string()),
+                ("UUID=abc".to_string(), "btrfs".to_string(), "/home".to_string(), "rw,subvol=@home".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_errors() {
+        let mut c = MhConfig::new();
+        let err = c.merge([("rootwait", "x"), ("init", "/bin/sh"), ("bogus", "1"), ("rescue", "")]).unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("\"rootwait\"") && err.to_string().contains("\"bogus\""));
+        assert_eq!(err.to_string().matches("; ").count(), 1);
+
+        // Valid keys are still applied
+        assert_eq!(c.get_init_path(), "/bin/sh");
+        assert_eq!(c.get_rescue(), Some(RESCUE_BUILTIN));
+    }
+
+    #[test]
+    fn fstab_file_in_root() {
+        let root = std::env::temp_dir().join(format!("microhop-cfg-{}", std::process::id()));
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(
+            root.join("etc/fstab"),
+            "LABEL=ROOT / ext4 ro 0 1\nLABEL=USR /usr ext4 ro,x-initrd.mount 0 2\nLABEL=HOME /home ext4 rw 0 2\n",
+        )
+        .unwrap();
+        fs::write(root.join("microhop.conf"), "modules: []\nfstab: /etc/fstab\ndisks:\n  /dev/vda2: xfs,/usr\n").unwrap();
+
+        // Only the root and the "x-initrd.mount" entries are taken
+        let c = get_mh_config_with_root(root.join("microhop.conf").to_str(), &root).unwrap();
+        let d = disks(&c);
+        assert_eq!(d.len(), 2);
+        assert!(d.contains(&("/dev/vda2".to_string(), "xfs".to_string(), "/usr".to_string(), "rw".to_string())));
+        assert!(d.contains(&("LABEL=ROOT".to_string(), "ext4".to_string(), "/".to_string(), "ro".to_string())));
+
+        fs::remove_dir_all(&root).unwrap();
     }
 }
@@ -0,0 +1,179 @@
+//! Filesystem table.
+//!
+//! Parser of fstab(5), so the same mounts need not to be defined twice:
+//! in the fstab of the system and in the disks of the profile.
+
+/// Mount option, which marks the entries to be mounted in the initramfs
+pub static INITRD_MOUNT_OPT: &str = "x-initrd.mount";
+
+/// Entry of the filesystem table
+#[derive(Debug, Clone)]
+pub struct FstabEntry {
+    spec: String,
+    file: String,
+    vfstype: String,
+    mntops: String,
+}
+
+impl FstabEntry {
+    /// Get the device: `UUID=`, `LABEL=`, `PARTUUID=` and `PARTLABEL=` are the same in the profile
+    pub fn get_device(&self) -> String {
+        match self.spec.split_once('=') {
+            Some((k, v)) => format!("{}={}", k, v.trim_matches('"')),
+            None => self.spec.to_owned(),
+        }
+    }
+
+    /// Get the mountpoint
+    pub fn get_mountpoint(&self) -> &str {
+        &self.file
+    }
+
+    /// Get the filesystem type
+    pub fn get_fstype(&self) -> &str {
+        &self.vfstype
+    }
+
+    /// Get the mode in the notation of the profile: "ro" or "rw" first, followed by the other options
+    pub fn get_mode(&self) -> String {
+        let opts = self.mntops.split(',').filter(|o| !o.is_empty()).collect::<Vec<&str>>();
+        let mode = opts.iter().rev().find(|o| **o == "ro" || **o == "rw").copied().unwrap_or("rw");
+
+        [mode]
+            .into_iter()
+            .chain(opts.into_iter().filter(|o| !["ro", "rw", "defaults"].contains(o)))
+            .collect::<Vec<&str>>()
+            .join(",")
+    }
+
+    /// Returns true if the entry has the given mount option
+    fn has_option(&self, opt: &str) -> bool {
+        self.mntops.split(',').any(|o| o == opt)
+    }
+
+    /// Returns true if the entry is a disk or a network share, which is mounted at boot.
+    /// Swap, "noauto" entries, bind mounts and pseudo filesystems (proc, tmpfs etc) are not.
+    pub fn is_mountable(&self) -> bool {
+        let is_disk = ["UUID=", "LABEL=", "PARTUUID=", "PARTLABEL="].iter().any(|p| self.spec.starts_with(p))
+            || self.spec.starts_with('/')
+            || self.spec.contains(":/");
+
+        is_disk
+            && self.file.starts_with('/')
+            && !["swap", "none"].contains(&self.vfstype.as_str())
+            && !["noauto", "bind", "rbind"].iter().any(|o| self.has_option(o))
+    }
+
+    /// Returns true if the entry should be mounted in the initramfs: it is the root or marked with "x-initrd.mount"
+    pub fn is_initrd(&self) -> bool {
+        self.file.trim_end_matches('/').is_empty() || self.has_option(INITRD_MOUNT_OPT)
+    }
+}
+
+/// Decode octal escapes of the fields, such as "\040" for a space
+fn unescape(field: &str) -> String {
+    let mut out: Vec<u8> = Vec::default();
+    let b = field.as_bytes();
+    let mut i = 0;
+    while i < b.len() {
+        match (b[i], field.get(i + 1..i + 4).and_then(|o| u8::from_str_radix(o, 8).ok())) {
+            (b'\\', Some(c)) => {
+                out.push(c);
+                i += 4;
+            }
+            (c, _) => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&out).to_string()
+}
+
+/// Parse the filesystem table. Comments, blank and incomplete lines are skipped.
+pub fn parse(data: &str) -> Vec<FstabEntry> {
+    data.lines()
+        .filter(|l| !l.trim_start().starts_with('#'))
+        .filter_map(|l| {
+            let f = l.split_whitespace().map(unescape).collect::<Vec<String>>();
+            if f.len() < 3 {
+                return None;
+            }
+
+            Some(FstabEntry {
+                spec: f[0].to_owned(),
+                file: f[1].to_owned(),
+                vfstype: f[2].to_owned(),
+                mntops: f.get(3).cloned().unwrap_or("defaults".to_string()),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FSTAB: &str = r#"
+# <file system> <mount point> <type> <options> <dump> <pass>
+UUID=24e1daee-e09b-4fd5-97f3-dde8aba6ad8a / ext4 defaults,ro 0 1
+LABEL="Data\040Disk" /mnt/my\040data xfs noatime 0 2
+PARTUUID=0a1b2c3d-02 /usr ext4 ro,x-initrd.mount 0 2
+/dev/vda3 none swap sw 0 0
+/dev/vdb1 /backup ext4 noauto,nofail 0 2
+proc /proc proc defaults 0 0
+tmpfs /tmp tmpfs mode=1777 0 0
+server:/export /srv nfs4 rw,vers=4.2
+/srv/www /var/www none bind 0 0
+/data /mnt/data none rbind,ro 0 0
+/dev/vdc1 /mnt/none none defaults 0 0
+incomplete /line
+"#;
+
+    #[test]
+    fn parse_entries() {
+        let e = parse(FSTAB);
+        assert_eq!(e.len(), 11);
+
+        assert_eq!(e[0].get_device(), "UUID=24e1daee-e09b-4fd5-97f3-dde8aba6ad8a");
+        assert_eq!((e[0].get_mountpoint(), e[0].get_fstype(), e[0].get_mode().as_str()), ("/", "ext4", "ro"));
+        assert_eq!(e[7].get_device(), "server:/export");
+        assert_eq!(e[7].get_mode(), "rw,vers=4.2");
+    }
+
+    #[test]
+    fn parse_escapes() {
+        let e = parse(FSTAB);
+        assert_eq!(e[1].get_device(), "LABEL=Data Disk");
+        assert_eq!(e[1].get_mountpoint(), "/mnt/my data");
+        assert_eq!(e[1].get_mode(), "rw,noatime");
+
+        assert_eq!(unescape(r"a\011b\134c"), "a\tb\\c");
+        assert_eq!(unescape(r"a\0b\999"), r"a\0b\999");
+    }
+
+    #[test]
+    fn mountable_entries() {
+        let e = parse(FSTAB);
+        let mpts: Vec<&str> = e.iter().filter(|e| e.is_mountable()).map(|e| e.get_mountpoint()).collect();
+        assert_eq!(mpts, vec!["/", "/mnt/my data", "/usr", "/srv"]);
+    }
+
+    #[test]
+    fn bind_mounts() {
+        let e = parse(FSTAB);
+        assert_eq!(e[8].get_mode(), "rw,bind");
+        assert!(!e[8].is_mountable());
+        assert!(!e[9].is_mountable());
+        assert!(!e[10].is_mountable());
+    }
+
+    #[test]
+    fn initrd_entries() {
+        let e = parse(FSTAB);
+        let mpts: Vec<&str> = e.iter().filter(|e| e.is_mountable() && e.is_initrd()).map(|e| e.get_mountpoint()).collect();
+        assert_eq!(mpts, vec!["/", "/usr"]);
+        assert_eq!(e[2].get_mode(), "ro,x-initrd.mount");
+    }
+}
@@ -0,0 +1,456 @@
+use profile::cfg::{MhConfNet, MhConfig, NET_ANY, NET_DHCP};
+use std::{fs, io::Error, net::Ipv4Addr};
+
+/// Path to the kernel command line
+static CMDLINE_PATH: &str = "/proc/cmdline";
+
+/// Root device of the network root, as the kernel expects it
+static NFS_ROOT: &str = "/dev/nfs";
+
+/// Autoconfiguration methods of `ip=`, which are all done via DHCP
+const IP_AUTOCONF: &[&str] = &["dhcp", "on", "any", "bootp", "both"];
+
+/// Microhop parameters, which are not profile keys
+const MH_RUNTIME_KEYS: &[&str] = &["break"];
+
+/// Kernel command line.
+///
+/// Parameters are kept in the order they were given, so the last one wins,
+/// exactly as the kernel does for `root=` and friends. Everything after `--`
+/// belongs to the init and is ignored.
+pub struct KernelCmdline {
+    args: Vec<(String, Option<String>)>,
+}
+
+impl KernelCmdline {
+    /// Read the kernel command line of the running system
+    pub fn load() -> Result<Self, Error> {
+        Ok(Self::parse(&fs::read_to_string(CMDLINE_PATH)?))
+    }
+
+    /// Parse the command line. Values can be double-quoted, if they contain spaces.
+    pub fn parse(data: &str) -> Self {
+        let mut args: Vec<(String, Option<String>)> = Vec::default();
+        let mut arg = String::new();
+        let mut quoted = false;
+
+        for c in data.trim().chars().chain([' ']) {
+            match c {
+                '"' => quoted = !quoted,
+                c if c.is_whitespace() && !quoted => {
+                    if arg == "--" {
+                        break;
+                    }
+
+                    if !arg.is_empty() {
+                        args.push(match arg.split_once('=') {
+                            Some((k, v)) => (k.to_string(), Some(v.to_string())),
+                            None => (arg.to_string(), None),
+                        });
+                        arg.clear();
+                    }
+                }
+                c => arg.push(c),
+            }
+        }
+
+        KernelCmdline { args }
+    }
+
+    /// Get the value of the last given parameter
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.args.iter().rev().find(|(k, v)| k == key && v.is_some()).and_then(|(_, v)| v.as_deref())
+    }
+
+    /// Returns true if the boot should stop at the given stage (`mh.break=<stage>[,<stage>...]`)
+    pub fn is_break(&self, stage: &str) -> bool {
+        self.get("mh.break").is_some_and(|b| b.split(',').any(|s| s == stage))
+    }
+
+    /// Get kernel module parameters, given as `<module>.<param>[=<value>]`.
+    /// Returns module names with their parameters.
+    pub fn get_module_params(&self) -> Vec<(String, String)> {
+        self.args
+            .iter()
+            .filter_map(|(k, v)| {
+                let (modname, param) = k.split_once('.')?;
+                if ["mh", "modprobe"].contains(&modname) || modname.is_empty() || param.is_empty() {
+                    return None;
+                }
+
+                Some((
+                    modname.to_string(),
+                    match v {
+                        Some(v) if v.contains(' ') => format!("{}=\"{}\"", param, v),
+                        Some(v) => format!("{}={}", param, v),
+                        None => param.to_string(),
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    /// Get modules, blacklisted with `modprobe.blacklist=<module>[,<module>...]`
+    pub fn get_module_blacklist(&self) -> Vec<String> {
+        self.args
+            .iter()
+            .filter(|(k, _)| k == "modprobe.blacklist")
+            .flat_map(|(_, v)| v.as_deref().unwrap_or_default().split(',').filter(|m| !m.is_empty()).map(|m| m.to_string()))
+            .collect()
+    }
+
+    /// Get the root mounting mode: the last of "ro" or "rw" wins
+    fn get_root_mode(&self) -> Option<&str> {
+        self.args.iter().rev().find(|(k, v)| v.is_none() && (k == "ro" || k == "rw")).map(|(k, _)| k.as_str())
+    }
+
+    /// Get the network root, given as `root=/dev/nfs nfsroot=<server>:<export>[,<options>]`.
+    /// Returns the share and its options.
+    fn get_nfsroot(&self) -> Option<(&str, Option<&str>)> {
+        if self.get("root") != Some(NFS_ROOT) {
+            return None;
+        }
+
+        let Some(nfsroot) = self.get("nfsroot") else {
+            log::warn!("Root device {} requires nfsroot=<server>:<export>", NFS_ROOT);
+            return None;
+        };
+
+        Some(match nfsroot.split_once(',') {
+            Some((share, opts)) => (share, Some(opts)),
+            None => (nfsroot, None),
+        })
+    }
+
+    /// Translate `root=` device to the notation of the profile
+    fn get_root_device(&self) -> Option<String> {
+        if let Some((share, _)) = self.get_nfsroot() {
+            return Some(share.to_string());
+        }
+
+        // The profile takes UUID=, LABEL=, PARTUUID= and PARTLABEL= as is
+        self.get("root").map(|root| root.to_string())
+    }
+
+    /// Get network configuration from `ip=` parameters, each one of:
+    ///
+    ///   - `off` or `none` to disable the network
+    ///   - `dhcp` (or `on`, `any`, `bootp`, `both`) for any interface
+    ///   - `<device>:dhcp` for the given interface
+    ///   - `<client>:<server>:<gateway>:<netmask>:<hostname>:<device>:<autoconf>`
+    ///
+    /// Returns `None` if there are no such parameters, or interface names with their configuration.
+    fn get_network(&self) -> Option<Vec<(String, MhConfNet)>> {
+        let params = self.args.iter().filter(|(k, _)| k == "ip").filter_map(|(_, v)| v.as_deref()).collect::<Vec<&str>>();
+        if params.is_empty() {
+            return None;
+        }
+
+        let mut network: Vec<(String, MhConfNet)> = Vec::default();
+        for ip in params {
+            if ["off", "none"].contains(&ip) {
+                network.clear();
+                continue;
+            }
+
+            if IP_AUTOCONF.contains(&ip) {
+                network.push((NET_ANY.to_string(), MhConfNet::new(NET_DHCP, None)));
+                continue;
+            }
+
+            let f = ip.split(':').collect::<Vec<&str>>();
+            if let [device, autoconf] = f[..] {
+                if IP_AUTOCONF.contains(&autoconf) {
+                    network.push((device.to_string(), MhConfNet::new(NET_DHCP, None)));
+                } else {
+                    log::warn!("Ignoring unsupported network configuration: ip={}", ip);
+                }
+                continue;
+            }
+
+            let field = |i: usize| f.get(i).copied().unwrap_or_default();
+            let device = Some(field(5)).filter(|d| !d.is_empty()).unwrap_or(NET_ANY);
+            let Ok(client) = field(0).parse::<Ipv4Addr>() else {
+                if field(0).is_empty() || IP_AUTOCONF.contains(&field(6)) {
+                    network.push((device.to_string(), MhConfNet::new(NET_DHCP, None)));
+                } else {
+                    log::warn!("Ignoring invalid client address: ip={}", ip);
+                }
+                continue;
+            };
+
+            // Without the netmask, the class of the address defines it, as in the kernel
+            let prefix = match field(3).parse::<Ipv4Addr>() {
+                Ok(mask) => syslib::net::get_prefix(mask),
+                Err(_) if client.octets()[0] < 128 => 8,
+                Err(_) if client.octets()[0] < 192 => 16,
+                Err(_) => 24,
+            };
+            let gateway = Some(field(2)).filter(|g| g.parse::<Ipv4Addr>().is_ok_and(|g| !g.is_unspecified()));
+            network.push((device.to_string(), MhConfNet::new(&format!("{}/{}", client, prefix), gateway)));
+        }
+
+        Some(network)
+    }
+
+    /// Overlay the kernel command line on top of the profile.
+    ///
+    /// The kernel command line always wins over the profile, which in turn
+    /// wins over the built-in defaults. Microhop-specific `mh.*` parameters
+    /// are applied after the standard ones, so they win over them.
+    pub fn overlay(&self, cfg: &mut MhConfig) -> Result<(), Error> {
+        self.overlay_root(cfg)?;
+        self.overlay_profile(cfg);
+
+        Ok(())
+    }
+
+    /// Override any profile key with `mh.<key>=<value>` parameters
+    fn overlay_profile(&self, cfg: &mut MhConfig) {
+        let mut kv: Vec<(&str, &str)> = Vec::default();
+        for (k, v) in &self.args {
+            if let Some(k) = k.strip_prefix("mh.").filter(|k| !MH_RUNTIME_KEYS.contains(k)) {
+                log::info!(
+                    "Profile key \"{}\" is overridden by the kernel command line: {}",
+                    k,
+                    v.as_deref().unwrap_or_default()
+                );
+                kv.push((k, v.as_deref().unwrap_or_default()));
+            }
+        }
+
+        if let Err(err) = cfg.merge(kv) {
+            log::warn!("Some of the kernel command line parameters were ignored: {}", err);
+        }
+    }
+
+    /// Overlay the standard boot parameters
+    fn overlay_root(&self, cfg: &mut MhConfig) -> Result<(), Error> {
+        if let Some(init) = self.get("init") {
+            log::info!("Init program {} is taken from the kernel command line (profile: {})", init, cfg.get_init_path());
+            cfg.set_init_path(init);
+        }
+
+        // Bare "rootwait" means to wait forever
+        if let Some((_, v)) = self.args.iter().rev().find(|(k, _)| k == "rootwait") {
+            log::info!("Root wait timeout is taken from the kernel command line: {}", v.as_deref().unwrap_or("forever"));
+            if let Err(err) = cfg.set_override("rootwait", v.as_deref().unwrap_or("-1")) {
+                log::warn!("Ignoring rootwait: {}", err);
+            }
+        }
+
+        if let Some(delay) = self.get("rootdelay") {
+            log::info!("Root delay is taken from the kernel command line: {}s", delay);
+            if let Err(err) = cfg.set_override("rootdelay", delay) {
+                log::warn!("Ignoring rootdelay: {}", err);
+            }
+        }
+
+        // Network from the kernel replaces the whole profile network
+        if let Some(network) = self.get_network() {
+            log::info!("Network configuration is taken from the kernel command line");
+            cfg.clear_network();
+            for (iface, net) in network {
+                cfg.set_network(&iface, net);
+            }
+        }
+
+        let device = self.get_root_device();
+        let nfsroot = self.get_nfsroot();
+        let fstype = self.get("rootfstype").or(nfsroot.map(|_| "nfs"));
+        let flags = match (self.get("rootflags"), nfsroot.and_then(|(_, opts)| opts)) {
+            (Some(f), Some(o)) => Some(format!("{},{}", o, f)),
+            (f, o) => f.or(o).map(|f| f.to_string()),
+        };
+        let mode = self.get_root_mode();
+
+        if device.is_none() && fstype.is_none() && flags.is_none() && mode.is_none() {
+            log::debug!("Root filesystem is taken from the profile");
+            return Ok(());
+        }
+
+        let root = cfg.get_root_disk()?;
+        let (p_device, p_fstype, p_mode) = match &root {
+            Some(d) => (d.get_device(), d.get_fstype(), d.get_mode()),
+            None => ("", "", "rw"),
+        };
+
+        for (name, kval, pval) in [
+            ("device", device.as_deref(), p_device),
+            ("type", fstype, p_fstype),
+            ("mode", mode, p_mode),
+            ("flags", flags.as_deref(), ""),
+        ] {
+            match kval {
+                Some(v) => log::info!("Root {} {} is taken from the kernel command line", name, v),
+                None if !pval.is_empty() => log::debug!("Root {} {} is taken from the profile", name, pval),
+                None => {}
+            }
+        }
+
+        // ro/rw goes first, wherever it was in the profile mode. The other options are kept, unless rootflags replace them.
+        let is_rw = |o: &&str| *o == "ro" || *o == "rw";
+        let p_opts = p_mode.split(',').filter(|o| !o.is_empty()).collect::<Vec<&str>>();
+        let mut r_mode = vec![mode.or(p_opts.iter().rev().copied().find(is_rw)).unwrap_or("rw")];
+        match &flags {
+            Some(flags) => r_mode.extend(flags.split(',').filter(|f| !f.is_empty())),
+            None => r_mode.extend(p_opts.iter().filter(|o| !is_rw(o))),
+        }
+
+        let r_device = device.unwrap_or(p_device.to_string());
+        if r_device.is_empty() {
+            log::error!("Root device is defined neither in the profile nor on the kernel command line");
+            return Ok(());
+        }
+
+        let r_fstype = fstype.unwrap_or(p_fstype).to_string();
+        cfg.set_root_disk(&r_device, &r_fstype, &r_mode.join(","))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Profile with a btrfs root
+    fn profile(mode: &str) -> MhConfig {
+        let mut cfg = MhConfig::new();
+        cfg.set_root_disk("LABEL=ROOT", "btrfs", mode).unwrap();
+        cfg
+    }
+
+    fn root(cfg: &MhConfig) -> (String, String, String) {
+        let d = cfg.get_root_disk().unwrap().unwrap();
+        (d.get_device().to_string(), d.get_fstype().to_string(), d.get_mode().to_string())
+    }
+
+    fn overlay(cmdline: &str, cfg: &mut MhConfig) {
+        KernelCmdline::parse(cmdline).overlay(cfg).unwrap();
+    }
+
+    #[test]
+    fn parse_quoted() {
+        let cl = KernelCmdline::parse("  root=LABEL=\"My Root\" quiet  e1000e.opt=\"a b\" e1000e.debug=1 e1000e.flag\n");
+        assert_eq!(cl.get("root"), Some("LABEL=My Root"));
+        assert_eq!(cl.get("quiet"), None);
+        assert_eq!(
+            cl.get_module_params(),
+            vec![
+                ("e1000e".to_string(), "opt=\"a b\"".to_string()),
+                ("e1000e".to_string(), "debug=1".to_string()),
+                ("e1000e".to_string(), "flag".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_last_wins() {
+        let cl = KernelCmdline::parse(
+            "root=/dev/vda1 ro root=/dev/vda2 rw mh.break=modules,disks modprobe.blacklist=a,b modprobe.blacklist=c",
+        );
+        assert_eq!(cl.get("root"), Some("/dev/vda2"));
+        assert_eq!(cl.get_root_mode(), Some("rw"));
+        assert!(cl.is_break("disks") && !cl.is_break("root"));
+        assert_eq!(cl.get_module_blacklist(), vec!["a", "b", "c"]);
+        assert!(cl.get_module_params().is_empty());
+    }
+
+    #[test]
+    fn parse_init_args() {
+        let cl = KernelCmdline::parse("ro init=/bin/sh -- rw root=/dev/vdb single");
+        assert_eq!(cl.get("init"), Some("/bin/sh"));
+        assert_eq!(cl.get_root_mode(), Some("ro"));
+        assert_eq!(cl.get("root"), None);
+        assert_eq!(cl.args.len(), 2);
+    }
+
+    #[test]
+    fn network() {
+        let net = |cmdline: &str| {
+            KernelCmdline::parse(cmdline).get_network().map(|n| {
+                n.iter()
+                    .map(|(i, n)| (i.to_string(), n.get_address().to_string(), n.get_gateway().map(|g| g.to_string())))
+                    .collect::<Vec<_>>()
+            })
+        };
+        let dhcp = |iface: &str| (iface.to_string(), NET_DHCP.to_string(), None);
+
+        assert_eq!(net("quiet"), None);
+        assert_eq!(net("ip=dhcp"), Some(vec![dhcp(NET_ANY)]));
+        assert_eq!(net("ip=eth0:dhcp ip=eth1:on"), Some(vec![dhcp("eth0"), dhcp("eth1")]));
+        assert_eq!(net("ip=eth0:dhcp ip=off"), Some(vec![]));
+        assert_eq!(net("ip=eth0:static"), Some(vec![]));
+        assert_eq!(net("ip=:::::eth0:dhcp"), Some(vec![dhcp("eth0")]));
+        assert_eq!(
+            net("ip=192.168.1.10::192.168.1.1:255.255.255.0:host:eth0:none"),
+            Some(vec![("eth0".to_string(), "192.168.1.10/24".to_string(), Some("192.168.1.1".to_string()))])
+        );
+
+        // Netmask by the address class, unspecified gateway
+        assert_eq!(net("ip=10.1.2.3::0.0.0.0"), Some(vec![(NET_ANY.to_string(), "10.1.2.3/8".to_string(), None)]));
+        assert_eq!(net("ip=172.16.0.5"), Some(vec![(NET_ANY.to_string(), "172.16.0.5/16".to_string(), None)]));
+        assert_eq!(net("ip=bogus::1.2.3.4"), Some(vec![]));
+    }
+
+    #[test]
+    fn overlay_network() {
+        let mut cfg = MhConfig::new();
+        cfg.set_network("eth1", MhConfNet::new("10.0.0.2/8", None));
+        overlay("ip=eth0:dhcp", &mut cfg);
+        assert_eq!(cfg.get_network().keys().collect::<Vec<&String>>(), vec!["eth0"]);
+    }
+
+    #[test]
+    fn overlay_root_device() {
+        let mut cfg = profile("rw,subvol=@");
+        overlay("quiet", &mut cfg);
+        assert_eq!(root(&cfg), ("LABEL=ROOT".to_string(), "btrfs".to_string(), "rw,subvol=@".to_string()));
+
+        overlay("root=UUID=1234 rootfstype=ext4", &mut cfg);
+        assert_eq!(root(&cfg), ("UUID=1234".to_string(), "ext4".to_string(), "rw,subvol=@".to_string()));
+        assert_eq!(cfg.get_disks().unwrap().len(), 1);
+
+        // rootflags replace the options of the profile, but not its mode
+        let mut cfg = profile("ro,subvol=@");
+        overlay("rootflags=subvol=@snap,compress=zstd", &mut cfg);
+        assert_eq!(root(&cfg).2, "ro,subvol=@snap,compress=zstd");
+    }
+
+    #[test]
+    fn overlay_root_mode() {
+        for (p_mode, cmdline, mode) in [
+            ("subvol=@,compress=zstd", "ro", "ro,subvol=@,compress=zstd"),
+            ("rw,subvol=@", "ro", "ro,subvol=@"),
+            ("subvol=@,ro", "rw", "rw,subvol=@"),
+            ("subvol=@,ro", "quiet root=LABEL=ROOT", "ro,subvol=@"),
+            ("ro,subvol=@", "rw ro rootflags=noatime", "ro,noatime"),
+        ] {
+            let mut cfg = profile(p_mode);
+            overlay(cmdline, &mut cfg);
+            assert_eq!(root(&cfg).2, mode, "{} + {}", p_mode, cmdline);
+        }
+    }
+
+    #[test]
+    fn overlay_nfsroot() {
+        let mut cfg = MhConfig::new();
+        overlay("root=/dev/nfs nfsroot=10.0.0.1:/srv/root,vers=4.2 rootflags=nolock ro", &mut cfg);
+        assert_eq!(root(&cfg), ("10.0.0.1:/srv/root".to_string(), "nfs".to_string(), "ro,vers=4.2,nolock".to_string()));
+    }
+
+    #[test]
+    fn overlay_precedence() {
+        let mut cfg = MhConfig::new();
+        cfg.set_init_path("/usr/lib/systemd/systemd");
+        overlay("init=/bin/bash rootwait rootdelay=2 mh.init=/bin/sh mh.rootwait=5", &mut cfg);
+
+        assert_eq!(cfg.get_init_path(), "/bin/sh");
+        assert_eq!(cfg.get_rootwait_as_secs(), &Some(5));
+        assert_eq!(cfg.get_rootdelay().as_secs(), 2);
+
+        overlay("rootwait", &mut cfg);
+        assert_eq!(cfg.get_rootwait(), None);
+    }
+}
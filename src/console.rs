@@ -0,0 +1,269 @@
+use crate::{cmdline::KernelCmdline, kmodprobe::KModProbe, logger};
+use colored::Colorize;
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    sys::reboot::{reboot, RebootMode},
+    unistd,
+};
+use profile::cfg::MhConfig;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, BufRead, Error, ErrorKind, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+use syslib::blk::BlkInfo;
+
+/// Built-in minimal console.
+///
+/// It is not a shell: only a handful of commands are available,
+/// just enough to find out why the system does not boot.
+pub struct Console {
+    prompt: String,
+    mpb: KModProbe,
+}
+
+impl Console {
+    pub fn new(prompt: &str, cfg: &MhConfig) -> Self {
+        Console { prompt: prompt.to_string(), mpb: KModProbe::new(cfg) }
+    }
+
+    /// Run the console until it is asked to continue the boot
+    pub fn run(&self) -> Result<(), Error> {
+        println!("Type \"help\" for the list of commands");
+
+        let mut stdin = io::stdin().lock();
+        loop {
+            print!("{} {} ", self.prompt.bright_yellow(), ">".bright_yellow());
+            io::stdout().flush()?;
+
+            let mut line = String::new();
+            if stdin.read_line(&mut line)? == 0 {
+                println!("Console input is closed, rebooting");
+                self.reboot();
+                continue;
+            }
+
+            let args = line.split_whitespace().collect::<Vec<&str>>();
+            let Some((cmd, args)) = args.split_first() else {
+                continue;
+            };
+
+            let ret = match *cmd {
+                "help" => {
+                    self.help();
+                    Ok(())
+                }
+                "ls" => self.ls(args),
+                "cat" => self.cat(args),
+                "blkid" => {
+                    self.blkid();
+                    Ok(())
+                }
+                "lsmod" => self.lsmod(),
+                "modprobe" => {
+                    self.modprobe(args);
+                    Ok(())
+                }
+                "mount" => self.mount(args),
+                "dmesg" => self.dmesg(),
+                "log" => {
+                    self.log();
+                    Ok(())
+                }
+                "continue" | "retry" => return Ok(()),
+                "reboot" => {
+                    self.reboot();
+                    Ok(())
+                }
+                cmd => Err(Error::new(ErrorKind::NotFound, format!("Unknown command: {}", cmd))),
+            };
+
+            if let Err(err) = ret {
+                println!("{}", err.to_string().bright_red());
+            }
+        }
+    }
+
+    /// Show available commands
+    fn help(&self) {
+        for (cmd, descr) in [
+            ("ls", "[PATH] list a directory"),
+            ("cat", "FILE... show content of files"),
+            ("blkid", "list block devices"),
+            ("lsmod", "list loaded kernel modules"),
+            ("modprobe", "MODULE... load kernel modules"),
+            ("mount", "[DEVICE DIR [FSTYPE]] list mounted filesystems or mount a device"),
+            ("dmesg", "show the kernel log"),
+            ("log", "show the boot log"),
+            ("continue", "continue the boot (\"retry\" in the rescue mode)"),
+            ("reboot", "reboot the system"),
+        ] {
+            println!("  {:<10} {}", cmd.bright_green(), descr);
+        }
+    }
+
+    /// Get exactly one argument or fail
+    fn get_arg<'a>(&self, args: &[&'a str], usage: &str) -> Result<&'a str, Error> {
+        match args {
+            [arg] => Ok(arg),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("Usage: {}", usage))),
+        }
+    }
+
+    /// List a directory
+    fn ls(&self, args: &[&str]) -> Result<(), Error> {
+        let p = if args.is_empty() { "." } else { self.get_arg(args, "ls [PATH]")? };
+
+        let mut entries = fs::read_dir(p)?.flatten().collect::<Vec<fs::DirEntry>>();
+        entries.sort_by_key(|e| e.file_name());
+        for e in entries {
+            let meta = e.path().symlink_metadata()?;
+            let name = e.file_name().to_string_lossy().to_string();
+            if meta.is_symlink() {
+                println!("{:>12} {} -> {}", "", name.bright_cyan(), fs::read_link(e.path())?.display());
+            } else if meta.is_dir() {
+                println!("{:>12} {}/", "", name.bright_blue().bold());
+            } else {
+                println!("{:>12} {}", meta.len(), name);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Show content of files
+    fn cat(&self, args: &[&str]) -> Result<(), Error> {
+        if args.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Usage: cat FILE..."));
+        }
+
+        for p in args {
+            print!("{}", String::from_utf8_lossy(&fs::read(p)?));
+        }
+        io::stdout().flush()
+    }
+
+    /// List block devices
+    fn blkid(&self) {
+        let mut blkid = BlkInfo::new();
+        if let Err(err) = blkid.probe_devices() {
+            println!("Unable to probe block devices: {}", err);
+            return;
+        }
+
+        for d in blkid.get_devices() {
+            println!(
+                "{}: TYPE=\"{}\" UUID=\"{}\" LABEL=\"{}\" PARTUUID=\"{}\" PARTLABEL=\"{}\"",
+                d.get_path().display(),
+                d.get_fstype(),
+                d.get_uuid(),
+                d.get_label(),
+                d.get_partuuid(),
+                d.get_partlabel()
+            );
+        }
+    }
+
+    /// List loaded kernel modules
+    fn lsmod(&self) -> Result<(), Error> {
+        println!("{:<30} {:<10} {}", "Module".bright_yellow(), "Size".bright_yellow(), "Used by".bright_yellow());
+        for l in fs::read_to_string("/proc/modules")?.lines() {
+            let m = l.split_whitespace().collect::<Vec<&str>>();
+            if m.len() > 3 {
+                println!("{:<30} {:<10} {} {}", m[0], m[1], m[2], m[3].trim_matches(|c| c == '-' || c == ','));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Load kernel modules
+    fn modprobe(&self, args: &[&str]) {
+        for m in args {
+            println!("{}: {}", m, self.mpb.modprobe(m));
+        }
+    }
+
+    /// List mounted filesystems or mount a device
+    fn mount(&self, args: &[&str]) -> Result<(), Error> {
+        let (dev, dst, fstype) = match args {
+            [] => {
+                print!("{}", fs::read_to_string("/proc/mounts")?);
+                return Ok(());
+            }
+            [dev, dst] => (*dev, *dst, None),
+            [dev, dst, fstype] => (*dev, *dst, Some(fstype.to_string())),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "Usage: mount [DEVICE DIR [FSTYPE]]")),
+        };
+
+        // Without the type given, take the one of the probed device
+        let fstype = match fstype {
+            Some(fstype) => fstype,
+            None => {
+                let mut blkid = BlkInfo::new();
+                blkid.probe_devices()?;
+                match blkid.by_path(dev) {
+                    Some(d) if !d.get_fstype().is_empty() => d.get_fstype().to_string(),
+                    _ => return Err(Error::new(ErrorKind::NotFound, format!("Unable to detect filesystem type of {}", dev))),
+                }
+            }
+        };
+
+        if !Path::new(dst).exists() {
+            fs::create_dir_all(dst)?;
+        }
+
+        syslib::fs::mount(&fstype, dev, dst, "")
+    }
+
+    /// Show the kernel log
+    fn dmesg(&self) -> Result<(), Error> {
+        let mut kmsg = OpenOptions::new().read(true).custom_flags(OFlag::O_NONBLOCK.bits()).open("/dev/kmsg")?;
+        let mut buff = [0u8; 0x2000];
+
+        // Each read returns exactly one record: "<prio>,<seq>,<usec>,<flags>;<message>"
+        loop {
+            match kmsg.read(&mut buff) {
+                Ok(0) => break,
+                Ok(n) => {
+                    let rec = String::from_utf8_lossy(&buff[..n]);
+                    if let Some((hdr, msg)) = rec.split_once(';') {
+                        let usec = hdr.split(',').nth(2).and_then(|t| t.parse::<u64>().ok()).unwrap_or_default();
+                        println!("[{:12.6}] {}", usec as f64 / 1_000_000.0, msg.lines().next().unwrap_or_default());
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.raw_os_error() == Some(Errno::EPIPE as i32) => continue, // Overwritten records
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Show the boot log
+    fn log(&self) {
+        for l in logger::history() {
+            println!("{}", l);
+        }
+    }
+
+    /// Reboot the system
+    fn reboot(&self) {
+        unistd::sync();
+        let Err(err) = reboot(RebootMode::RB_AUTOBOOT);
+        println!("Unable to reboot: {}", err);
+    }
+}
+
+/// Stop the boot at the given stage with the console, if asked on the kernel command line
+pub fn breakpoint(kcl: &KernelCmdline, cfg: &MhConfig, stage: &str) -> Result<(), Error> {
+    if kcl.is_break(stage) {
+        log::warn!("Boot is stopped at \"{}\"", stage);
+        Console::new(stage, cfg).run()?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use crate::microhop::{resolve_device, wait_blk_devices};
+use nix::{
+    mount::{self, MsFlags},
+    sys::termios::{self, LocalFlags, SetArg},
+};
+use profile::cfg::{MhConfCrypt, MhConfig};
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+use syslib::{dm, luks::Luks2};
+
+/// Temporary mountpoint of the key device
+static KEYDEV_MPT: &str = "/run/microhop/keydev";
+
+/// Attempts to enter the passphrase
+const PASSPHRASE_ATTEMPTS: usize = 3;
+
+/// Unlock all configured encrypted devices, which are not unlocked yet
+pub fn unlock(cfg: &MhConfig) -> Result<(), Error> {
+    let pending = cfg.get_crypt().iter().filter(|(name, _)| !dm::exists(name)).collect::<Vec<_>>();
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    let blkid = wait_blk_devices(cfg, &pending.iter().map(|(_, c)| c.get_device()).collect::<Vec<&str>>(), cfg.get_rootwait())?;
+    for (name, c) in pending {
+        let Some(dev) = resolve_device(&blkid, c.get_device()) else {
+            return Err(Error::new(ErrorKind::NotFound, format!("Unknown encrypted device: {}", c.get_device())));
+        };
+
+        let luks = Luks2::open(Path::new(&dev))?;
+        let mut key = match read_key(cfg, c) {
+            Ok(Some(mut k)) => {
+                let ret = luks.unlock(&k);
+                k.fill(0);
+                match ret {
+                    Ok(key) => key,
+                    Err(err) => {
+                        log::warn!("Unable to unlock {} with the key: {}", dev, err);
+                        unlock_interactive(&luks, &dev, name)?
+                    }
+                }
+            }
+            Ok(None) => unlock_interactive(&luks, &dev, name)?,
+            Err(err) => {
+                log::warn!("Unable to read the key for {}: {}", dev, err);
+                unlock_interactive(&luks, &dev, name)?
+            }
+        };
+
+        let ret = luks.activate(name, &key, false);
+        key.fill(0);
+        log::info!("Unlocked {} as {}", dev, ret?.display());
+    }
+
+    Ok(())
+}
+
+/// Read the key of an encrypted device, if it is configured: either a keyfile in the initramfs,
+/// or a keyfile on the key device, or the key device itself.
+fn read_key(cfg: &MhConfig, c: &MhConfCrypt) -> Result<Option<Vec<u8>>, Error> {
+    let Some(kdev) = c.get_keydevice() else {
+        return c.get_keyfile().map(|kf| read_keyfile(Path::new(kf), c)).transpose();
+    };
+
+    log::info!("Waiting for the key device {}", kdev);
+    let blkid = wait_blk_devices(cfg, &[kdev], c.get_keytimeout())?;
+    let Some(dev) = resolve_device(&blkid, kdev) else {
+        return Err(Error::new(ErrorKind::NotFound, format!("Unknown key device: {}", kdev)));
+    };
+
+    let Some(kf) = c.get_keyfile() else {
+        return read_keyfile(Path::new(&dev), c).map(Some);
+    };
+
+    // Mount the key device only for the time of reading the keyfile
+    let fstype = blkid.by_path(&dev).map(|d| d.get_fstype()).unwrap_or_default();
+    fs::create_dir_all(KEYDEV_MPT)?;
+    mount::mount(
+        Some(dev.as_str()),
+        KEYDEV_MPT,
+        Some(fstype),
+        MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        Option::<&str>::None,
+    )
+    .map_err(|err| Error::new(ErrorKind::NotConnected, format!("Failed to mount the key device {}: {}", dev, err)))?;
+
+    let key = read_keyfile(&Path::new(KEYDEV_MPT).join(kf.trim_start_matches('/')), c);
+    mount::umount(KEYDEV_MPT)?;
+    fs::remove_dir(KEYDEV_MPT).unwrap_or_default();
+
+    key.map(Some)
+}
+
+/// Read the key from a keyfile or a device at the configured offset and size
+fn read_keyfile(p: &Path, c: &MhConfCrypt) -> Result<Vec<u8>, Error> {
+    let mut f = File::open(p)?;
+    f.seek(SeekFrom::Start(c.get_keyoffset()))?;
+
+    let mut key: Vec<u8> = Vec::default();
+    match c.get_keysize() {
+        Some(size) => {
+            key.resize(size as usize, 0);
+            f.read_exact(&mut key)?;
+        }
+        None => {
+            f.read_to_end(&mut key)?;
+        }
+    }
+
+    Ok(key)
+}
+
+/// Ask the passphrase on the console until it fits or the attempts are over
+fn unlock_interactive(luks: &Luks2, dev: &str, name: &str) -> Result<Vec<u8>, Error> {
+    for _ in 0..PASSPHRASE_ATTEMPTS {
+        let mut pwd = read_passphrase(&format!("Passphrase for {} ({}): ", dev, name))?.into_bytes();
+        let ret = luks.unlock(&pwd);
+        pwd.fill(0);
+
+        match ret {
+            Ok(key) => return Ok(key),
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => println!("Wrong passphrase"),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Err(Error::new(ErrorKind::PermissionDenied, format!("Unable to unlock {}", dev)))
+}
+
+/// Read a line from the console without echo
+fn read_passphrase(prompt: &str) -> Result<String, Error> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+
+    let stdin = io::stdin();
+    let tty = termios::tcgetattr(&stdin).ok();
+    if let Some(tty) = &tty {
+        let mut noecho = tty.clone();
+        noecho.local_flags.remove(LocalFlags::ECHO);
+        termios::tcsetattr(&stdin, SetArg::TCSANOW, &noecho)?;
+    }
+
+    let mut pwd = String::new();
+    let ret = stdin.lock().read_line(&mut pwd);
+    if let Some(tty) = &tty {
+        termios::tcsetattr(&stdin, SetArg::TCSANOW, tty)?;
+    }
+    println!();
+
+    if ret? == 0 {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "Console input is closed"));
+    }
+
+    let len = pwd.trim_end_matches(['\n', '\r']).len();
+    pwd.truncate(len);
+
+    Ok(pwd)
+}
@@ -0,0 +1,49 @@
+use crate::microhop::mount_run;
+use nix::mount::{self, MntFlags};
+use std::{
+    fs,
+    io::{Error, ErrorKind},
+    path::Path,
+};
+use syslib::{blk::BlkInfo, loopdev};
+
+/// Carrier partitions of the images, which are kept under /run in the new root
+static CARRIER_DIR: &str = "/run/microhop/carrier";
+
+/// Mount the carrier partition, unless it is already mounted, and attach the image on it to a loop device,
+/// unless it is already attached, e.g. by an earlier attempt to mount the disks.
+/// Returns path to the loop device.
+pub fn attach(blkid: &BlkInfo, carrier: &str, image: &str) -> Result<String, Error> {
+    mount_run()?;
+
+    let mpt = Path::new(CARRIER_DIR).join(Path::new(carrier).file_name().unwrap_or_default());
+    let mpt = mpt.to_str().unwrap_or_default();
+    let mounted = syslib::fs::is_mountpoint(mpt);
+    if !mounted {
+        let Some(fstype) = blkid.by_path(carrier).map(|d| d.get_fstype().to_string()).filter(|f| !f.is_empty()) else {
+            return Err(Error::new(ErrorKind::InvalidData, format!("No filesystem on the image carrier {}", carrier)));
+        };
+
+        fs::create_dir_all(mpt)?;
+        syslib::fs::mount(&fstype, carrier, mpt, "")?;
+    }
+
+    let img = Path::new(mpt).join(image.trim_start_matches('/'));
+    if let Some(dev) = loopdev::find(&img) {
+        log::debug!("Image {} from {} is already attached to {}", image, carrier, dev.display());
+        return Ok(dev.to_str().unwrap_or_default().to_string());
+    }
+
+    match loopdev::attach(&img, true) {
+        Ok(dev) => {
+            log::info!("Attached image {} from {} to {}", image, carrier, dev.display());
+            Ok(dev.to_str().unwrap_or_default().to_string())
+        }
+        Err(err) => {
+            if !mounted {
+                mount::umount2(mpt, MntFlags::MNT_DETACH).unwrap_or_default();
+            }
+            Err(Error::new(err.kind(), format!("Unable to attach image {} from {}: {}", image, carrier, err)))
+        }
+    }
+}
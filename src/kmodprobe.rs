@@ -1,91 +1,622 @@
+use crate::cmdline::KernelCmdline;
 use nix::{
     errno::Errno,
     kmod::{self, ModuleInitFlags},
 };
+use profile::cfg::MhConfig;
 use std::{
+    collections::{HashMap, HashSet},
     ffi::CString,
-    fs::File,
-    io::{BufReader, Error, Read},
-    path::PathBuf,
+    fmt,
+    fs::{self, File},
+    io::Error,
+    path::{Path, PathBuf},
 };
+use syslib::compress::{self, Compression};
 use walkdir::WalkDir;
 
+static MOD_DEP_F: &str = "modules.dep";
+static MOD_ALIAS_F: &str = "modules.alias";
+static MOD_BUILTIN_F: &str = "modules.builtin";
+static MOD_COMPRESSION_F: &str = "/sys/module/compression";
+
+/// MODULE_INIT_COMPRESSED_FILE flag of finit_module(2), since Linux 5.17
+const MODULE_INIT_COMPRESSED_FILE: u32 = 4;
+
+/// Directories with modprobe configuration, the first ones take precedence for the same file names
+pub const MODPROBE_D: &[&str] = &["/etc/modprobe.d", "/run/modprobe.d", "/usr/lib/modprobe.d", "/lib/modprobe.d"];
+
+/// Result of loading a kernel module
+#[derive(Debug, Clone, PartialEq)]
+pub enum KModStatus {
+    Loaded,
+    AlreadyLoaded,
+    Builtin,
+    Blacklisted,
+    Missing,
+    Failed(String),
+}
+
+impl fmt::Display for KModStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KModStatus::Loaded => write!(f, "loaded"),
+            KModStatus::AlreadyLoaded => write!(f, "already loaded"),
+            KModStatus::Builtin => write!(f, "built into the kernel"),
+            KModStatus::Blacklisted => write!(f, "blacklisted"),
+            KModStatus::Missing => write!(f, "missing"),
+            KModStatus::Failed(err) => write!(f, "failed: {}", err),
+        }
+    }
+}
+
 /// Modprobe utility to load kernel modules.
 /// Currently it is looking for the modules only in /lib/modules/<kernel> directory,
 /// which is usually sufficient for the initramfs purposes.
 ///
-/// Modules are expected to be either uncompressed ELF binaries or compressed with ZStandard.
+/// Modules are resolved by their exact names via modules.dep or by their aliases
+/// via modules.alias, where "-" and "_" in the names are equivalent. Dependencies
+/// are loaded first, skipping those already loaded.
+///
+/// Module parameters and blacklists are taken from modprobe.d, the profile and
+/// the kernel command line (`<module>.<param>=` and `modprobe.blacklist=`), in that order.
+/// As with modprobe, a blacklist from modprobe.d only stops loading a module by its alias
+/// or by autodetection, while the kernel command line blacklist stops loading it at all.
+///
+/// Modules can be uncompressed ELF binaries or compressed with ZStandard, XZ or GZip.
+/// If the kernel can decompress modules itself, it is done in the kernel for the
+/// modules in its compression, and in the userspace for all others.
 pub struct KModProbe {
     km_path: PathBuf,
+
+    // Module name -> path to the module, relative to the modules directory
+    modules: HashMap<String, PathBuf>,
+
+    // Module name -> names of all its dependencies, as listed in modules.dep
+    deps: HashMap<String, Vec<String>>,
+
+    // Alias pattern -> module name
+    aliases: Vec<(String, String)>,
+
+    // Names of the modules, built into the kernel
+    builtin: HashSet<String>,
+
+    // Module name -> parameters
+    options: HashMap<String, Vec<String>>,
+
+    // Names of the modules, which should not be loaded by their aliases or autodetected
+    blacklist: HashSet<String>,
+
+    // Names of the modules, which should not be loaded at all
+    kblacklist: HashSet<String>,
+
+    // Compression, which the kernel decompresses itself
+    kcompression: Option<Compression>,
+
+    // Driver subsystems, which are autodetected
+    subsystems: Vec<String>,
 }
 
 impl KModProbe {
-    pub fn new() -> Self {
-        KModProbe { km_path: PathBuf::from(format!("/lib/modules/{}", uname::uname().unwrap().release)) }
+    pub fn new(cfg: &MhConfig) -> Self {
+        let mut mpb = Self::from_index(
+            PathBuf::from(format!("/lib/modules/{}", uname::uname().unwrap().release)),
+            cfg.get_autodetect_subsystems(),
+        );
+        mpb.load_modprobe_d();
+
+        for m in cfg.get_modules() {
+            if let Some(params) = cfg.get_module_params(&m) {
+                mpb.add_options(&m, params);
+            }
+        }
+
+        if let Ok(kcl) = KernelCmdline::load() {
+            for (m, params) in kcl.get_module_params() {
+                mpb.add_options(&m, &params);
+            }
+            mpb.kblacklist.extend(kcl.get_module_blacklist().iter().map(|m| Self::get_modname(m)));
+        }
+
+        mpb
+    }
+
+    /// Create from the index of a modules directory only, without any configuration
+    fn from_index(km_path: PathBuf, subsystems: Vec<&str>) -> Self {
+        let mut mpb = KModProbe {
+            km_path,
+            modules: HashMap::default(),
+            deps: HashMap::default(),
+            aliases: Vec::default(),
+            builtin: HashSet::default(),
+            options: HashMap::default(),
+            blacklist: HashSet::default(),
+            kblacklist: HashSet::default(),
+            kcompression: fs::read_to_string(MOD_COMPRESSION_F).ok().and_then(|c| Compression::from_name(&c)),
+            subsystems: subsystems.iter().map(|s| s.to_string()).collect(),
+        };
+
+        if let Err(err) = mpb.load_index() {
+            log::warn!("Unable to load kernel modules index: {}", err);
+        }
+
+        mpb
+    }
+
+    /// Add parameters of a module
+    fn add_options(&mut self, name: &str, params: &str) {
+        self.options.entry(Self::get_modname(name)).or_default().push(params.to_string());
+    }
+
+    /// Load "options" and "blacklist" commands from modprobe.d configuration files
+    fn load_modprobe_d(&mut self) {
+        let mut conf: HashMap<String, PathBuf> = HashMap::default();
+        for d in MODPROBE_D.iter().rev() {
+            for e in fs::read_dir(d).into_iter().flatten().flatten() {
+                if e.file_name().to_string_lossy().ends_with(".conf") {
+                    conf.insert(e.file_name().to_string_lossy().to_string(), e.path());
+                }
+            }
+        }
+
+        let mut names = conf.keys().cloned().collect::<Vec<String>>();
+        names.sort();
+        for n in names {
+            if let Ok(data) = fs::read_to_string(&conf[&n]) {
+                self.add_modprobe_conf(&data);
+            }
+        }
+    }
+
+    /// Add "options" and "blacklist" commands of one modprobe.d configuration file
+    fn add_modprobe_conf(&mut self, data: &str) {
+        for l in data.replace("\\\n", " ").lines() {
+            match l.split_whitespace().collect::<Vec<&str>>()[..] {
+                ["options", modname, ref params @ ..] if !params.is_empty() => self.add_options(modname, &params.join(" ")),
+                ["blacklist", modname] => {
+                    self.blacklist.insert(Self::get_modname(modname));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Normalise module name: "-" and "_" are equivalent, and the name is without any extensions
+    fn get_modname(name: &str) -> String {
+        Path::new(name).file_name().unwrap_or_default().to_string_lossy().split('.').next().unwrap_or_default().replace('-', "_")
     }
 
-    fn feedback(e: Result<(), Errno>, name: &str) {
-        if let Err(e) = e {
-            match e {
-                Errno::EEXIST => {
-                    log::warn!("{}: already loaded", name);
+    /// Load modules.dep, modules.alias and modules.builtin.
+    /// Missing index files are not an error, as long as the modules can still be found on the disk.
+    fn load_index(&mut self) -> Result<(), Error> {
+        let dep_f = self.km_path.join(MOD_DEP_F);
+        if dep_f.exists() {
+            for l in fs::read_to_string(dep_f)?.lines() {
+                if let Some((mp, deps)) = l.split_once(':') {
+                    let modname = Self::get_modname(mp);
+                    self.deps.insert(modname.to_owned(), deps.split_whitespace().map(Self::get_modname).collect());
+                    self.modules.insert(modname, PathBuf::from(mp.trim()));
                 }
-                _ => {
-                    log::error!("Error loading module: {}", e);
+            }
+        }
+
+        let alias_f = self.km_path.join(MOD_ALIAS_F);
+        if alias_f.exists() {
+            for l in fs::read_to_string(alias_f)?.lines() {
+                if let ["alias", pattern, modname] = l.split_whitespace().collect::<Vec<&str>>()[..] {
+                    self.aliases.push((pattern.to_string(), Self::get_modname(modname)));
                 }
             }
         }
+
+        let builtin_f = self.km_path.join(MOD_BUILTIN_F);
+        if builtin_f.exists() {
+            self.builtin.extend(fs::read_to_string(builtin_f)?.lines().map(Self::get_modname));
+        }
+
+        Ok(())
     }
 
-    /// Load a kernel module
-    pub fn modprobe(&self, name: &str) {
-        let mp: PathBuf = if !name.contains('/') || !name.contains('.') {
-            self.find_module(name).unwrap_or_default()
-        } else {
-            self.km_path.join(name)
+    /// Get names of the currently loaded modules
+    fn get_loaded() -> HashSet<String> {
+        fs::read_to_string("/proc/modules")
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|l| l.split_whitespace().next().map(|m| m.to_string()))
+            .collect()
+    }
+
+    /// Load a kernel module with all its dependencies
+    pub fn modprobe(&self, name: &str) -> KModStatus {
+        let loaded = Self::get_loaded();
+
+        // Path to a module file, relative to the modules directory
+        if name.contains('/') && name.contains('.') {
+            return self.load(&self.km_path.join(name), &loaded);
+        }
+
+        let order = match self.get_load_order(name) {
+            Ok(order) => order,
+            Err(status) => return status,
         };
 
-        let mps = mp.file_name().unwrap().to_string_lossy().into_owned();
-        let modname = mps.split('.').collect::<Vec<&str>>()[0];
+        let (mp, deps) = order.split_last().unwrap();
+        for dp in deps {
+            match self.load(dp, &loaded) {
+                KModStatus::Loaded | KModStatus::AlreadyLoaded | KModStatus::Builtin => {}
+                status => {
+                    return KModStatus::Failed(format!("dependency {} is {}", Self::get_modname(&dp.to_string_lossy()), status))
+                }
+            }
+        }
+
+        self.load(mp, &loaded)
+    }
 
-        if mps.ends_with(".zst") {
-            KModProbe::feedback(kmod::init_module(&self.unzstd(mp.clone()).unwrap(), &CString::new("").unwrap()), modname);
-        } else if mp.exists() {
-            KModProbe::feedback(
-                kmod::finit_module(File::open(mp.clone()).unwrap(), &CString::new("").unwrap(), ModuleInitFlags::empty()),
-                modname,
-            );
-        } else {
-            log::error!("Kernel module {} not found", mp.as_os_str().to_str().unwrap());
+    /// Resolve a module into the files to load: its dependencies first, then the module itself.
+    /// Returns the status instead, if the module cannot be loaded.
+    fn get_load_order(&self, name: &str) -> Result<Vec<PathBuf>, KModStatus> {
+        let Some(mp) = self.resolve(name) else {
+            if self.builtin.contains(&Self::get_modname(name)) {
+                return Err(KModStatus::Builtin);
+            }
+            return Err(KModStatus::Missing);
+        };
+
+        let modname = Self::get_modname(&mp.to_string_lossy());
+        if self.kblacklist.contains(&modname) || (modname != Self::get_modname(name) && self.blacklist.contains(&modname)) {
+            return Err(KModStatus::Blacklisted);
+        }
+
+        // In modules.dep the last dependency has to be loaded first
+        let mut order: Vec<PathBuf> = Vec::default();
+        for dep in self.deps.get(&modname).into_iter().flatten().rev() {
+            match self.modules.get(dep) {
+                Some(_) if self.kblacklist.contains(dep) => {
+                    return Err(KModStatus::Failed(format!("dependency {} is {}", dep, KModStatus::Blacklisted)))
+                }
+                Some(dp) => order.push(self.km_path.join(dp)),
+                None => return Err(KModStatus::Failed(format!("dependency {} is {}", dep, KModStatus::Missing))),
+            }
+        }
+        order.push(mp);
+
+        Ok(order)
+    }
+
+    /// Load one kernel module file, unless it is already loaded
+    fn load(&self, mp: &Path, loaded: &HashSet<String>) -> KModStatus {
+        let modname = Self::get_modname(&mp.to_string_lossy());
+        if loaded.contains(&modname) {
+            return KModStatus::AlreadyLoaded;
+        }
+
+        if !mp.exists() {
+            return KModStatus::Missing;
+        }
+
+        let params = CString::new(self.options.get(&modname).map(|o| o.join(" ")).unwrap_or_default()).unwrap_or_default();
+        if !params.is_empty() {
+            log::debug!("{}: parameters \"{}\"", modname, params.to_string_lossy());
+        }
+        let ret = match Compression::from_path(mp) {
+            Compression::None => match File::open(mp) {
+                Ok(f) => kmod::finit_module(f, &params, ModuleInitFlags::empty()),
+                Err(err) => return KModStatus::Failed(err.to_string()),
+            },
+            c if self.kcompression == Some(c) => match File::open(mp) {
+                Ok(f) => kmod::finit_module(f, &params, ModuleInitFlags::from_bits_retain(MODULE_INIT_COMPRESSED_FILE)),
+                Err(err) => return KModStatus::Failed(err.to_string()),
+            },
+            _ => match compress::decompress(mp) {
+                Ok(data) => kmod::init_module(&data, &params),
+                Err(err) => return KModStatus::Failed(err.to_string()),
+            },
+        };
+
+        match ret {
+            Ok(_) => {
+                log::debug!("{}: loaded", modname);
+                KModStatus::Loaded
+            }
+            Err(Errno::EEXIST) => KModStatus::AlreadyLoaded,
+            Err(err) => KModStatus::Failed(err.to_string()),
         }
     }
 
-    /// Decompress a zstd binary into a blob in a memory
-    fn unzstd(&self, p: PathBuf) -> Result<Vec<u8>, Error> {
-        let mut dec = zstd::Decoder::new(BufReader::new(File::open(p).unwrap())).unwrap();
-        let mut buff = [0u8; 0x1000];
-        let mut data: Vec<u8> = Vec::new();
+    /// Returns true if a module is a driver of one of the autodetected subsystems
+    fn is_autodetected(&self, modname: &str) -> bool {
+        self.modules.get(modname).is_some_and(|mp| {
+            let mp = mp.to_string_lossy();
+            self.subsystems.iter().any(|s| mp.contains(&format!("drivers/{}/", s)))
+        })
+    }
+
+    /// Find drivers for the present hardware, matching modalias of each device in sysfs against modules.alias.
+    /// Only drivers of the autodetected subsystems are returned.
+    pub fn autodetect(&self) -> Vec<String> {
+        let mut found: Vec<String> = Vec::default();
+        for e in WalkDir::new("/sys/devices").into_iter().flatten().filter(|e| e.file_name() == "modalias") {
+            let Ok(modalias) = fs::read_to_string(e.path()) else {
+                continue;
+            };
+
+            for (pattern, modname) in &self.aliases {
+                if !found.contains(modname) && fnmatch(pattern, modalias.trim()) && self.is_autodetected(modname) {
+                    found.push(modname.to_owned());
+                }
+            }
+        }
+
+        found
+    }
 
+    /// Load drivers for the present hardware, which were not tried yet.
+    /// New devices may appear after their bus drivers are loaded, so it can be called repeatedly.
+    pub fn coldplug(&self, tried: &mut HashSet<String>) -> Vec<(String, KModStatus)> {
+        let mut results: Vec<(String, KModStatus)> = Vec::default();
         loop {
-            match dec.read(&mut buff) {
-                Ok(0) => break,
-                Ok(bts) => data.extend(&buff[..bts]),
-                Err(e) => return Err(e),
+            let detected = self.autodetect().into_iter().filter(|m| !tried.contains(m)).collect::<Vec<String>>();
+            if detected.is_empty() {
+                break;
             }
+
+            for m in detected {
+                tried.insert(m.to_owned());
+                let status = if self.blacklist.contains(&m) { KModStatus::Blacklisted } else { self.modprobe(&m) };
+                if status != KModStatus::AlreadyLoaded {
+                    results.push((m, status));
+                }
+            }
+        }
+
+        results
+    }
+
+    /// Resolve a module name or its alias to the module path
+    pub fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let modname = Self::get_modname(name);
+        if let Some(mp) = self.modules.get(&modname) {
+            return Some(self.km_path.join(mp));
+        }
+
+        if let Some((_, modname)) = self.aliases.iter().find(|(pattern, _)| fnmatch(pattern, name)) {
+            log::debug!("{} is an alias of {}", name, modname);
+            return self.modules.get(modname).map(|mp| self.km_path.join(mp));
+        }
+
+        // Without the index, look for the module on the filesystem
+        if self.modules.is_empty() {
+            return self.find_module(&modname);
         }
 
-        Ok(data)
+        None
     }
 
     /// Find module on the filesystem, which corresponds to the current kernel
-    fn find_module(&self, name: &str) -> Option<PathBuf> {
-        let mut p: Option<PathBuf> = None;
-        WalkDir::new(&self.km_path).into_iter().flat_map(|r| r.ok()).for_each(|e| {
-            if p.is_none() && e.path().is_file() && e.path().file_name().unwrap().to_str().unwrap().contains(name) {
-                p = Some(e.path().to_path_buf());
+    fn find_module(&self, modname: &str) -> Option<PathBuf> {
+        WalkDir::new(&self.km_path)
+            .into_iter()
+            .flat_map(|r| r.ok())
+            .find(|e| {
+                e.path().is_file()
+                    && e.path().to_string_lossy().contains(".ko")
+                    && Self::get_modname(&e.path().to_string_lossy()) == modname
+            })
+            .map(|e| e.path().to_path_buf())
+    }
+}
+
+/// Report results of loading kernel modules
+pub fn report(results: &[(String, KModStatus)]) {
+    let (mut loaded, mut present, mut blacklisted, mut missing, mut failed) = (0, 0, 0, 0, 0);
+    for (name, status) in results {
+        match status {
+            KModStatus::Loaded => loaded += 1,
+            KModStatus::AlreadyLoaded | KModStatus::Builtin => present += 1,
+            KModStatus::Blacklisted => blacklisted += 1,
+            KModStatus::Missing => missing += 1,
+            KModStatus::Failed(_) => failed += 1,
+        }
+
+        match status {
+            KModStatus::Missing | KModStatus::Failed(_) => log::error!("Kernel module {}: {}", name, status),
+            _ => log::debug!("Kernel module {}: {}", name, status),
+        }
+    }
+
+    log::info!(
+        "Kernel modules: {} loaded, {} already loaded, {} blacklisted, {} missing, {} failed",
+        loaded,
+        present,
+        blacklisted,
+        missing,
+        failed
+    );
+}
+
+/// Match a string against a shell wildcard pattern with "*", "?" and "[...]",
+/// as used in modules.alias
+fn fnmatch(pattern: &str, s: &str) -> bool {
+    let (p, s) = (pattern.as_bytes(), s.as_bytes());
+    let (mut pi, mut si) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while si < s.len() {
+        if pi < p.len() {
+            match p[pi] {
+                b'*' => {
+                    backtrack = Some((pi, si));
+                    pi += 1;
+                    continue;
+                }
+                b'?' => {
+                    pi += 1;
+                    si += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, len)) = match_class(&p[pi..], s[si]) {
+                        if matched {
+                            pi += len;
+                            si += 1;
+                            continue;
+                        }
+                    }
+                }
+                c if c == s[si] => {
+                    pi += 1;
+                    si += 1;
+                    continue;
+                }
+                _ => {}
             }
-        });
+        }
+
+        // Mismatch: let the last "*" swallow one more character
+        match backtrack {
+            Some((bpi, bsi)) => {
+                pi = bpi + 1;
+                si = bsi + 1;
+                backtrack = Some((bpi, bsi + 1));
+            }
+            None => return false,
+        }
+    }
+
+    p[pi..].iter().all(|c| *c == b'*')
+}
+
+/// Match a character against a "[...]" class at the beginning of the pattern.
+/// Returns the match and the length of the class, or `None` if the class is not closed.
+fn match_class(p: &[u8], c: u8) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negate = matches!(p.get(i), Some(b'!') | Some(b'^'));
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    while i < p.len() {
+        if p[i] == b']' && !first {
+            return Some((matched != negate, i + 1));
+        }
+
+        if i + 2 < p.len() && p[i + 1] == b'-' && p[i + 2] != b']' {
+            matched |= p[i] <= c && c <= p[i + 2];
+            i += 3;
+        } else {
+            matched |= p[i] == c;
+            i += 1;
+        }
+        first = false;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture() -> KModProbe {
+        KModProbe::from_index(Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/kmod"), vec!["net"])
+    }
+
+    fn modnames(mpb: &KModProbe, name: &str) -> Result<Vec<String>, KModStatus> {
+        Ok(mpb.get_load_order(name)?.iter().map(|p| KModProbe::get_modname(&p.to_string_lossy())).collect())
+    }
+
+    #[test]
+    fn resolve_exact_name() {
+        let mpb = fixture();
+        assert_eq!(mpb.resolve("ext4"), Some(mpb.km_path.join("kernel/fs/ext4/ext4.ko.zst")));
+        assert_eq!(mpb.resolve("ext4_test"), Some(mpb.km_path.join("kernel/fs/ext4_test.ko.zst")));
+        assert_eq!(mpb.resolve("ext"), None);
+        assert_eq!(mpb.resolve("ext4-inode"), None);
+    }
+
+    #[test]
+    fn resolve_dash_underscore() {
+        let mpb = fixture();
+        let dm_crypt = Some(mpb.km_path.join("kernel/drivers/md/dm-crypt.ko.zst"));
+        assert_eq!(mpb.resolve("dm-crypt"), dm_crypt);
+        assert_eq!(mpb.resolve("dm_crypt"), dm_crypt);
+        assert_eq!(mpb.resolve("virtio-blk"), Some(mpb.km_path.join("kernel/drivers/block/virtio_blk.ko.xz")));
+        assert_eq!(mpb.resolve("ext4_inode_test"), Some(mpb.km_path.join("kernel/fs/ext4/ext4-inode-test.ko.zst")));
+    }
+
+    #[test]
+    fn resolve_alias() {
+        let mpb = fixture();
+        assert_eq!(mpb.resolve("fs-ext4"), mpb.resolve("ext4"));
+        assert_eq!(mpb.resolve("ext3"), mpb.resolve("ext4"));
+        assert_eq!(mpb.resolve("pci:v00008086d000010D3sv00008086sd0000A01Fbc02sc00i00"), mpb.resolve("e1000e"));
+        assert_eq!(mpb.resolve("pci:v00008086d000010D4sv00008086sd0000A01Fbc02sc00i00"), None);
+        assert_eq!(mpb.resolve("pci:v000010DEd00001C82sv00001458sd00003763bc03sc00i00"), mpb.resolve("nouveau"));
+        assert_eq!(mpb.resolve("pci:v000010DEd00001C82sv00001458sd00003763bc03sc01i00"), None);
+
+        // Alias of a module, which is not in the index
+        assert_eq!(mpb.resolve("crypto-aes"), None);
+    }
+
+    #[test]
+    fn fnmatch_patterns() {
+        assert!(fnmatch("virtio:d00000002v*", "virtio:d00000002v00001AF4"));
+        assert!(fnmatch("a?c", "abc"));
+        assert!(!fnmatch("a?c", "ac"));
+        assert!(fnmatch("*sc0[02]i*", "bc03sc02i00"));
+        assert!(!fnmatch("*sc0[02]i*", "bc03sc01i00"));
+        assert!(fnmatch("x[a-c]y", "xby"));
+        assert!(!fnmatch("x[!a-c]y", "xby"));
+        assert!(fnmatch("*", ""));
+        assert!(!fnmatch("abc", "abcd"));
+    }
+
+    #[test]
+    fn dependency_order() {
+        let mpb = fixture();
+        assert_eq!(modnames(&mpb, "e1000e"), Ok(vec!["pps_core".to_string(), "ptp".to_string(), "e1000e".to_string()]));
+        assert_eq!(modnames(&mpb, "dm_crypt"), Ok(vec!["dm_mod".to_string(), "dm_crypt".to_string()]));
+        assert_eq!(modnames(&mpb, "jbd2"), Ok(vec!["jbd2".to_string()]));
+        assert_eq!(modnames(&mpb, "nouveau"), Err(KModStatus::Failed("dependency drm is missing".to_string())));
+    }
+
+    #[test]
+    fn blacklist() {
+        let mut mpb = fixture();
+        mpb.add_modprobe_conf(&fs::read_to_string(mpb.km_path.join("blacklist.conf")).unwrap());
+
+        // Only loading by an alias is stopped
+        assert_eq!(modnames(&mpb, "pci:v000010DEd00001C82sv00001458sd00003763bc03sc00i00"), Err(KModStatus::Blacklisted));
+        assert_eq!(modnames(&mpb, "nouveau"), Err(KModStatus::Failed("dependency drm is missing".to_string())));
+        assert_eq!(modnames(&mpb, "pps_core"), Ok(vec!["pps_core".to_string()]));
+        assert_eq!(modnames(&mpb, "pps-core"), Ok(vec!["pps_core".to_string()]));
+        assert_eq!(modnames(&mpb, "e1000e"), Ok(vec!["pps_core".to_string(), "ptp".to_string(), "e1000e".to_string()]));
+        assert_eq!(mpb.options.get("dm_crypt"), Some(&vec!["max_read_size=65536".to_string()]));
+    }
+
+    #[test]
+    fn kernel_blacklist() {
+        let mut mpb = fixture();
+        mpb.kblacklist.insert("pps_core".to_string());
+
+        assert_eq!(modnames(&mpb, "pps-core"), Err(KModStatus::Blacklisted));
+        assert_eq!(modnames(&mpb, "e1000e"), Err(KModStatus::Failed("dependency pps_core is blacklisted".to_string())));
+        assert_eq!(
+            modnames(&mpb, "pci:v00008086d000010D3sv00008086sd0000A01Fbc02sc00i00"),
+            Err(KModStatus::Failed("dependency pps_core is blacklisted".to_string()))
+        );
+        assert_eq!(modnames(&mpb, "dm_crypt"), Ok(vec!["dm_mod".to_string(), "dm_crypt".to_string()]));
+    }
 
-        p
+    #[test]
+    fn builtin() {
+        let mpb = fixture();
+        assert_eq!(modnames(&mpb, "vfat"), Err(KModStatus::Builtin));
+        assert_eq!(modnames(&mpb, "xhci_hcd"), Err(KModStatus::Builtin));
+        assert_eq!(modnames(&mpb, "xhci-hcd"), Err(KModStatus::Builtin));
+        assert_eq!(modnames(&mpb, "nosuchmodule"), Err(KModStatus::Missing));
     }
 }
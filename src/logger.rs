@@ -1,5 +1,9 @@
 use colored::{self, Colorize};
 use log::{Level, Metadata, Record};
+use std::sync::Mutex;
+
+/// Everything logged so far, so it can be shown again in the rescue console
+static HISTORY: Mutex<Vec<String>> = Mutex::new(Vec::new());
 
 pub(crate) struct STDOUTLogger;
 
@@ -21,9 +25,19 @@ impl log::Log for STDOUTLogger {
 
             let tsb = nix::time::clock_gettime(nix::time::ClockId::CLOCK_BOOTTIME);
             let dsb = std::time::Duration::from(tsb.unwrap());
-            println!("[{:12.6}][{:>6}][Microhop] {}", dsb.as_secs_f32(), format!("T{}", dsb.as_secs()), l_msg);
+            let line = format!("[{:12.6}][{:>6}][Microhop] {}", dsb.as_secs_f32(), format!("T{}", dsb.as_secs()), l_msg);
+            println!("{}", line);
+
+            if let Ok(mut h) = HISTORY.lock() {
+                h.push(line);
+            }
         }
     }
 
     fn flush(&self) {}
 }
+
+/// Return all messages, logged so far
+pub(crate) fn history() -> Vec<String> {
+    HISTORY.lock().map(|h| h.to_owned()).unwrap_or_default()
+}
@@ -0,0 +1,67 @@
+use crate::microhop::wait_blk;
+use profile::cfg::MhConfig;
+use std::io::{Error, ErrorKind};
+use syslib::{
+    dm,
+    lvm::{self, LvmPv},
+};
+
+/// Type of the physical volumes, as detected by blkid
+static LVM_MEMBER: &str = "LVM2_member";
+
+/// Activate logical volumes, referred by the given devices as "vg/lv" or "/dev/mapper/vg-lv".
+/// Waits until all their physical volumes appear.
+pub fn activate(cfg: &MhConfig, devices: &[&str]) -> Result<(), Error> {
+    // Encrypted and verified devices are never volumes, even if their names look so
+    let is_mapped = |name: &str| cfg.get_crypt().contains_key(name) || cfg.get_verity().contains_key(name);
+    let mut pending = devices
+        .iter()
+        .filter(|d| !d.strip_prefix("/dev/mapper/").is_some_and(is_mapped))
+        .filter_map(|d| lvm::parse_lv_ref(d).map(|(vg, lv)| (d.to_string(), vg, lv)))
+        .filter(|(_, vg, lv)| {
+            let name = lvm::get_dm_name(vg, lv);
+            !dm::exists(&name) && !is_mapped(&name)
+        })
+        .collect::<Vec<(String, String, String)>>();
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    wait_blk(cfg, cfg.get_rootwait(), |blkid| {
+        let pvs = blkid
+            .get_devices()
+            .iter()
+            .filter(|d| d.get_fstype() == LVM_MEMBER)
+            .filter_map(|d| match LvmPv::open(d.get_path()) {
+                Ok(pv) => Some(pv),
+                Err(err) => {
+                    log::warn!("{}", err);
+                    None
+                }
+            })
+            .collect::<Vec<LvmPv>>();
+
+        let mut missing: Vec<String> = Vec::default();
+        for (dev, vg, lv) in std::mem::take(&mut pending) {
+            // A label with a slash, e.g. "boot/efi", is not a volume
+            if blkid.by_label(&dev).is_some() {
+                log::debug!("{} is a label, not a logical volume", dev);
+                continue;
+            }
+
+            match lvm::activate(&vg, &lv, &pvs) {
+                Ok(node) => log::info!("Activated logical volume {}/{} as {}", vg, lv, node.display()),
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    log::debug!("{}", err);
+                    missing.push(format!("{}/{}", vg, lv));
+                    pending.push((dev, vg, lv));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(missing)
+    })?;
+
+    Ok(())
+}
@@ -1,27 +1,52 @@
+mod cmdline;
+mod console;
+mod crypt;
+mod image;
 mod kmodprobe;
 mod logger;
+mod lvm;
+mod md;
 mod microhop;
+mod network;
+mod nfs;
+mod overlay;
+mod rescue;
+mod slot;
+mod verity;
 
-use crate::microhop::{get_blk_devices, greet, mount_fs, SYS_MPT};
+use crate::microhop::{greet, mount_fs, mount_sysroot, RUN_MPT, SYS_MPT};
 use nix::{mount::MsFlags, sys::stat, unistd};
-use std::{ffi::CString, io::Error, path::Path};
+use std::{
+    ffi::CString,
+    io::{Error, ErrorKind},
+    path::Path,
+};
 
 static LOGGER: logger::STDOUTLogger = logger::STDOUTLogger;
 
 fn main() -> Result<(), Error> {
     // Set logger
-    let cfg = profile::cfg::get_mh_config(None)?;
+    let mut cfg = profile::cfg::get_mh_config(None)?;
     log::set_logger(&LOGGER).map(|()| log::set_max_level(cfg.get_log_level())).unwrap();
 
+    // Mount system dirs, so the kernel command line and devices are available
+    mount_fs(SYS_MPT).unwrap_or_default();
+
+    // Kernel command line takes precedence over the profile
+    let kcl = cmdline::KernelCmdline::load().unwrap_or_else(|err| {
+        log::warn!("Unable to read kernel command line: {}", err);
+        cmdline::KernelCmdline::parse("")
+    });
+    kcl.overlay(&mut cfg)?;
+    log::set_max_level(cfg.get_log_level());
+
     greet(&cfg)?;
+    console::breakpoint(&kcl, &cfg, "premodules")?;
 
     // Load required modules
-    let mpb = kmodprobe::KModProbe::new();
-    for mname in cfg.get_modules() {
-        mpb.modprobe(mname);
-    }
     if !cfg.get_modules().is_empty() {
-        log::info!("loaded required kernel modules");
+        let mpb = kmodprobe::KModProbe::new(&cfg);
+        kmodprobe::report(&cfg.get_modules().iter().map(|m| (m.to_string(), mpb.modprobe(m))).collect::<Vec<_>>());
     }
 
     // Create sysroot entry point
@@ -31,33 +56,66 @@ fn main() -> Result<(), Error> {
         log::debug!("Init sysroot path: {}", temp_mpt);
     }
 
-    mount_fs(SYS_MPT);
+    // Mount disks into the sysroot
+    console::breakpoint(&kcl, &cfg, "premount")?;
 
-    let (root_fstype, blk_mpt) = get_blk_devices(&cfg)?;
-    if root_fstype.is_empty() {
-        log::error!("Type of the root filesystem was not detected. Please double-check the configuration!");
+    // Network goes first, as the disks can be on it
+    let ifaces = rescue::attempt(&cfg, || network::setup(&cfg))?;
+
+    // Root device of the chosen A/B slot replaces the configured one.
+    // The boot try is taken only once, even if choosing the slot is retried in the rescue mode.
+    if let Some(active) = rescue::attempt(&cfg, || slot::take_try(&cfg))? {
+        let dev = rescue::attempt(&cfg, || slot::select(&cfg, &active))?;
+        let Some(root) = cfg.get_root_disk()? else {
+            return Err(Error::new(ErrorKind::NotFound, "Root disk is not configured for the slots"));
+        };
+        cfg.set_root_disk(&dev, root.get_fstype(), root.get_mode())?;
     }
-    mount_fs(&blk_mpt);
+
+    rescue::attempt(&cfg, || {
+        // Arrays go first, as both logical volumes and encrypted devices can be on top of them.
+        // Logical volumes can be both under and on top of the encrypted devices.
+        md::assemble(&cfg)?;
+        lvm::activate(&cfg, &cfg.get_crypt().values().map(|c| c.get_device()).collect::<Vec<&str>>())?;
+        crypt::unlock(&cfg)?;
+        lvm::activate(&cfg, &cfg.get_disks()?.iter().map(|d| d.get_device()).collect::<Vec<&str>>())?;
+        lvm::activate(
+            &cfg,
+            &cfg.get_verity().values().flat_map(|v| [v.get_device(), v.get_hashdevice()]).collect::<Vec<&str>>(),
+        )?;
+        verity::activate(&cfg)
+    })?;
+    let root_fstype = rescue::attempt(&cfg, || mount_sysroot(&cfg))?;
 
     // Remount sysfs, switch root
-    log::debug!("switching root");
-    for t in SYS_MPT {
-        let tgt = format!("{}{}", temp_mpt, t.dst);
-        nix::mount::mount(Some(t.dst), tgt.as_str(), Some(t.fstype), MsFlags::MS_MOVE, Option::<&str>::None)?;
-    }
+    console::breakpoint(&kcl, &cfg, "prepivot")?;
+    rescue::attempt(&cfg, || {
+        log::debug!("switching root");
+        for t in SYS_MPT {
+            let tgt = format!("{}{}", temp_mpt, t.dst);
+            nix::mount::mount(Some(t.dst), tgt.as_str(), Some(t.fstype), MsFlags::MS_MOVE, Option::<&str>::None)?;
+        }
 
-    // Pivot the system
-    syslib::fs::pivot(temp_mpt, root_fstype.as_str())?;
+        // Runtime state, such as the overlay layers, is kept for the system
+        if syslib::fs::is_mountpoint(RUN_MPT) {
+            let tgt = format!("{}{}", temp_mpt, RUN_MPT);
+            std::fs::create_dir_all(&tgt)?;
+            nix::mount::mount(Some(RUN_MPT), tgt.as_str(), Option::<&str>::None, MsFlags::MS_MOVE, Option::<&str>::None)?;
+        }
 
-    // Start external init
-    log::info!("Launching init at {}", cfg.get_init_path());
+        // Pivot the system
+        syslib::fs::pivot(temp_mpt, root_fstype.as_str())
+    })?;
 
-    let argv: Vec<CString> = vec![CString::new(cfg.get_init_path()).unwrap()];
+    // Network configuration is either torn down or kept for the system
+    rescue::attempt(&cfg, || network::finish(&cfg, &ifaces))?;
 
-    #[allow(irrefutable_let_patterns)]
-    if let Err(err) = unistd::execv(&CString::new(cfg.get_init_path()).unwrap(), &argv) {
-        log::error!("{:?}", err);
-    }
+    // Start external init
+    rescue::attempt(&cfg, || {
+        log::info!("Launching init at {}", cfg.get_init_path());
 
-    Ok(())
+        let init = CString::new(cfg.get_init_path())?;
+        let Err(err) = unistd::execv(&init, &[&init]);
+        Err(Error::new(ErrorKind::NotFound, format!("Unable to launch init at {}: {}", cfg.get_init_path(), err)))
+    })
 }
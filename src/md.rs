@@ -0,0 +1,72 @@
+use crate::{
+    kmodprobe::{KModProbe, KModStatus},
+    microhop::wait_blk,
+};
+use profile::cfg::MhConfig;
+use std::{io::Error, time::Instant};
+use syslib::md::{self, MdMember};
+
+/// Type of the array members, as detected by blkid
+static MD_MEMBER: &str = "linux_raid_member";
+
+/// Assemble all configured arrays, which are not assembled yet.
+/// Waits until all their members appear, or assembles an array degraded after its timeout.
+/// The wait is extended to the longest degraded timeout, if that is longer than rootwait.
+/// Members with stale data are left out, spares do not count towards the complete array.
+pub fn assemble(cfg: &MhConfig) -> Result<(), Error> {
+    let mut pending = cfg.get_raid().iter().filter(|(name, _)| !md::exists(name)).collect::<Vec<_>>();
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    let mpb = KModProbe::new(cfg);
+    let started = Instant::now();
+
+    let timeout = cfg.get_rootwait().map(|t| pending.iter().filter_map(|(_, r)| r.get_degraded()).fold(t, |t, d| t.max(d)));
+    wait_blk(cfg, timeout, |blkid| {
+        let members = blkid
+            .get_devices()
+            .iter()
+            .filter(|d| d.get_fstype() == MD_MEMBER)
+            .filter_map(|d| match MdMember::open(d.get_path()) {
+                Ok(m) => Some(m),
+                Err(err) => {
+                    log::warn!("{}", err);
+                    None
+                }
+            })
+            .collect::<Vec<MdMember>>();
+
+        let mut missing: Vec<String> = Vec::default();
+        for (name, r) in std::mem::take(&mut pending) {
+            let uuid = md::normalise_uuid(r.get_uuid());
+            let found = md::get_recent(&members.iter().filter(|m| m.get_uuid() == uuid).collect::<Vec<&MdMember>>());
+            let complete = md::is_complete(&found);
+            let degraded = !found.is_empty() && r.get_degraded().is_some_and(|t| started.elapsed() >= t);
+
+            if !complete && !degraded {
+                missing.push(format!("{} ({}/{} members)", name, found.len(), found.first().map_or(0, |m| m.get_raid_disks())));
+                pending.push((name, r));
+                continue;
+            }
+
+            if let Some(p) = found.first().and_then(|m| m.get_personality()) {
+                match mpb.modprobe(p) {
+                    status @ (KModStatus::Missing | KModStatus::Failed(_)) => log::warn!("RAID personality {}: {}", p, status),
+                    status => log::debug!("RAID personality {}: {}", p, status),
+                }
+            }
+
+            let node = md::assemble(name, &found)?;
+            if complete {
+                log::info!("Assembled array {} as {}", name, node.display());
+            } else {
+                log::warn!("Assembled array {} as {} degraded with {} members", name, node.display(), found.len());
+            }
+        }
+
+        Ok(missing)
+    })?;
+
+    Ok(())
+}
@@ -1,28 +1,62 @@
-use profile::cfg::MhConfig;
-use std::io::Error;
-use syslib::blk::BlkInfo;
+use crate::{
+    image,
+    kmodprobe::{self, KModProbe},
+    nfs, overlay,
+};
+use nix::{
+    errno::Errno,
+    mount::{self, MsFlags},
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    sys::inotify::{AddWatchFlags, InitFlags, Inotify},
+};
+use profile::cfg::{MhConfDisk, MhConfig};
+use std::{
+    collections::HashSet,
+    fs,
+    io::{Error, ErrorKind},
+    os::fd::AsFd,
+    path::Path,
+    sync::Once,
+    thread,
+    time::{Duration, Instant},
+};
+use syslib::{
+    blk::{BlkDev, BlkInfo},
+    lvm,
+};
 use uuid::Uuid;
 
 static VERSION: &str = "0.1.0";
 
+/// Interval to re-probe block devices while waiting for them,
+/// as not every device change results to a new node in /dev
+const REPROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay before probing the devices happens only once
+static ROOTDELAY: Once = Once::new();
+
 pub struct SystemDir<T: AsRef<str>> {
     pub fstype: T,
     pub dev: T,
     pub dst: T,
+    pub opts: T,
 }
 
 impl<T: AsRef<str>> SystemDir<T> {
-    const fn new(fstype: T, dev: T, dst: T) -> Self {
-        Self { fstype, dev, dst }
+    const fn new(fstype: T, dev: T, dst: T, opts: T) -> Self {
+        Self { fstype, dev, dst, opts }
     }
 }
 
+/// Runtime state, which is moved to the new root along with the system dirs
+pub static RUN_MPT: &str = "/run";
+
 // Mount required system dirs
 pub const SYS_MPT: &[SystemDir<&'static str>] = &[
     // Has to go always first
-    SystemDir::new("proc", "none", "/proc"),
-    SystemDir::new("sysfs", "none", "/sys"),
-    SystemDir::new("devtmpfs", "devtmpfs", "/dev"),
+    SystemDir::new("proc", "none", "/proc", ""),
+    SystemDir::new("sysfs", "none", "/sys", ""),
+    SystemDir::new("devtmpfs", "devtmpfs", "/dev", ""),
 ];
 
 // Initial greetings
@@ -50,20 +84,200 @@ pub fn greet(cfg: &MhConfig) -> Result<(), Error> {
 }
 
 /// Mount configured filesystems in a batch
-pub fn mount_fs<T: AsRef<str>>(filesystems: &[SystemDir<T>]) {
+pub fn mount_fs<T: AsRef<str>>(filesystems: &[SystemDir<T>]) -> Result<(), Error> {
+    let mut failed: Vec<&str> = Vec::default();
     for t in filesystems {
-        if let Err(err) = syslib::fs::mount(t.fstype.as_ref(), t.dev.as_ref(), t.dst.as_ref()) {
+        if let Err(err) = syslib::fs::mount(t.fstype.as_ref(), t.dev.as_ref(), t.dst.as_ref(), t.opts.as_ref()) {
             log::error!("Error mounting {}: {}", t.dst.as_ref(), err);
+            failed.push(t.dst.as_ref());
         };
     }
+
+    if !failed.is_empty() {
+        return Err(Error::other(format!("Failed to mount {}", failed.join(", "))));
+    }
+
+    Ok(())
+}
+
+/// Un-mount filesystems of a batch in reverse order.
+/// Those, which are not mounted, are skipped.
+pub fn umount_fs<T: AsRef<str>>(filesystems: &[SystemDir<T>]) {
+    for t in filesystems.iter().rev() {
+        syslib::fs::umount(t.dst.as_ref()).unwrap_or_default();
+    }
+}
+
+/// Mount tmpfs at /run, unless it is already there.
+/// It is moved to the new root, so the state of the boot is kept for the system.
+pub fn mount_run() -> Result<(), Error> {
+    if syslib::fs::is_mountpoint(RUN_MPT) {
+        return Ok(());
+    }
+
+    fs::create_dir_all(RUN_MPT)?;
+    mount::mount(
+        Some("tmpfs"),
+        RUN_MPT,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_STRICTATIME,
+        Some("mode=0755"),
+    )
+    .map_err(|err| Error::new(ErrorKind::NotConnected, format!("Failed to mount tmpfs at {}: {}", RUN_MPT, err)))
+}
+
+/// Mount all configured disks into the sysroot.
+/// Returns the type of the root filesystem.
+pub fn mount_sysroot(cfg: &MhConfig) -> Result<String, Error> {
+    let (mut root_fstype, mut blk_mpt) = get_blk_devices(cfg)?;
+    if root_fstype.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            "Type of the root filesystem was not detected. Please double-check the configuration!",
+        ));
+    }
+
+    // Network root is mounted first, as everything else is mounted on top of it
+    let sysroot = cfg.get_sysroot_path();
+    let (nfs_root, nfs_disks): (Vec<MhConfDisk>, Vec<MhConfDisk>) =
+        cfg.get_disks()?.into_iter().filter(|d| d.is_nfs()).partition(|d| d.get_mountpoint().trim_end_matches('/').is_empty());
+    if let Some(root) = nfs_root.first() {
+        nfs::mount(cfg, root, &sysroot)?;
+    }
+
+    // Overlay root is mounted first as well
+    if let Some(upper) = cfg.get_root_disk()?.as_ref().and_then(|d| d.get_overlay()) {
+        if let Some(pos) = blk_mpt.iter().position(|t| t.dst == sysroot) {
+            overlay::mount(cfg, &blk_mpt.remove(pos), upper)?;
+            root_fstype = overlay::OVERLAY_FSTYPE.to_string();
+        }
+    }
+
+    let nfs_mpt = |d: &MhConfDisk| format!("{}{}", sysroot, d.get_mountpoint().trim_end_matches('/'));
+    let mut ret = mount_fs(&blk_mpt);
+    for d in &nfs_disks {
+        ret = ret.and_then(|_| nfs::mount(cfg, d, &nfs_mpt(d)));
+    }
+
+    if let Err(err) = ret {
+        for d in nfs_disks.iter().rev() {
+            syslib::fs::umount(&nfs_mpt(d)).unwrap_or_default();
+        }
+        umount_fs(&blk_mpt);
+        if root_fstype == overlay::OVERLAY_FSTYPE {
+            overlay::umount(&sysroot);
+        } else if !nfs_root.is_empty() {
+            syslib::fs::umount(&sysroot).unwrap_or_default();
+        }
+        return Err(err);
+    }
+
+    Ok(root_fstype)
+}
+
+/// Resolve disk device from the profile to its path in /dev.
+/// It is given as `UUID=`, `LABEL=`, `PARTUUID=` or `PARTLABEL=`, a path in /dev, a logical volume,
+/// or a bare UUID or label.
+pub fn resolve_device(blkid: &BlkInfo, dev: &str) -> Option<String> {
+    let path = |d: &BlkDev| d.get_path().to_str().unwrap().to_string();
+    if let Some(id) = dev.strip_prefix("UUID=") {
+        blkid.by_uuid(id).map(path)
+    } else if let Some(lbl) = dev.strip_prefix("LABEL=") {
+        blkid.by_label(lbl).map(path)
+    } else if let Some(id) = dev.strip_prefix("PARTUUID=") {
+        blkid.by_partuuid(id).map(path)
+    } else if let Some(lbl) = dev.strip_prefix("PARTLABEL=") {
+        blkid.by_partlabel(lbl).map(path)
+    } else if Uuid::parse_str(dev).is_ok() {
+        blkid.by_uuid(dev).map(path)
+    } else if dev.starts_with("/dev") {
+        Path::new(dev).exists().then(|| dev.to_string())
+    } else if let Some((vg, lv)) = lvm::parse_lv_ref(dev).filter(|_| blkid.by_label(dev).is_none()) {
+        let dev = format!("/dev/mapper/{}", lvm::get_dm_name(&vg, &lv));
+        Path::new(&dev).exists().then_some(dev)
+    } else {
+        // label
+        blkid.by_label(dev).map(path)
+    }
+}
+
+/// Probe block devices until all given devices appear or the timeout passes, if any.
+pub fn wait_blk_devices(cfg: &MhConfig, devices: &[&str], timeout: Option<Duration>) -> Result<BlkInfo, Error> {
+    wait_blk(cfg, timeout, |blkid| {
+        Ok(devices.iter().filter(|d| resolve_device(blkid, d).is_none()).map(|d| d.to_string()).collect())
+    })
+}
+
+/// Probe block devices until nothing is missing or the timeout passes, if any.
+/// The check returns what is still missing. Devices are re-probed each time a new node is created in /dev.
+pub fn wait_blk(
+    cfg: &MhConfig, timeout: Option<Duration>, mut check: impl FnMut(&BlkInfo) -> Result<Vec<String>, Error>,
+) -> Result<BlkInfo, Error> {
+    ROOTDELAY.call_once(|| {
+        let delay = cfg.get_rootdelay();
+        if !delay.is_zero() {
+            log::info!("Waiting {}s before probing devices", delay.as_secs());
+            thread::sleep(delay);
+        }
+    });
+
+    let devwatch = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+    devwatch.add_watch("/dev", AddWatchFlags::IN_CREATE)?;
+
+    let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+    let mut waiting = false;
+
+    // Drivers for the devices, which appear while waiting, are loaded as well
+    let mpb = cfg.get_autodetect().then(|| KModProbe::new(cfg));
+    let mut tried: HashSet<String> = HashSet::default();
+
+    loop {
+        if let Some(mpb) = &mpb {
+            let results = mpb.coldplug(&mut tried);
+            if !results.is_empty() {
+                kmodprobe::report(&results);
+            }
+        }
+
+        let mut blkid = BlkInfo::new();
+        blkid.probe_devices()?;
+
+        let missing = check(&blkid)?;
+        if missing.is_empty() {
+            return Ok(blkid);
+        }
+
+        let now = Instant::now();
+        if deadline.is_some_and(|d| now >= d) {
+            return Err(Error::new(ErrorKind::TimedOut, format!("Timed out waiting for devices: {}", missing.join(", "))));
+        }
+
+        if !waiting {
+            log::info!("Waiting for devices: {}", missing.join(", "));
+            waiting = true;
+        }
+
+        let tmo = deadline.map(|d| d - now).unwrap_or(REPROBE_INTERVAL).min(REPROBE_INTERVAL);
+        match poll(
+            &mut [PollFd::new(devwatch.as_fd(), PollFlags::POLLIN)],
+            PollTimeout::try_from(tmo).unwrap_or(PollTimeout::ZERO),
+        ) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(err) => return Err(err.into()),
+        }
+        devwatch.read_events().unwrap_or_default();
+    }
 }
 
 /// Get block devices
-pub fn get_blk_devices(cfg: &MhConfig) -> Result<(String, Vec<SystemDir<String>>), Error> {
+fn get_blk_devices(cfg: &MhConfig) -> Result<(String, Vec<SystemDir<String>>), Error> {
     let mut root_fstype = String::new();
-    let mut blkid = BlkInfo::new();
-
-    blkid.probe_devices()?;
+    let disks = cfg.get_disks()?;
+    let blkid = wait_blk_devices(
+        cfg,
+        &disks.iter().filter(|d| !d.is_nfs()).map(|d| d.get_device()).collect::<Vec<&str>>(),
+        cfg.get_rootwait(),
+    )?;
 
     for d in blkid.get_devices() {
         if !d.get_fstype().is_empty() {
@@ -72,36 +286,36 @@ pub fn get_blk_devices(cfg: &MhConfig) -> Result<(String, Vec<SystemDir<String>>
     }
 
     let mut blk_mpt: Vec<SystemDir<String>> = Vec::new();
-    for dev in cfg.get_disks()? {
+    for dev in disks {
         let mpt = dev.get_mountpoint().trim_end_matches('/').to_string();
         if mpt.is_empty() && root_fstype.is_empty() {
             root_fstype = dev.get_fstype().into();
         }
 
-        let mut devpath = "";
-        if Uuid::parse_str(dev.get_device()).is_ok() {
-            if let Some(blkdev) = blkid.by_uuid(dev.get_device()) {
-                devpath = blkdev.get_path().to_str().unwrap();
-            }
-        } else if dev.get_device().starts_with("/dev") {
-            devpath = dev.get_device();
-        } else {
-            // label
-            if let Some(blkdev) = blkid.by_label(dev.get_device()) {
-                devpath = blkdev.get_path().to_str().unwrap();
-            }
+        // Network shares are mounted separately
+        if dev.is_nfs() {
+            continue;
         }
 
-        if devpath.is_empty() {
-            log::warn!("Unknown device: {}", dev.get_device());
-        } else {
-            let dir = SystemDir::new(dev.get_fstype().into(), devpath.into(), format!("{}{}", &cfg.get_sysroot_path(), mpt));
+        if let Some(mut devpath) = resolve_device(&blkid, dev.get_device()) {
+            // The disk is only a carrier of the image, which is mounted instead
+            if let Some(img) = dev.get_image() {
+                devpath = image::attach(&blkid, &devpath, img)?;
+            }
+            let dir = SystemDir::new(
+                dev.get_fstype().into(),
+                devpath,
+                format!("{}{}", &cfg.get_sysroot_path(), mpt),
+                dev.get_mode().into(),
+            );
             blk_mpt.push(dir);
+        } else {
+            log::warn!("Unknown device: {}", dev.get_device());
         }
     }
 
-    // Sort mountpoints, so the "/" goes always first
-    blk_mpt.sort_by(|ela, elb| ela.dev.cmp(&elb.dev));
+    // Sort mountpoints, so the "/" goes always first and each one goes before those nested in it
+    blk_mpt.sort_by(|ela, elb| ela.dst.cmp(&elb.dst));
 
     Ok((root_fstype, blk_mpt))
 }
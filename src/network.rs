@@ -0,0 +1,198 @@
+use crate::{
+    kmodprobe::{self, KModProbe},
+    microhop::mount_run,
+};
+use profile::cfg::{MhConfNet, MhConfig, NET_ANY};
+use std::{
+    collections::HashSet,
+    fs,
+    io::{Error, ErrorKind},
+    net::Ipv4Addr,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+use syslib::{
+    dhcp::{DhcpClient, Lease},
+    net::{self, RtNetlink},
+};
+
+/// Network configuration of each interface, exposed to the system
+static NET_STATE_DIR: &str = "/run/microhop/net";
+
+/// Interval to check the interfaces, while they are missing or not connected
+const WAIT_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Configured network interface
+pub struct NetIface {
+    name: String,
+    index: u32,
+    address: Ipv4Addr,
+    prefix: u8,
+}
+
+/// Bring up the configured network interfaces and assign their addresses, either static or via DHCP.
+/// Returns the configured interfaces.
+pub fn setup(cfg: &MhConfig) -> Result<Vec<NetIface>, Error> {
+    let mut ifaces: Vec<NetIface> = Vec::default();
+    if cfg.get_network().is_empty() {
+        return Ok(ifaces);
+    }
+
+    mount_run()?;
+    fs::create_dir_all(NET_STATE_DIR)?;
+
+    let mut rtnl = RtNetlink::new()?;
+    for (name, conf) in cfg.get_network() {
+        let name = connect(cfg, &mut rtnl, name)?;
+        let index = net::get_index(&name)?;
+
+        let (address, prefix, gateway, lease) = if conf.is_dhcp() {
+            log::info!("Requesting an address for {} via DHCP", name);
+            let lease = DhcpClient::new(&name)?.request(cfg.get_rootwait())?;
+            (lease.get_address(), lease.get_prefix(), lease.get_gateway(), Some(lease))
+        } else {
+            let (address, prefix) = parse_address(conf)?;
+            (address, prefix, parse_gateway(conf)?, None)
+        };
+
+        rtnl.add_address(index, address, prefix)
+            .map_err(|err| Error::new(err.kind(), format!("Unable to assign {}/{} to {}: {}", address, prefix, name, err)))?;
+        if let Some(gw) = gateway {
+            rtnl.add_route(index, Ipv4Addr::UNSPECIFIED, 0, Some(gw))
+                .map_err(|err| Error::new(err.kind(), format!("Unable to add default route via {}: {}", gw, err)))?;
+        }
+
+        log::info!(
+            "Configured {} with {}/{}{}",
+            name,
+            address,
+            prefix,
+            gateway.map(|gw| format!(" via {}", gw)).unwrap_or_default()
+        );
+
+        let iface = NetIface { name, index, address, prefix };
+        write_state(&iface, gateway, lease.as_ref())?;
+        ifaces.push(iface);
+    }
+
+    Ok(ifaces)
+}
+
+/// Tear down the network configuration before the init starts, unless it is handed over to the system.
+/// It is always kept, if the network shares are mounted.
+pub fn finish(cfg: &MhConfig, ifaces: &[NetIface]) -> Result<(), Error> {
+    if ifaces.is_empty() || cfg.get_nethandover() {
+        return Ok(());
+    }
+
+    if cfg.get_disks()?.iter().any(|d| d.is_nfs()) {
+        log::warn!("Network configuration is handed over to the system, as the network shares are mounted");
+        return Ok(());
+    }
+
+    let mut rtnl = RtNetlink::new()?;
+    for iface in ifaces {
+        rtnl.del_address(iface.index, iface.address, iface.prefix)?;
+        rtnl.set_up(iface.index, false)?;
+        log::debug!("Deconfigured {}", iface.name);
+    }
+    fs::remove_dir_all(NET_STATE_DIR).unwrap_or_default();
+
+    Ok(())
+}
+
+/// Wait for the interface to appear and get connected, bringing it up.
+/// Any Ethernet interface is taken for "*". Returns name of the interface.
+fn connect(cfg: &MhConfig, rtnl: &mut RtNetlink, name: &str) -> Result<String, Error> {
+    let deadline = cfg.get_rootwait().and_then(|t| Instant::now().checked_add(t));
+    let mut waiting = false;
+    let mut up: Vec<String> = Vec::default();
+
+    // Drivers of the network interfaces are autodetected as well
+    let mpb = cfg.get_autodetect().then(|| KModProbe::new(cfg));
+    let mut tried: HashSet<String> = HashSet::default();
+
+    loop {
+        if let Some(mpb) = &mpb {
+            let results = mpb.coldplug(&mut tried);
+            if !results.is_empty() {
+                kmodprobe::report(&results);
+            }
+        }
+
+        let candidates = if name == NET_ANY { net::get_interfaces() } else { vec![name.to_string()] };
+        for iface in candidates.into_iter().filter(|i| !up.contains(i)).collect::<Vec<String>>() {
+            // Not appeared yet
+            let Ok(index) = net::get_index(&iface) else {
+                continue;
+            };
+            rtnl.set_up(index, true).map_err(|err| Error::new(err.kind(), format!("Unable to bring up {}: {}", iface, err)))?;
+            up.push(iface);
+        }
+
+        if let Some(iface) = up.iter().find(|i| net::has_carrier(i)).cloned() {
+            // Interfaces, which were tried for "*", are not needed anymore
+            for other in up.iter().filter(|i| **i != iface) {
+                if let Ok(index) = net::get_index(other) {
+                    rtnl.set_up(index, false).unwrap_or_default();
+                }
+            }
+            return Ok(iface);
+        }
+
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                match up.is_empty() {
+                    true => format!("Timed out waiting for the network interface {}", name),
+                    false => format!("Timed out waiting for a link on {}", up.join(", ")),
+                },
+            ));
+        }
+
+        if !waiting {
+            log::info!("Waiting for the network interface {}", name);
+            waiting = true;
+        }
+        thread::sleep(WAIT_INTERVAL);
+    }
+}
+
+/// Parse static address with the netmask length
+fn parse_address(conf: &MhConfNet) -> Result<(Ipv4Addr, u8), Error> {
+    conf.get_address()
+        .split_once('/')
+        .and_then(|(a, p)| Some((a.parse::<Ipv4Addr>().ok()?, p.parse::<u8>().ok().filter(|p| *p <= 32)?)))
+        .ok_or(Error::new(
+            ErrorKind::InvalidData,
+            format!("Address should be \"dhcp\" or IPv4 with the netmask length: {}", conf.get_address()),
+        ))
+}
+
+/// Parse static gateway, if any
+fn parse_gateway(conf: &MhConfNet) -> Result<Option<Ipv4Addr>, Error> {
+    conf.get_gateway()
+        .map(|gw| gw.parse::<Ipv4Addr>().map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid gateway: {}", gw))))
+        .transpose()
+}
+
+/// Expose configuration of the interface to the system, as "key=value" lines
+fn write_state(iface: &NetIface, gateway: Option<Ipv4Addr>, lease: Option<&Lease>) -> Result<(), Error> {
+    let mut state = format!("address={}/{}\n", iface.address, iface.prefix);
+    if let Some(gw) = gateway {
+        state.push_str(&format!("gateway={}\n", gw));
+    }
+
+    if let Some(lease) = lease {
+        if !lease.get_dns().is_empty() {
+            state.push_str(&format!("dns={}\n", lease.get_dns().iter().map(|a| a.to_string()).collect::<Vec<_>>().join(" ")));
+        }
+        state.push_str(&format!("dhcpserver={}\n", lease.get_server()));
+        if let Some(t) = lease.get_lease_time() {
+            state.push_str(&format!("lease={}\n", t));
+        }
+    }
+
+    fs::write(Path::new(NET_STATE_DIR).join(format!("{}.conf", iface.name)), state)
+}
@@ -0,0 +1,69 @@
+use nix::{
+    errno::Errno,
+    mount::{self, MsFlags},
+};
+use profile::cfg::{MhConfDisk, MhConfig};
+use std::{
+    io::{Error, ErrorKind},
+    net::IpAddr,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Interval to retry mounting, while the server is not reachable
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Build options of the in-kernel NFS client from the disk mode.
+/// Returns mount flags and the options.
+fn get_nfs_options(disk: &MhConfDisk, server: IpAddr) -> (MsFlags, String) {
+    let (flags, data) = syslib::fs::parse_mount_options(disk.get_mode());
+    let mut opts = data.split(',').filter(|o| !o.is_empty()).map(|o| o.to_string()).collect::<Vec<String>>();
+
+    // There is no lock manager (rpc.statd) in the initramfs, same as for the kernel nfsroot
+    if !opts.iter().any(|o| o == "lock" || o == "nolock" || o.starts_with("vers=4") || o.starts_with("nfsvers=4")) {
+        opts.push("nolock".to_string());
+    }
+
+    // The kernel does not resolve the server itself
+    opts.push(format!("addr={}", server));
+
+    (flags, opts.join(","))
+}
+
+/// Mount a network share "server:/export" via the in-kernel NFS client.
+/// The network should be already configured. Retries until the root wait timeout passes, if any.
+pub fn mount(cfg: &MhConfig, disk: &MhConfDisk, dst: &str) -> Result<(), Error> {
+    let Some((server, _)) = disk.get_device().split_once(":/") else {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Network share should be server:/export: {}", disk.get_device())));
+    };
+
+    let server =
+        server.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().map_err(|_| {
+            Error::new(ErrorKind::InvalidData, format!("NFS server should be an IP address: {}", disk.get_device()))
+        })?;
+
+    let (flags, opts) = get_nfs_options(disk, server);
+    let deadline = cfg.get_rootwait().and_then(|t| Instant::now().checked_add(t));
+    loop {
+        match mount::mount(Some(disk.get_device()), dst, Some(disk.get_fstype()), flags, Some(opts.as_str())) {
+            Ok(_) => break,
+            // The network can be not ready yet
+            Err(err @ (Errno::ETIMEDOUT | Errno::ENETUNREACH | Errno::EHOSTUNREACH | Errno::ECONNREFUSED | Errno::EIO))
+                if deadline.is_none_or(|d| Instant::now() < d) =>
+            {
+                log::debug!("Retrying to mount {}: {}", disk.get_device(), err);
+                thread::sleep(RETRY_INTERVAL);
+            }
+            Err(err) => {
+                return Err(Error::new(
+                    ErrorKind::NotConnected,
+                    format!("Failed to mount {} at {} as {} ({}): {}", disk.get_device(), dst, disk.get_fstype(), opts, err),
+                ))
+            }
+        }
+    }
+
+    log::info!("Mounted {} at {} as {}", disk.get_device(), dst, disk.get_fstype());
+
+    Ok(())
+}
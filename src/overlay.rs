@@ -0,0 +1,88 @@
+use crate::microhop::{mount_run, resolve_device, wait_blk_devices, SystemDir};
+use nix::mount::{self, MsFlags};
+use profile::cfg::{MhConfig, OVERLAY_TMPFS};
+use std::{
+    fs,
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+/// Filesystem type of the overlay root
+pub static OVERLAY_FSTYPE: &str = "overlay";
+
+/// Layers of the overlay root, which are kept under /run in the new root
+static OVERLAY_LOWER: &str = "/run/microhop/overlay/lower";
+static OVERLAY_RW: &str = "/run/microhop/overlay/rw";
+
+/// Mount an overlay at the destination of the given disk.
+/// The disk itself is the read-only lower layer, and the upper layer with its work dir
+/// is either on tmpfs or in the "upper" and "work" dirs of the given device.
+pub fn mount(cfg: &MhConfig, lower: &SystemDir<String>, upper: &str) -> Result<(), Error> {
+    mount_run()?;
+    for d in [OVERLAY_LOWER, OVERLAY_RW] {
+        fs::create_dir_all(d)?;
+    }
+
+    // Mount options of the disk apply to the lower layer, which is always read-only
+    let (flags, data) = syslib::fs::parse_mount_options(&lower.opts);
+    mount_layer(
+        &lower.dev,
+        OVERLAY_LOWER,
+        &lower.fstype,
+        flags | MsFlags::MS_RDONLY,
+        Some(data.as_str()).filter(|d| !d.is_empty()),
+    )?;
+
+    let ret = mount_upper(cfg, upper).and_then(|_| {
+        let (upperdir, workdir) = (Path::new(OVERLAY_RW).join("upper"), Path::new(OVERLAY_RW).join("work"));
+        for d in [&upperdir, &workdir] {
+            fs::create_dir_all(d)?;
+        }
+
+        let opts = format!("lowerdir={},upperdir={},workdir={}", OVERLAY_LOWER, upperdir.display(), workdir.display());
+        mount_layer(OVERLAY_FSTYPE, &lower.dst, OVERLAY_FSTYPE, MsFlags::MS_NOATIME, Some(&opts))
+    });
+
+    if let Err(err) = ret {
+        for d in [OVERLAY_RW, OVERLAY_LOWER] {
+            mount::umount(d).unwrap_or_default();
+        }
+        return Err(err);
+    }
+
+    log::info!("Mounted overlay of {} with the upper layer on {} at {}", lower.dev, upper, lower.dst);
+
+    Ok(())
+}
+
+/// Un-mount the overlay with all its layers
+pub fn umount(dst: &str) {
+    for d in [dst, OVERLAY_RW, OVERLAY_LOWER] {
+        mount::umount(d).unwrap_or_default();
+    }
+}
+
+/// Mount the medium of the upper layer
+fn mount_upper(cfg: &MhConfig, upper: &str) -> Result<(), Error> {
+    if upper == OVERLAY_TMPFS {
+        return mount_layer(OVERLAY_TMPFS, OVERLAY_RW, OVERLAY_TMPFS, MsFlags::MS_NOATIME, Some("mode=0755"));
+    }
+
+    let blkid = wait_blk_devices(cfg, &[upper], cfg.get_rootwait())?;
+    let Some(dev) = resolve_device(&blkid, upper) else {
+        return Err(Error::new(ErrorKind::NotFound, format!("Unknown upper layer device: {}", upper)));
+    };
+    let fstype = blkid.by_path(&dev).map(|d| d.get_fstype().to_string()).unwrap_or_default();
+
+    mount_layer(&dev, OVERLAY_RW, &fstype, MsFlags::MS_NOATIME, None)
+}
+
+/// Mount a layer of the overlay
+fn mount_layer(dev: &str, dst: &str, fstype: &str, flags: MsFlags, opts: Option<&str>) -> Result<(), Error> {
+    mount::mount(Some(dev), dst, Some(fstype), flags, opts).map_err(|err| {
+        Error::new(ErrorKind::NotConnected, format!("Failed to mount {} at {} as {}: {}", dev, dst, fstype, err))
+    })?;
+    log::debug!("Mounted {} at {} as {}", dev, dst, fstype);
+
+    Ok(())
+}
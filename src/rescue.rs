@@ -0,0 +1,57 @@
+use crate::console::Console;
+use nix::{
+    sys::wait::waitpid,
+    unistd::{self, ForkResult},
+};
+use profile::cfg::{MhConfig, RESCUE_BUILTIN};
+use std::{ffi::CString, io::Error, path::Path, process};
+
+/// Run a boot step until it succeeds.
+/// On each failure the rescue mode is entered, if it is enabled, otherwise the error is returned.
+pub fn attempt<T>(cfg: &MhConfig, mut step: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+    loop {
+        match step() {
+            Ok(r) => return Ok(r),
+            Err(err) => rescue(cfg, err)?,
+        }
+    }
+}
+
+/// Enter the rescue mode: either a configured shell or the built-in prompt.
+/// Returns when the boot step should be retried.
+pub fn rescue(cfg: &MhConfig, err: Error) -> Result<(), Error> {
+    let Some(shell) = cfg.get_rescue() else {
+        return Err(err);
+    };
+
+    log::error!("{}", err);
+    log::warn!("Entering rescue mode");
+
+    if shell != RESCUE_BUILTIN {
+        if Path::new(shell).exists() {
+            return run_shell(shell);
+        }
+        log::error!("Rescue shell {} was not found, falling back to the built-in prompt", shell);
+    }
+
+    Console::new("rescue", cfg).run()
+}
+
+/// Run the rescue shell and wait for it to exit
+fn run_shell(shell: &str) -> Result<(), Error> {
+    println!("Exit the shell to retry the boot");
+
+    let sh = CString::new(shell)?;
+    match unsafe { unistd::fork() }? {
+        ForkResult::Child => {
+            let Err(err) = unistd::execv(&sh, &[&sh]);
+            println!("Unable to start {}: {}", shell, err);
+            process::exit(1);
+        }
+        ForkResult::Parent { child } => {
+            waitpid(child, None)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,292 @@
+use crate::microhop::{mount_run, resolve_device, wait_blk_devices};
+use nix::mount::{self, MsFlags};
+use profile::cfg::{MhConfSlots, MhConfig};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Chosen slot, exposed to the system
+static SLOT_FILE: &str = "/run/microhop/slot";
+
+/// Temporary mountpoint of the state device
+static STATE_MPT: &str = "/run/microhop/state";
+
+/// Size of the env block at the start of the state device
+const STATE_BLOCK_SIZE: usize = 4096;
+
+/// Boot state of the slots, stored as "key=value" lines:
+///
+///   active=b
+///   tries=3
+///   successful=0
+///
+/// An updater sets the new slot active with some tries, and the system marks it successful, once it booted fine.
+/// Until then, each boot takes one try, and the other slot is booted, when they are over.
+struct SlotState {
+    active: String,
+    tries: u32,
+    successful: bool,
+}
+
+impl SlotState {
+    fn parse(data: &str) -> Self {
+        let mut state = SlotState { active: "a".to_string(), tries: 0, successful: true };
+        for (k, v) in data.lines().filter_map(|l| l.split_once('=')).map(|(k, v)| (k.trim(), v.trim())) {
+            match k {
+                "active" => state.active = v.to_lowercase(),
+                "tries" => state.tries = v.parse().unwrap_or_default(),
+                "successful" => state.successful = v == "1",
+                _ => {}
+            }
+        }
+
+        state
+    }
+
+    fn dump(&self) -> String {
+        format!("active={}\ntries={}\nsuccessful={}\n", self.active, self.tries, self.successful as u8)
+    }
+
+    /// Take one try of the boot. Returns true if the state has changed.
+    fn take_try(&mut self) -> bool {
+        if self.successful {
+            return false;
+        }
+
+        if self.tries > 0 {
+            self.tries -= 1;
+            log::info!("Booting slot {}, {} tries remaining", self.active, self.tries);
+        } else {
+            let fallback = if self.active == "a" { "b" } else { "a" };
+            let failed = std::mem::replace(&mut self.active, fallback.to_string());
+            self.successful = true;
+            log::warn!("Slot {} has no tries remaining, falling back to slot {}", failed, self.active);
+        }
+
+        true
+    }
+}
+
+/// Read the boot state and take one try of the active slot.
+/// Returns the active slot, if the slots are configured.
+pub fn take_try(cfg: &MhConfig) -> Result<Option<String>, Error> {
+    let Some(slots) = cfg.get_slots() else {
+        return Ok(None);
+    };
+
+    mount_run()?;
+    let blkid = wait_blk_devices(cfg, &[slots.get_statedevice()], cfg.get_rootwait())?;
+    let Some(dev) = resolve_device(&blkid, slots.get_statedevice()) else {
+        return Err(Error::new(ErrorKind::NotFound, format!("Unknown slot state device: {}", slots.get_statedevice())));
+    };
+
+    let fstype = blkid.by_path(&dev).map(|d| d.get_fstype()).unwrap_or_default();
+    Ok(Some(update_state(&dev, fstype, slots)?.active))
+}
+
+/// Choose the root slot, which is active. Returns the root device of the slot.
+pub fn select(cfg: &MhConfig, active: &str) -> Result<String, Error> {
+    let Some(slots) = cfg.get_slots() else {
+        return Err(Error::new(ErrorKind::NotFound, "Slots are not configured"));
+    };
+
+    let active = if slots.get_device(active).is_some() {
+        active
+    } else {
+        log::error!("Unknown active slot \"{}\", booting slot a", active);
+        "a"
+    };
+
+    // Expose the chosen slot to the system
+    if let Some(p) = Path::new(SLOT_FILE).parent() {
+        fs::create_dir_all(p)?;
+    }
+    fs::write(SLOT_FILE, format!("{}\n", active))?;
+
+    let root = slots.get_device(active).unwrap_or_default();
+    log::info!("Selected slot {} with the root device {}", active, root);
+
+    Ok(root.to_string())
+}
+
+/// Read the boot state, take one try and write it back
+fn update_state(dev: &str, fstype: &str, slots: &MhConfSlots) -> Result<SlotState, Error> {
+    let Some(sf) = slots.get_statefile() else {
+        // The env block would overwrite the start of the filesystem, e.g. the boot sector of the ESP
+        if !fstype.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Slot state device {} has a {} filesystem, but no state file is configured", dev, fstype),
+            ));
+        }
+        return update_state_block(Path::new(dev));
+    };
+
+    fs::create_dir_all(STATE_MPT)?;
+    mount::mount(
+        Some(dev),
+        STATE_MPT,
+        Some(fstype),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        Option::<&str>::None,
+    )
+    .map_err(|err| Error::new(ErrorKind::NotConnected, format!("Failed to mount the slot state device {}: {}", dev, err)))?;
+
+    let ret = update_state_file(&Path::new(STATE_MPT).join(sf.trim_start_matches('/')));
+
+    // The try is already taken, so it is not an error anymore
+    if let Err(err) = mount::umount(STATE_MPT) {
+        log::warn!("Unable to unmount the slot state device {}: {}", dev, err);
+    }
+    fs::remove_dir(STATE_MPT).unwrap_or_default();
+
+    ret
+}
+
+/// Update the boot state in a file. A missing file means the slot "a" is booted fine.
+/// The file is replaced atomically, as an empty one would undo a pending fallback.
+fn update_state_file(p: &Path) -> Result<SlotState, Error> {
+    let mut state = SlotState::parse(&fs::read_to_string(p).unwrap_or_default());
+    if state.take_try() {
+        let tmp = p.with_file_name(format!("{}.tmp", p.file_name().unwrap_or_default().to_string_lossy()));
+        let mut f = File::create(&tmp)?;
+        f.write_all(state.dump().as_bytes())?;
+        f.sync_all()?;
+        fs::rename(&tmp, p)?;
+
+        // Persist the rename as well
+        if let Some(dir) = p.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+    }
+
+    Ok(state)
+}
+
+/// Update the boot state in the env block at the start of a device
+fn update_state_block(p: &Path) -> Result<SlotState, Error> {
+    let mut f = OpenOptions::new().read(true).write(true).open(p)?;
+    let mut block = vec![0u8; STATE_BLOCK_SIZE];
+    f.read_exact(&mut block)?;
+
+    let mut state = SlotState::parse(String::from_utf8_lossy(&block).trim_end_matches('\0'));
+    if state.take_try() {
+        block.fill(0);
+        let data = state.dump();
+        block[..data.len()].copy_from_slice(data.as_bytes());
+        f.seek(SeekFrom::Start(0))?;
+        f.write_all(&block)?;
+        f.sync_all()?;
+    }
+
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Scratch file, unique for each test
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("microhop-slot-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let p = dir.join(name);
+        fs::remove_file(&p).unwrap_or_default();
+        p
+    }
+
+    fn state(data: &str) -> (String, u32, bool) {
+        let s = SlotState::parse(data);
+        (s.active, s.tries, s.successful)
+    }
+
+    #[test]
+    fn parse_state() {
+        assert_eq!(state("active=b\ntries=3\nsuccessful=0\n"), ("b".to_string(), 3, false));
+        assert_eq!(state(" active = B \n tries=1\nunknown=1\n"), ("b".to_string(), 1, true));
+
+        // Empty or corrupt state means the slot "a" is booted fine
+        assert_eq!(state(""), ("a".to_string(), 0, true));
+        assert_eq!(state("\u{1}\u{2}garbage\n\0\0"), ("a".to_string(), 0, true));
+        assert_eq!(state("tries=many\nsuccessful=0\n"), ("a".to_string(), 0, false));
+    }
+
+    #[test]
+    fn dump_state() {
+        let data = "active=b\ntries=2\nsuccessful=0\n";
+        assert_eq!(SlotState::parse(data).dump(), data);
+    }
+
+    #[test]
+    fn take_tries() {
+        let mut s = SlotState::parse("active=b\ntries=2\nsuccessful=0\n");
+        assert!(s.take_try());
+        assert_eq!((s.active.as_str(), s.tries, s.successful), ("b", 1, false));
+        assert!(s.take_try());
+        assert_eq!((s.active.as_str(), s.tries, s.successful), ("b", 0, false));
+
+        // No tries remaining: fall back to the other slot
+        assert!(s.take_try());
+        assert_eq!((s.active.as_str(), s.tries, s.successful), ("a", 0, true));
+        assert!(!s.take_try());
+
+        let mut s = SlotState::parse("active=a\ntries=0\nsuccessful=0\n");
+        assert!(s.take_try());
+        assert_eq!((s.active.as_str(), s.successful), ("b", true));
+    }
+
+    #[test]
+    fn take_try_successful() {
+        let mut s = SlotState::parse("active=b\ntries=3\nsuccessful=1\n");
+        assert!(!s.take_try());
+        assert_eq!((s.active.as_str(), s.tries, s.successful), ("b", 3, true));
+    }
+
+    #[test]
+    fn state_file() {
+        let p = scratch("slot.state");
+        assert_eq!(update_state_file(&p).unwrap().active, "a");
+        assert!(!p.exists());
+
+        fs::write(&p, "active=b\ntries=1\nsuccessful=0\n").unwrap();
+        assert_eq!(update_state_file(&p).unwrap().active, "b");
+        assert_eq!(fs::read_to_string(&p).unwrap(), "active=b\ntries=0\nsuccessful=0\n");
+        assert_eq!(update_state_file(&p).unwrap().active, "a");
+        assert_eq!(fs::read_to_string(&p).unwrap(), "active=a\ntries=0\nsuccessful=1\n");
+        assert!(!p.with_file_name("slot.state.tmp").exists());
+    }
+
+    #[test]
+    fn state_block() {
+        let p = scratch("state.img");
+        let mut img = b"active=b\ntries=1\nsuccessful=0\n".to_vec();
+        img.resize(STATE_BLOCK_SIZE * 2, 0xff);
+        fs::write(&p, &img).unwrap();
+
+        assert_eq!(update_state_block(&p).unwrap().active, "b");
+        let img = fs::read(&p).unwrap();
+        assert!(img.starts_with(b"active=b\ntries=0\nsuccessful=0\n\0"));
+        assert!(img[STATE_BLOCK_SIZE..].iter().all(|b| *b == 0xff));
+
+        // Zeroed block is the default state, which is not written back
+        fs::write(&p, vec![0u8; STATE_BLOCK_SIZE]).unwrap();
+        assert_eq!(update_state_block(&p).unwrap().active, "a");
+        assert!(fs::read(&p).unwrap().iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn state_block_on_filesystem() {
+        let p = scratch("esp.img");
+        fs::write(&p, vec![0u8; STATE_BLOCK_SIZE]).unwrap();
+        let c = scratch("microhop.conf");
+        fs::write(&c, "modules: []\nslots:\n  a: /dev/vda2\n  b: /dev/vda3\n  statedevice: ESP\n").unwrap();
+        let cfg = profile::cfg::get_mh_config(c.to_str()).unwrap();
+
+        let err = update_state(p.to_str().unwrap(), "vfat", cfg.get_slots().unwrap()).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(fs::read(&p).unwrap().iter().all(|b| *b == 0));
+    }
+}
@@ -0,0 +1,36 @@
+use crate::microhop::{resolve_device, wait_blk_devices};
+use profile::cfg::MhConfig;
+use std::{
+    io::{Error, ErrorKind},
+    path::Path,
+};
+use syslib::{dm, verity::Verity};
+
+/// Map all configured verified devices, which are not mapped yet.
+/// Any verification failure is an error: the unverified data device is never used instead.
+pub fn activate(cfg: &MhConfig) -> Result<(), Error> {
+    let pending = cfg.get_verity().iter().filter(|(name, _)| !dm::exists(name)).collect::<Vec<_>>();
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    let devices = pending.iter().flat_map(|(_, v)| [v.get_device(), v.get_hashdevice()]).collect::<Vec<&str>>();
+    let blkid = wait_blk_devices(cfg, &devices, cfg.get_rootwait())?;
+    for (name, v) in pending {
+        let Some(roothash) = v.get_roothash() else {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("No root hash for the verified device {}", name)));
+        };
+
+        let (Some(data), Some(hash)) = (resolve_device(&blkid, v.get_device()), resolve_device(&blkid, v.get_hashdevice()))
+        else {
+            return Err(Error::new(ErrorKind::NotFound, format!("Unknown verified device: {}", v.get_device())));
+        };
+
+        let node = Verity::open(Path::new(&data), Path::new(&hash), v.get_hashoffset(), v.get_salt())?
+            .activate(name, roothash)
+            .map_err(|err| Error::new(err.kind(), format!("Verification of {} failed: {}", data, err)))?;
+        log::info!("Verified {} as {}", data, node.display());
+    }
+
+    Ok(())
+}
@@ -1,10 +1,34 @@
+use crate::parttable::{self, PartTable};
+#[cfg(all(feature = "libblkid", not(feature = "native-probe")))]
 use libblkid_rs::BlkidProbe;
 use std::{
+    collections::HashMap,
     fs::{self, File},
-    io::{BufRead, BufReader, Error},
+    io::Error,
+    os::fd::AsRawFd,
     path::{Path, PathBuf},
 };
 
+static SYS_CLASS_BLOCK: &str = "/sys/class/block";
+
+/// Sizes in sysfs are in 512-byte sectors, and it is also the default logical sector size
+const SECTOR_SIZE: u64 = 512;
+
+nix::ioctl_read!(blkgetsize64, 0x12, 114, u64);
+
+/// Return size of a block device (or a disk image) in bytes
+pub fn get_size(p: &Path) -> Result<u64, Error> {
+    let f = File::open(p)?;
+    if f.metadata()?.is_file() {
+        return Ok(f.metadata()?.len());
+    }
+
+    let mut size: u64 = 0;
+    unsafe { blkgetsize64(f.as_raw_fd(), &mut size) }?;
+
+    Ok(size)
+}
+
 /// Block device metadata.
 /// This contains its path, UUID, size and other info
 #[derive(Clone)]
@@ -13,6 +37,15 @@ pub struct BlkDev {
     uuid: String,
     label: String,
     fstype: String,
+    partuuid: String,
+    partlabel: String,
+    parent: Option<PathBuf>,
+    slaves: Vec<PathBuf>,
+    holders: Vec<PathBuf>,
+    partnum: Option<u32>,
+    size: u64,
+    removable: bool,
+    readonly: bool,
 }
 
 impl BlkDev {
@@ -32,6 +65,51 @@ impl BlkDev {
         &self.label
     }
 
+    /// Get unique ID of the partition in the partition table, if it is a partition
+    pub fn get_partuuid(&self) -> &str {
+        &self.partuuid
+    }
+
+    /// Get name of the partition in the GPT, if it is a partition
+    pub fn get_partlabel(&self) -> &str {
+        &self.partlabel
+    }
+
+    /// Get path to the disk, if it is a partition. Devices, which a stacked device is built of, are its slaves.
+    pub fn get_parent(&self) -> Option<&PathBuf> {
+        self.parent.as_ref()
+    }
+
+    /// Get paths to the devices, which a stacked device (RAID, device-mapper, loop) is built of
+    pub fn get_slaves(&self) -> &Vec<PathBuf> {
+        &self.slaves
+    }
+
+    /// Get paths to the stacked devices (RAID, device-mapper), which are built on this device
+    pub fn get_holders(&self) -> &Vec<PathBuf> {
+        &self.holders
+    }
+
+    /// Get number of the partition on its disk, if it is a partition
+    pub fn get_partition_number(&self) -> Option<u32> {
+        self.partnum
+    }
+
+    /// Get size in bytes
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns true if the device (or its disk) is removable, such as an USB stick
+    pub fn is_removable(&self) -> bool {
+        self.removable
+    }
+
+    /// Returns true if the device is read-only
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
     /// Fall back one after another: UUID, LABEL, PATH
     pub fn get_mount_criterion(&self) -> &str {
         for mpt in [self.label.as_str(), self.uuid.as_str(), self.path.to_str().unwrap()] {
@@ -56,36 +134,88 @@ impl BlkInfo {
         BlkInfo { devices: Vec::default() }
     }
 
-    /// Return device stats.
-    /// This specifically gets only device names
-    fn load_dev_stats(&mut self) -> Result<Vec<String>, Error> {
-        let mut stats: Vec<String> = Vec::default();
+    /// Read an attribute of the device in sysfs
+    fn read_attr(sys: &Path, attr: &str) -> Option<String> {
+        fs::read_to_string(sys.join(attr)).ok().map(|a| a.trim().to_string())
+    }
 
-        for l in BufReader::new(File::open("/proc/diskstats")?).lines().map_while(Result::ok) {
-            let d: Vec<&str> = l.split_whitespace().collect();
-            if d.len() > 2 {
-                stats.push(d[2].to_owned());
-            }
-        }
+    /// Get path of the device in /dev, which is not always its name in sysfs (e.g. "cciss!c0d0")
+    fn get_dev_path(sys: &Path) -> PathBuf {
+        let devname = Self::read_attr(sys, "uevent")
+            .and_then(|u| u.lines().find_map(|l| l.strip_prefix("DEVNAME=").map(|n| n.to_string())))
+            .unwrap_or_else(|| sys.file_name().unwrap_or_default().to_string_lossy().replace('!', "/"));
 
-        Ok(stats)
+        Path::new("/dev").join(devname)
     }
 
-    /// Load block devices for the given physical device
-    fn load_blk_device(&mut self, dev: &str, stats: &Vec<String>) -> Result<(), Error> {
-        log::debug!("Probing \"{}\" device", dev);
-        for devname in stats {
-            // Get only partitions, omit the physical device
-            if devname.starts_with(dev) && !devname.eq(dev) {
-                let dev = format!("/dev/{}", devname);
-                let blkid = self.blk_id(dev.as_str())?; // uuid, fstype
-                self.devices.push(BlkDev { path: PathBuf::from(dev), uuid: blkid.0, label: blkid.1, fstype: blkid.2 });
-            }
+    /// Get paths to the devices, linked in a directory of the device in sysfs, such as "slaves" or "holders"
+    fn get_linked_devices(sys: &Path, dir: &str) -> Vec<PathBuf> {
+        let mut devices = fs::read_dir(sys.join(dir))
+            .map(|d| d.flatten().filter_map(|e| fs::canonicalize(e.path()).ok()).map(|p| Self::get_dev_path(&p)).collect())
+            .unwrap_or_else(|_| Vec::default());
+        devices.sort();
+
+        devices
+    }
+
+    /// Load a block device by its directory in sysfs: a whole disk, a partition or a stacked device (RAID, device-mapper, loop).
+    /// Partition tables of the disks are cached by their paths.
+    fn load_blk_device(&mut self, sys: &Path, ptables: &mut HashMap<PathBuf, PartTable>) -> Result<(), Error> {
+        // Empty devices, such as unused loop devices or drives without a medium, have no size
+        let size = Self::read_attr(sys, "size").and_then(|s| s.parse::<u64>().ok()).unwrap_or_default() * SECTOR_SIZE;
+        if size == 0 {
+            return Ok(());
+        }
+
+        let path = Self::get_dev_path(sys);
+        if self.devices.iter().any(|d| d.get_path() == &path) {
+            return Ok(());
         }
+        log::debug!("Probing \"{}\" device", path.display());
+
+        // Partitions are nested in their disks in sysfs, and are also identified by their entries in the partition table
+        let partnum = Self::read_attr(sys, "partition").and_then(|n| n.parse::<u32>().ok());
+        let disk_sys = partnum.and_then(|_| sys.parent());
+        let parent = disk_sys.map(Self::get_dev_path);
+        let part = match (partnum, disk_sys, &parent) {
+            (Some(num), Some(dsys), Some(disk)) => {
+                ptables.entry(disk.to_owned()).or_insert_with(|| Self::read_partition_table(dsys, disk)).get_entry(num)
+            }
+            _ => None,
+        };
+
+        let blkid = self.blk_id(path.to_str().unwrap_or_default())?; // uuid, fstype
+        self.devices.push(BlkDev {
+            path,
+            uuid: blkid.0,
+            label: blkid.1,
+            fstype: blkid.2,
+            partuuid: part.as_ref().map(|p| p.get_uuid().to_string()).unwrap_or_default(),
+            partlabel: part.as_ref().map(|p| p.get_name().to_string()).unwrap_or_default(),
+            parent,
+            slaves: Self::get_linked_devices(sys, "slaves"),
+            holders: Self::get_linked_devices(sys, "holders"),
+            partnum,
+            size,
+            removable: Self::read_attr(disk_sys.unwrap_or(sys), "removable").is_some_and(|r| r == "1"),
+            readonly: Self::read_attr(sys, "ro").is_some_and(|r| r == "1"),
+        });
 
         Ok(())
     }
 
+    /// Read the partition table of the disk. Unreadable one is empty.
+    fn read_partition_table(sys: &Path, disk: &Path) -> PartTable {
+        let sector_size =
+            Self::read_attr(sys, "queue/logical_block_size").and_then(|s| s.parse::<u64>().ok()).unwrap_or(SECTOR_SIZE);
+
+        parttable::read(disk, sector_size).unwrap_or_else(|err| {
+            log::debug!("Unable to read partition table of {}: {}", disk.display(), err);
+            PartTable::default()
+        })
+    }
+
+    #[cfg(all(feature = "libblkid", not(feature = "native-probe")))]
     fn blk_id(&self, dev: &str) -> Result<(String, String, String), Error> {
         let mut uuid = "".to_string();
         let mut fstype = "".to_string();
@@ -94,7 +224,9 @@ impl BlkInfo {
         if let Ok(mut pb) = BlkidProbe::new_from_filename(Path::new(dev)) {
             pb.enable_superblocks(true).unwrap_or_default();
             pb.enable_partitions(true).unwrap_or_default();
-            pb.do_safeprobe().unwrap();
+            if pb.do_safeprobe().is_err() {
+                return Ok((uuid, label, fstype));
+            }
 
             if let Ok(disk_id) = pb.lookup_value("UUID") {
                 uuid = disk_id;
@@ -112,11 +244,31 @@ impl BlkInfo {
         Ok((uuid, label, fstype))
     }
 
-    /// Find all currently available devices
+    /// Read superblocks directly, instead of libblkid
+    #[cfg(feature = "native-probe")]
+    fn blk_id(&self, dev: &str) -> Result<(String, String, String), Error> {
+        Ok(match crate::probe::probe(Path::new(dev)) {
+            Ok(Some(sb)) => (sb.get_uuid().to_string(), sb.get_label().to_string(), sb.get_fstype().to_string()),
+            Ok(None) => Default::default(),
+            Err(err) => {
+                log::debug!("Unable to probe {}: {}", dev, err);
+                Default::default()
+            }
+        })
+    }
+
+    /// Find all currently available devices: whole disks with their partitions and stacked devices
     pub fn probe_devices(&mut self) -> Result<(), Error> {
-        let stats = self.load_dev_stats()?;
-        for dname in fs::read_dir("/sys/block")? {
-            self.load_blk_device(dname?.file_name().into_string().unwrap().as_str(), &stats)?;
+        let mut ptables: HashMap<PathBuf, PartTable> = HashMap::default();
+        let mut devices = fs::read_dir(SYS_CLASS_BLOCK)?.flatten().map(|e| e.path()).collect::<Vec<PathBuf>>();
+        devices.sort();
+
+        for d in devices {
+            // Entries are links to the devices, which are nested in their parents
+            let Ok(sys) = fs::canonicalize(&d) else {
+                continue;
+            };
+            self.load_blk_device(&sys, &mut ptables)?;
         }
 
         Ok(())
@@ -137,6 +289,21 @@ impl BlkInfo {
         self.devices.iter().find(|&d| d.get_label().eq(lbl))
     }
 
+    /// Resolve device by unique ID of the partition, which is case-insensitive
+    pub fn by_partuuid(&self, id: &str) -> Option<&BlkDev> {
+        self.devices.iter().find(|&d| !d.get_partuuid().is_empty() && d.get_partuuid().eq_ignore_ascii_case(id))
+    }
+
+    /// Resolve device by name of the partition
+    pub fn by_partlabel(&self, lbl: &str) -> Option<&BlkDev> {
+        self.devices.iter().find(|&d| !d.get_partlabel().is_empty() && d.get_partlabel().eq(lbl))
+    }
+
+    /// Return partitions of the disk
+    pub fn get_partitions(&self, disk: &Path) -> Vec<&BlkDev> {
+        self.devices.iter().filter(|d| d.get_parent().is_some_and(|p| p == disk)).collect()
+    }
+
     /// Return all known block devices
     pub fn get_devices(&self) -> &Vec<BlkDev> {
         &self.devices
@@ -148,3 +315,59 @@ impl Default for BlkInfo {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn linked_devices() {
+        // Fake sysfs: device-mapper device on top of two partitions
+        let sys = std::env::temp_dir().join(format!("microhop-sysfs-{}", std::process::id()));
+        let dm = sys.join("virtual/block/dm-0");
+        fs::create_dir_all(dm.join("slaves")).unwrap();
+        fs::write(dm.join("uevent"), "MAJOR=253\nMINOR=0\nDEVNAME=dm-0\nDEVTYPE=disk\n").unwrap();
+        for part in ["vda3", "vda2"] {
+            let p = sys.join("pci0000:00/block/vda").join(part);
+            fs::create_dir_all(p.join("holders")).unwrap();
+            fs::write(p.join("uevent"), format!("MAJOR=252\nDEVNAME={}\nDEVTYPE=partition\n", part)).unwrap();
+            symlink(&p, dm.join("slaves").join(part)).unwrap();
+            symlink(&dm, p.join("holders/dm-0")).unwrap();
+        }
+
+        assert_eq!(BlkInfo::get_linked_devices(&dm, "slaves"), vec![PathBuf::from("/dev/vda2"), PathBuf::from("/dev/vda3")]);
+        assert!(BlkInfo::get_linked_devices(&dm, "holders").is_empty());
+        assert_eq!(
+            BlkInfo::get_linked_devices(&sys.join("pci0000:00/block/vda/vda2"), "holders"),
+            vec![PathBuf::from("/dev/dm-0")]
+        );
+
+        fs::remove_dir_all(&sys).unwrap();
+    }
+
+    #[test]
+    fn partition_parents() {
+        // Fake sysfs: partitions of two disks, one name being the prefix of the other
+        let sys = std::env::temp_dir().join(format!("microhop-sysfs-parents-{}", std::process::id()));
+        let mut blkid = BlkInfo::new();
+        let mut ptables: HashMap<PathBuf, PartTable> = HashMap::default();
+        for (disk, part) in [("nvme0n1", "nvme0n1p1"), ("nvme0n10", "nvme0n10p1")] {
+            let d = sys.join("nvme/nvme0").join(disk);
+            fs::create_dir_all(d.join(part)).unwrap();
+            fs::write(d.join("size"), "2048\n").unwrap();
+            fs::write(d.join(part).join("size"), "1024\n").unwrap();
+            fs::write(d.join(part).join("partition"), "1\n").unwrap();
+            blkid.load_blk_device(&d, &mut ptables).unwrap();
+            blkid.load_blk_device(&d.join(part), &mut ptables).unwrap();
+        }
+        fs::remove_dir_all(&sys).unwrap();
+
+        let parts =
+            |disk: &str| blkid.get_partitions(Path::new(disk)).iter().map(|d| d.get_path().to_owned()).collect::<Vec<PathBuf>>();
+        assert_eq!(parts("/dev/nvme0n1"), vec![PathBuf::from("/dev/nvme0n1p1")]);
+        assert_eq!(parts("/dev/nvme0n10"), vec![PathBuf::from("/dev/nvme0n10p1")]);
+        assert_eq!(blkid.by_path("/dev/nvme0n10p1").unwrap().get_partition_number(), Some(1));
+        assert!(blkid.by_path("/dev/nvme0n1").unwrap().get_parent().is_none());
+    }
+}
@@ -0,0 +1,125 @@
+//! Compression of kernel modules.
+//!
+//! Kernel modules are usually shipped compressed with ZStandard, XZ or GZip.
+//! The compression is detected by the file extension, the same way kmod does.
+
+use flate2::{read::GzDecoder, write::GzEncoder};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{BufReader, Error, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Compression of a kernel module
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Zstd,
+    Xz,
+    Gzip,
+}
+
+impl Compression {
+    /// Get compression by the file extension
+    pub fn from_path(p: &Path) -> Self {
+        match p.extension().and_then(|e| e.to_str()).unwrap_or_default() {
+            "zst" => Compression::Zstd,
+            "xz" => Compression::Xz,
+            "gz" => Compression::Gzip,
+            _ => Compression::None,
+        }
+    }
+
+    /// Get compression by its name, as in /sys/module/compression or in the CLI
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim() {
+            "none" => Some(Compression::None),
+            "zstd" => Some(Compression::Zstd),
+            "xz" => Some(Compression::Xz),
+            "gzip" => Some(Compression::Gzip),
+            _ => None,
+        }
+    }
+
+    /// Get file extension, without a dot
+    pub fn get_extension(&self) -> Option<&str> {
+        match self {
+            Compression::None => None,
+            Compression::Zstd => Some("zst"),
+            Compression::Xz => Some("xz"),
+            Compression::Gzip => Some("gz"),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Zstd => write!(f, "zstd"),
+            Compression::Xz => write!(f, "xz"),
+            Compression::Gzip => write!(f, "gzip"),
+        }
+    }
+}
+
+/// Read a file into a blob in a memory, decompressing it according to its extension
+pub fn decompress(p: &Path) -> Result<Vec<u8>, Error> {
+    let mut data: Vec<u8> = Vec::new();
+    let mut fr = BufReader::new(File::open(p)?);
+
+    match Compression::from_path(p) {
+        Compression::None => {
+            fr.read_to_end(&mut data)?;
+        }
+        Compression::Zstd => {
+            zstd::Decoder::new(fr)?.read_to_end(&mut data)?;
+        }
+        Compression::Xz => {
+            lzma_rs::xz_decompress(&mut fr, &mut data).map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?;
+        }
+        Compression::Gzip => {
+            GzDecoder::new(fr).read_to_end(&mut data)?;
+        }
+    }
+
+    Ok(data)
+}
+
+/// Compress a blob. XZ is not supported for writing, as the kernel
+/// can only decompress XZ streams with CRC32 checks, which are not produced.
+pub fn compress(data: &[u8], c: Compression) -> Result<Vec<u8>, Error> {
+    match c {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Zstd => zstd::encode_all(data, 0),
+        Compression::Gzip => {
+            let mut enc = GzEncoder::new(Vec::new(), flate2::Compression::best());
+            enc.write_all(data)?;
+            enc.finish()
+        }
+        Compression::Xz => Err(Error::new(ErrorKind::Unsupported, "XZ compression is not supported")),
+    }
+}
+
+/// Copy a file, converting it to a different compression.
+/// Returns the destination path, which has the extension of the new compression.
+pub fn recompress(src: &Path, dst: &Path, c: Compression) -> Result<PathBuf, Error> {
+    let dst = with_compression(dst, c);
+    if Compression::from_path(src) == c {
+        fs::copy(src, &dst)?;
+    } else {
+        fs::write(&dst, compress(&decompress(src)?, c)?)?;
+    }
+
+    Ok(dst)
+}
+
+/// Replace the compression extension of a path
+pub fn with_compression(p: &Path, c: Compression) -> PathBuf {
+    let p = if Compression::from_path(p) != Compression::None { p.with_extension("") } else { p.to_path_buf() };
+    match c.get_extension() {
+        Some(ext) => p.with_extension(format!("{}.{}", p.extension().and_then(|e| e.to_str()).unwrap_or_default(), ext)),
+        None => p,
+    }
+}
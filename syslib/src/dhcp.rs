@@ -0,0 +1,535 @@
+//! DHCPv4 client.
+//!
+//! A minimal client, which obtains a lease via DISCOVER/OFFER/REQUEST/ACK exchange.
+//! The interface has no address yet, so the packets are sent and received
+//! with IP and UDP headers over a packet socket, bound to the interface.
+
+use crate::net;
+use nix::{
+    errno::Errno,
+    libc,
+    sys::{
+        socket::{self, sockopt, MsgFlags},
+        time::{TimeVal, TimeValLike},
+    },
+};
+use std::{
+    io::{Error, ErrorKind},
+    mem,
+    net::Ipv4Addr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Interval to resend a request, while there is no reply
+const RESEND_INTERVAL: Duration = Duration::from_secs(3);
+
+const ETH_P_IP: u16 = 0x0800;
+const IPPROTO_UDP: u8 = 17;
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+
+// Sizes of the headers and of the minimal BOOTP message
+const IP_HDR_SIZE: usize = 20;
+const UDP_HDR_SIZE: usize = 8;
+const BOOTP_SIZE: usize = 300;
+
+// Offsets of the fields in the BOOTP message
+const BOOTP_XID: usize = 4;
+const BOOTP_FLAGS: usize = 10;
+const BOOTP_YIADDR: usize = 16;
+const BOOTP_CHADDR: usize = 28;
+const BOOTP_OPTIONS: usize = 236;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const BOOTP_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+// Options
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MSG_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMS: u8 = 55;
+const OPT_END: u8 = 255;
+
+// Message types
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+/// Lease, obtained from a DHCP server
+#[derive(Debug, Clone)]
+pub struct Lease {
+    address: Ipv4Addr,
+    prefix: u8,
+    gateway: Option<Ipv4Addr>,
+    dns: Vec<Ipv4Addr>,
+    server: Ipv4Addr,
+    lease_time: Option<u32>,
+}
+
+impl Lease {
+    /// Get assigned address
+    pub fn get_address(&self) -> Ipv4Addr {
+        self.address
+    }
+
+    /// Get netmask length
+    pub fn get_prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Get default gateway
+    pub fn get_gateway(&self) -> Option<Ipv4Addr> {
+        self.gateway
+    }
+
+    /// Get name servers
+    pub fn get_dns(&self) -> &[Ipv4Addr] {
+        &self.dns
+    }
+
+    /// Get address of the DHCP server
+    pub fn get_server(&self) -> Ipv4Addr {
+        self.server
+    }
+
+    /// Get lease time in seconds. None is infinite.
+    pub fn get_lease_time(&self) -> Option<u32> {
+        self.lease_time
+    }
+}
+
+/// Reply of the server: message type, offered address and the options
+struct Reply {
+    mtype: u8,
+    yiaddr: Ipv4Addr,
+    options: Vec<(u8, Vec<u8>)>,
+}
+
+impl Reply {
+    fn get_option(&self, code: u8) -> Option<&[u8]> {
+        self.options.iter().find(|(c, _)| *c == code).map(|(_, v)| v.as_slice())
+    }
+
+    fn get_addr(&self, code: u8) -> Option<Ipv4Addr> {
+        self.get_option(code).filter(|v| v.len() >= 4).map(|v| Ipv4Addr::new(v[0], v[1], v[2], v[3]))
+    }
+
+    /// Returns true if the reply is from the server, which made the offer, if any.
+    /// Other servers see the REQUEST as well, but it is not theirs to acknowledge or decline.
+    fn is_from(&self, offer: Option<&Reply>) -> bool {
+        offer.and_then(|o| o.get_addr(OPT_SERVER_ID)).is_none_or(|server| self.get_addr(OPT_SERVER_ID) == Some(server))
+    }
+
+    fn to_lease(&self) -> Lease {
+        Lease {
+            address: self.yiaddr,
+            prefix: self.get_addr(OPT_SUBNET_MASK).map(net::get_prefix).unwrap_or(24),
+            gateway: self.get_addr(OPT_ROUTER),
+            dns: self
+                .get_option(OPT_DNS)
+                .unwrap_or_default()
+                .chunks_exact(4)
+                .map(|a| Ipv4Addr::new(a[0], a[1], a[2], a[3]))
+                .collect(),
+            server: self.get_addr(OPT_SERVER_ID).unwrap_or(Ipv4Addr::UNSPECIFIED),
+            lease_time: self
+                .get_option(OPT_LEASE_TIME)
+                .and_then(|v| v.try_into().ok())
+                .map(u32::from_be_bytes)
+                .filter(|t| *t != u32::MAX),
+        }
+    }
+}
+
+/// DHCP client on an interface
+pub struct DhcpClient {
+    fd: OwnedFd,
+    index: u32,
+    mac: [u8; 6],
+    xid: u32,
+}
+
+impl DhcpClient {
+    /// Open a packet socket on the interface. The interface should be up.
+    pub fn new(iface: &str) -> Result<Self, Error> {
+        let index = net::get_index(iface)?;
+        let mac = net::get_mac(iface)?;
+
+        let fd = Errno::result(unsafe {
+            libc::socket(libc::AF_PACKET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, ETH_P_IP.to_be() as libc::c_int)
+        })?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let addr = Self::link_addr(index, [0u8; 6]);
+        Errno::result(unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        })?;
+        socket::setsockopt(&fd, sockopt::ReceiveTimeout, &TimeVal::milliseconds(250))?;
+
+        // Transaction ID only has to differ between the clients
+        let seed = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+        let xid = seed ^ u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]]);
+
+        Ok(DhcpClient { fd, index, mac, xid })
+    }
+
+    fn link_addr(index: u32, dst: [u8; 6]) -> libc::sockaddr_ll {
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = ETH_P_IP.to_be();
+        addr.sll_ifindex = index as i32;
+        addr.sll_halen = dst.len() as u8;
+        addr.sll_addr[..dst.len()].copy_from_slice(&dst);
+
+        addr
+    }
+
+    /// Obtain a lease. Gives up after the timeout, if any.
+    pub fn request(&mut self, timeout: Option<Duration>) -> Result<Lease, Error> {
+        let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+        loop {
+            let offer = self.exchange(&self.message(DHCPDISCOVER, None), &[DHCPOFFER], None, deadline)?;
+            log::debug!("Offered {} by {}", offer.yiaddr, offer.get_addr(OPT_SERVER_ID).unwrap_or(Ipv4Addr::UNSPECIFIED));
+
+            let request = self.message(DHCPREQUEST, Some(&offer));
+            let ack = self.exchange(&request, &[DHCPACK, DHCPNAK], Some(&offer), deadline)?;
+            if ack.mtype == DHCPACK {
+                return Ok(ack.to_lease());
+            }
+
+            log::warn!("DHCP server declined {}, starting over", offer.yiaddr);
+            self.xid = self.xid.wrapping_add(1);
+        }
+    }
+
+    /// Send the message and wait for a reply of the given types, resending it periodically.
+    /// A reply to the REQUEST of an offer is only taken from the server, which made the offer.
+    fn exchange(&self, msg: &[u8], types: &[u8], offer: Option<&Reply>, deadline: Option<Instant>) -> Result<Reply, Error> {
+        loop {
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                return Err(Error::new(ErrorKind::TimedOut, "No reply from a DHCP server"));
+            }
+
+            self.send(msg)?;
+            let resend = Instant::now() + RESEND_INTERVAL;
+            while Instant::now() < resend {
+                if let Some(reply) = self.recv()?.filter(|r| types.contains(&r.mtype) && r.is_from(offer)) {
+                    return Ok(reply);
+                }
+            }
+        }
+    }
+
+    /// Build a DISCOVER or a REQUEST of the offer
+    fn message(&self, mtype: u8, offer: Option<&Reply>) -> Vec<u8> {
+        let mut msg = vec![0u8; BOOTP_OPTIONS];
+        msg[0..3].copy_from_slice(&[BOOTREQUEST, 1, self.mac.len() as u8]);
+        msg[BOOTP_XID..BOOTP_XID + 4].copy_from_slice(&self.xid.to_be_bytes());
+        msg[BOOTP_FLAGS..BOOTP_FLAGS + 2].copy_from_slice(&BOOTP_BROADCAST.to_be_bytes());
+        msg[BOOTP_CHADDR..BOOTP_CHADDR + self.mac.len()].copy_from_slice(&self.mac);
+
+        msg.extend(MAGIC_COOKIE);
+        msg.extend([OPT_MSG_TYPE, 1, mtype]);
+        if let Some(offer) = offer {
+            msg.extend([OPT_REQUESTED_IP, 4]);
+            msg.extend(offer.yiaddr.octets());
+            if let Some(server) = offer.get_addr(OPT_SERVER_ID) {
+                msg.extend([OPT_SERVER_ID, 4]);
+                msg.extend(server.octets());
+            }
+        }
+        msg.extend([OPT_PARAMS, 4, OPT_SUBNET_MASK, OPT_ROUTER, OPT_DNS, OPT_LEASE_TIME]);
+        msg.push(OPT_END);
+        msg.resize(msg.len().max(BOOTP_SIZE), OPT_PAD);
+
+        msg
+    }
+
+    /// Send the message as a broadcast from 0.0.0.0
+    fn send(&self, msg: &[u8]) -> Result<(), Error> {
+        let udp_len = UDP_HDR_SIZE + msg.len();
+        let mut pkt = Vec::with_capacity(IP_HDR_SIZE + udp_len);
+
+        // IP header: version and length, tos, total length, id, fragment, ttl, protocol, checksum, addresses
+        pkt.extend([0x45, 0]);
+        pkt.extend(((IP_HDR_SIZE + udp_len) as u16).to_be_bytes());
+        pkt.extend([0, 0, 0, 0, 64, IPPROTO_UDP, 0, 0]);
+        pkt.extend(Ipv4Addr::UNSPECIFIED.octets());
+        pkt.extend(Ipv4Addr::BROADCAST.octets());
+        let csum = checksum(&pkt);
+        pkt[10..12].copy_from_slice(&csum.to_be_bytes());
+
+        // UDP header without a checksum
+        pkt.extend(CLIENT_PORT.to_be_bytes());
+        pkt.extend(SERVER_PORT.to_be_bytes());
+        pkt.extend((udp_len as u16).to_be_bytes());
+        pkt.extend([0, 0]);
+        pkt.extend(msg);
+
+        let addr = Self::link_addr(self.index, [0xff; 6]);
+        Errno::result(unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                pkt.as_ptr() as *const libc::c_void,
+                pkt.len(),
+                0,
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        })?;
+
+        Ok(())
+    }
+
+    /// Receive a reply to this client. Returns None on timeout or on any other packet.
+    fn recv(&self) -> Result<Option<Reply>, Error> {
+        let mut buf = vec![0u8; 1500];
+        let len = match socket::recv(self.fd.as_raw_fd(), &mut buf, MsgFlags::empty()) {
+            Ok(len) => len,
+            Err(Errno::EAGAIN) | Err(Errno::EINTR) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(self.parse_reply(&buf[..len]))
+    }
+
+    /// Parse a received IP packet into a reply to this client. Returns None on any other packet.
+    fn parse_reply(&self, pkt: &[u8]) -> Option<Reply> {
+        let ihl = (pkt.first().copied().unwrap_or_default() & 0x0f) as usize * 4;
+        if pkt.len() < ihl + UDP_HDR_SIZE + BOOTP_OPTIONS + MAGIC_COOKIE.len() || pkt[9] != IPPROTO_UDP {
+            return None;
+        }
+        if u16::from_be_bytes([pkt[ihl + 2], pkt[ihl + 3]]) != CLIENT_PORT {
+            return None;
+        }
+
+        let msg = &pkt[ihl + UDP_HDR_SIZE..];
+        if msg[0] != BOOTREPLY
+            || msg[BOOTP_XID..BOOTP_XID + 4] != self.xid.to_be_bytes()
+            || msg[BOOTP_OPTIONS..BOOTP_OPTIONS + 4] != MAGIC_COOKIE
+        {
+            return None;
+        }
+
+        let options = parse_options(&msg[BOOTP_OPTIONS + MAGIC_COOKIE.len()..]);
+        let mtype = options.iter().find(|(c, _)| *c == OPT_MSG_TYPE).and_then(|(_, v)| v.first().copied())?;
+        let y = &msg[BOOTP_YIADDR..BOOTP_YIADDR + 4];
+
+        Some(Reply { mtype, yiaddr: Ipv4Addr::new(y[0], y[1], y[2], y[3]), options })
+    }
+}
+
+/// Parse the options into code and value pairs
+fn parse_options(data: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut options = Vec::default();
+    let mut off = 0;
+    while off < data.len() {
+        match data[off] {
+            OPT_PAD => off += 1,
+            OPT_END => break,
+            code => {
+                let Some(&len) = data.get(off + 1) else {
+                    break;
+                };
+                let Some(val) = data.get(off + 2..off + 2 + len as usize) else {
+                    break;
+                };
+                options.push((code, val.to_vec()));
+                off += 2 + len as usize;
+            }
+        }
+    }
+
+    options
+}
+
+/// Internet checksum of the header
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data.chunks(2).map(|c| u16::from_be_bytes([c[0], c.get(1).copied().unwrap_or_default()]) as u32).sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    /// Client without a socket, which is only good for building and parsing messages
+    fn client() -> DhcpClient {
+        DhcpClient {
+            fd: File::open("/dev/null").unwrap().into(),
+            index: 1,
+            mac: [0x52, 0x54, 0x00, 0x12, 0x34, 0x56],
+            xid: 0x1234abcd,
+        }
+    }
+
+    fn reply(mtype: u8, options: &[(u8, &[u8])]) -> Reply {
+        let mut opts = vec![(OPT_MSG_TYPE, vec![mtype])];
+        opts.extend(options.iter().map(|(c, v)| (*c, v.to_vec())));
+        Reply { mtype, yiaddr: Ipv4Addr::new(10, 0, 0, 42), options: opts }
+    }
+
+    /// IP packet of a reply from the server
+    fn packet(op: u8, xid: u32, port: u16, options: &[u8]) -> Vec<u8> {
+        let mut msg = vec![0u8; BOOTP_OPTIONS];
+        msg[0] = op;
+        msg[BOOTP_XID..BOOTP_XID + 4].copy_from_slice(&xid.to_be_bytes());
+        msg[BOOTP_YIADDR..BOOTP_YIADDR + 4].copy_from_slice(&[10, 0, 0, 42]);
+        msg.extend(MAGIC_COOKIE);
+        msg.extend(options);
+
+        let mut pkt = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, IPPROTO_UDP, 0, 0, 10, 0, 0, 1, 255, 255, 255, 255];
+        pkt.extend(SERVER_PORT.to_be_bytes());
+        pkt.extend(port.to_be_bytes());
+        pkt.extend(((UDP_HDR_SIZE + msg.len()) as u16).to_be_bytes());
+        pkt.extend([0, 0]);
+        pkt.extend(msg);
+
+        pkt
+    }
+
+    #[test]
+    fn options() {
+        let data =
+            [OPT_PAD, OPT_PAD, OPT_MSG_TYPE, 1, DHCPACK, OPT_PAD, OPT_SUBNET_MASK, 4, 255, 255, 255, 0, OPT_END, OPT_ROUTER, 4];
+        assert_eq!(parse_options(&data), vec![(OPT_MSG_TYPE, vec![DHCPACK]), (OPT_SUBNET_MASK, vec![255, 255, 255, 0])]);
+        assert_eq!(parse_options(&[OPT_DNS, 0, OPT_PAD]), vec![(OPT_DNS, vec![])]);
+        assert!(parse_options(&[]).is_empty());
+    }
+
+    #[test]
+    fn options_truncated() {
+        assert_eq!(parse_options(&[OPT_MSG_TYPE, 1, DHCPOFFER, OPT_ROUTER, 4, 10, 0]), vec![(OPT_MSG_TYPE, vec![DHCPOFFER])]);
+        assert_eq!(parse_options(&[OPT_MSG_TYPE, 1, DHCPOFFER, OPT_ROUTER]), vec![(OPT_MSG_TYPE, vec![DHCPOFFER])]);
+        assert!(parse_options(&[OPT_MSG_TYPE]).is_empty());
+    }
+
+    #[test]
+    fn lease() {
+        let lease = reply(
+            DHCPACK,
+            &[
+                (OPT_SUBNET_MASK, &[255, 255, 252, 0]),
+                (OPT_ROUTER, &[10, 0, 0, 1, 10, 0, 0, 2]),
+                (OPT_DNS, &[10, 0, 0, 53, 9, 9, 9, 9, 1]),
+                (OPT_SERVER_ID, &[10, 0, 0, 5]),
+                (OPT_LEASE_TIME, &3600u32.to_be_bytes()),
+            ],
+        )
+        .to_lease();
+
+        assert_eq!(lease.get_address(), Ipv4Addr::new(10, 0, 0, 42));
+        assert_eq!(lease.get_prefix(), 22);
+        assert_eq!(lease.get_gateway(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(lease.get_dns(), &[Ipv4Addr::new(10, 0, 0, 53), Ipv4Addr::new(9, 9, 9, 9)]);
+        assert_eq!(lease.get_server(), Ipv4Addr::new(10, 0, 0, 5));
+        assert_eq!(lease.get_lease_time(), Some(3600));
+    }
+
+    #[test]
+    fn lease_defaults() {
+        let lease = reply(DHCPACK, &[(OPT_LEASE_TIME, &[0xff; 4]), (OPT_ROUTER, &[10, 0])]).to_lease();
+        assert_eq!(lease.get_prefix(), 24);
+        assert_eq!(lease.get_gateway(), None);
+        assert!(lease.get_dns().is_empty());
+        assert_eq!(lease.get_server(), Ipv4Addr::UNSPECIFIED);
+        assert_eq!(lease.get_lease_time(), None);
+
+        assert_eq!(reply(DHCPACK, &[(OPT_LEASE_TIME, &[0, 1])]).to_lease().get_lease_time(), None);
+    }
+
+    #[test]
+    fn discover() {
+        let msg = client().message(DHCPDISCOVER, None);
+        assert_eq!(msg.len(), BOOTP_SIZE);
+        assert_eq!(msg[0..4], [BOOTREQUEST, 1, 6, 0]);
+        assert_eq!(msg[BOOTP_XID..BOOTP_XID + 4], [0x12, 0x34, 0xab, 0xcd]);
+        assert_eq!(msg[BOOTP_FLAGS..BOOTP_FLAGS + 2], [0x80, 0]);
+        assert_eq!(msg[BOOTP_YIADDR..BOOTP_YIADDR + 4], [0; 4]);
+        assert_eq!(msg[BOOTP_CHADDR..BOOTP_CHADDR + 16], [0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(msg[BOOTP_OPTIONS..BOOTP_OPTIONS + 4], MAGIC_COOKIE);
+        assert_eq!(
+            msg[BOOTP_OPTIONS + 4..BOOTP_OPTIONS + 14],
+            [OPT_MSG_TYPE, 1, DHCPDISCOVER, OPT_PARAMS, 4, OPT_SUBNET_MASK, OPT_ROUTER, OPT_DNS, OPT_LEASE_TIME, OPT_END]
+        );
+        assert!(msg[BOOTP_OPTIONS + 14..].iter().all(|b| *b == OPT_PAD));
+    }
+
+    #[test]
+    fn request() {
+        let offer = reply(DHCPOFFER, &[(OPT_SERVER_ID, &[10, 0, 0, 5])]);
+        let msg = client().message(DHCPREQUEST, Some(&offer));
+        assert_eq!(msg.len(), BOOTP_SIZE);
+        assert_eq!(
+            parse_options(&msg[BOOTP_OPTIONS + 4..]),
+            vec![
+                (OPT_MSG_TYPE, vec![DHCPREQUEST]),
+                (OPT_REQUESTED_IP, vec![10, 0, 0, 42]),
+                (OPT_SERVER_ID, vec![10, 0, 0, 5]),
+                (OPT_PARAMS, vec![OPT_SUBNET_MASK, OPT_ROUTER, OPT_DNS, OPT_LEASE_TIME]),
+            ]
+        );
+    }
+
+    #[test]
+    fn replies() {
+        let c = client();
+        let opts = [OPT_MSG_TYPE, 1, DHCPOFFER, OPT_SERVER_ID, 4, 10, 0, 0, 5, OPT_END];
+        let offer = c.parse_reply(&packet(BOOTREPLY, c.xid, CLIENT_PORT, &opts)).unwrap();
+        assert_eq!(offer.mtype, DHCPOFFER);
+        assert_eq!(offer.yiaddr, Ipv4Addr::new(10, 0, 0, 42));
+        assert_eq!(offer.get_addr(OPT_SERVER_ID), Some(Ipv4Addr::new(10, 0, 0, 5)));
+
+        // Replies to other clients, other packets and replies without a type
+        assert!(c.parse_reply(&packet(BOOTREPLY, c.xid + 1, CLIENT_PORT, &opts)).is_none());
+        assert!(c.parse_reply(&packet(BOOTREPLY, c.xid, SERVER_PORT, &opts)).is_none());
+        assert!(c.parse_reply(&packet(BOOTREQUEST, c.xid, CLIENT_PORT, &opts)).is_none());
+        assert!(c.parse_reply(&packet(BOOTREPLY, c.xid, CLIENT_PORT, &[OPT_END])).is_none());
+        assert!(c.parse_reply(&packet(BOOTREPLY, c.xid, CLIENT_PORT, &opts)[..100]).is_none());
+        assert!(c.parse_reply(&[]).is_none());
+    }
+
+    #[test]
+    fn reply_server() {
+        let offer = reply(DHCPOFFER, &[(OPT_SERVER_ID, &[10, 0, 0, 5])]);
+        assert!(reply(DHCPNAK, &[(OPT_SERVER_ID, &[10, 0, 0, 5])]).is_from(Some(&offer)));
+        assert!(!reply(DHCPNAK, &[(OPT_SERVER_ID, &[10, 0, 0, 6])]).is_from(Some(&offer)));
+        assert!(!reply(DHCPACK, &[]).is_from(Some(&offer)));
+
+        // Any server, if the offer does not tell its one
+        assert!(reply(DHCPNAK, &[(OPT_SERVER_ID, &[10, 0, 0, 6])]).is_from(Some(&reply(DHCPOFFER, &[]))));
+        assert!(reply(DHCPOFFER, &[(OPT_SERVER_ID, &[10, 0, 0, 6])]).is_from(None));
+    }
+
+    #[test]
+    fn header_checksum() {
+        let mut hdr = [0x45, 0, 0, 0x73, 0, 0, 0x40, 0, 0x40, 0x11, 0, 0, 0xc0, 0xa8, 0, 1, 0xc0, 0xa8, 0, 0xc7];
+        assert_eq!(checksum(&hdr), 0xb861);
+        hdr[10..12].copy_from_slice(&0xb861u16.to_be_bytes());
+        assert_eq!(checksum(&hdr), 0);
+
+        // Odd length is padded with zero, and carries are folded
+        assert_eq!(checksum(&[0x12, 0x34, 0x56]), !0x6834);
+        assert_eq!(checksum(&[0xff, 0xff, 0x00, 0x02]), !0x0002);
+        assert_eq!(checksum(&[]), 0xffff);
+    }
+}
@@ -0,0 +1,167 @@
+//! Device-mapper.
+//!
+//! A minimal replacement of dmsetup: it creates mapped devices from the given
+//! tables via device-mapper ioctls and makes their nodes in /dev/mapper.
+
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    libc,
+    sys::stat::{self, Mode, SFlag},
+};
+use std::{
+    fs::{self, OpenOptions},
+    io::{Error, ErrorKind},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+static DM_CONTROL: &str = "/dev/mapper/control";
+static DM_DIR: &str = "/dev/mapper";
+
+// Size of struct dm_ioctl and struct dm_target_spec
+const DM_IOCTL_SIZE: usize = 312;
+const DM_TARGET_SPEC_SIZE: usize = 40;
+
+// Interface version 4.0.0
+const DM_VERSION: [u32; 3] = [4, 0, 0];
+
+// Commands
+const DM_DEV_CREATE: u8 = 3;
+const DM_DEV_REMOVE: u8 = 4;
+const DM_DEV_SUSPEND: u8 = 6;
+const DM_TABLE_LOAD: u8 = 9;
+
+// Flags
+const DM_READONLY_FLAG: u32 = 1 << 0;
+const DM_SECURE_DATA_FLAG: u32 = 1 << 15;
+
+/// One line of a device-mapper table
+pub struct DmTarget {
+    /// Start sector
+    pub start: u64,
+
+    /// Length in sectors
+    pub length: u64,
+
+    /// Target type, e.g. "linear" or "crypt"
+    pub ttype: String,
+
+    /// Target parameters
+    pub params: String,
+}
+
+impl DmTarget {
+    pub fn new(start: u64, length: u64, ttype: &str, params: &str) -> Self {
+        DmTarget { start, length, ttype: ttype.to_string(), params: params.to_string() }
+    }
+}
+
+/// Build an ioctl buffer: struct dm_ioctl with the given payload
+fn dm_ioctl_buf(name: &str, uuid: &str, flags: u32, target_count: u32, payload: &[u8]) -> Result<Vec<u8>, Error> {
+    if name.is_empty() || name.len() > 127 || uuid.len() > 128 {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid device-mapper name or UUID: {}", name)));
+    }
+
+    let mut buf = vec![0u8; DM_IOCTL_SIZE + payload.len()];
+    for (i, v) in DM_VERSION.iter().enumerate() {
+        buf[i * 4..i * 4 + 4].copy_from_slice(&v.to_ne_bytes());
+    }
+    let data_size = buf.len() as u32;
+    buf[12..16].copy_from_slice(&data_size.to_ne_bytes());
+    buf[16..20].copy_from_slice(&(DM_IOCTL_SIZE as u32).to_ne_bytes()); // data_start
+    buf[20..24].copy_from_slice(&target_count.to_ne_bytes());
+    buf[28..32].copy_from_slice(&flags.to_ne_bytes());
+    buf[48..48 + name.len()].copy_from_slice(name.as_bytes());
+    buf[176..176 + uuid.len()].copy_from_slice(uuid.as_bytes());
+    buf[DM_IOCTL_SIZE..].copy_from_slice(payload);
+
+    Ok(buf)
+}
+
+/// Call a device-mapper command
+fn dm_ioctl(cmd: u8, buf: &mut [u8]) -> Result<(), Error> {
+    let ctl = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(OFlag::O_CLOEXEC.bits())
+        .open(DM_CONTROL)
+        .map_err(|err| Error::other(format!("Device-mapper is not available at {}: {}", DM_CONTROL, err)))?;
+    let req = nix::request_code_readwrite!(0xfd, cmd, DM_IOCTL_SIZE);
+    let ret = unsafe { libc::ioctl(ctl.as_raw_fd(), req as _, buf.as_mut_ptr()) };
+    Errno::result(ret)?;
+
+    Ok(())
+}
+
+/// Serialise targets into a table payload: struct dm_target_spec, followed by its parameters
+fn dm_table(targets: &[DmTarget]) -> Result<Vec<u8>, Error> {
+    let mut payload: Vec<u8> = Vec::default();
+    for t in targets {
+        if t.ttype.len() > 15 {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid target type: {}", t.ttype)));
+        }
+
+        // Each spec is aligned to 8 bytes, the parameters are NUL-terminated
+        let size = (DM_TARGET_SPEC_SIZE + t.params.len() + 1).div_ceil(8) * 8;
+        let mut spec = vec![0u8; size];
+        spec[0..8].copy_from_slice(&t.start.to_ne_bytes());
+        spec[8..16].copy_from_slice(&t.length.to_ne_bytes());
+        spec[20..24].copy_from_slice(&(size as u32).to_ne_bytes()); // next
+        spec[24..24 + t.ttype.len()].copy_from_slice(t.ttype.as_bytes());
+        spec[DM_TARGET_SPEC_SIZE..DM_TARGET_SPEC_SIZE + t.params.len()].copy_from_slice(t.params.as_bytes());
+        payload.extend(spec);
+    }
+
+    Ok(payload)
+}
+
+/// Create a mapped device, load its table and activate it.
+/// Returns path to the device node in /dev/mapper.
+pub fn create(name: &str, uuid: &str, targets: &[DmTarget], readonly: bool) -> Result<PathBuf, Error> {
+    // Secure data flag wipes the buffers in the kernel, as tables may contain keys
+    let flags = DM_SECURE_DATA_FLAG | if readonly { DM_READONLY_FLAG } else { 0 };
+
+    let mut buf = dm_ioctl_buf(name, uuid, flags, 0, &[])?;
+    dm_ioctl(DM_DEV_CREATE, &mut buf)?;
+    let dev = u64::from_ne_bytes(buf[40..48].try_into().unwrap());
+
+    let activate = || -> Result<(), Error> {
+        let mut table = dm_table(targets)?;
+        let mut buf = dm_ioctl_buf(name, "", flags, targets.len() as u32, &table)?;
+        table.fill(0);
+        let ret = dm_ioctl(DM_TABLE_LOAD, &mut buf);
+        buf.fill(0);
+        ret?;
+
+        // Resume the device, i.e. "suspend" without the suspend flag
+        dm_ioctl(DM_DEV_SUSPEND, &mut dm_ioctl_buf(name, "", flags & !DM_SECURE_DATA_FLAG, 0, &[])?)
+    };
+
+    if let Err(err) = activate() {
+        remove(name).unwrap_or_default();
+        return Err(Error::new(err.kind(), format!("Unable to activate {}: {}", name, err)));
+    }
+
+    let node = Path::new(DM_DIR).join(name);
+    fs::create_dir_all(DM_DIR)?;
+    if !node.exists() {
+        stat::mknod(&node, SFlag::S_IFBLK, Mode::S_IRUSR | Mode::S_IWUSR, dev as libc::dev_t)?;
+    }
+    log::debug!("Created device-mapper device {}", node.display());
+
+    Ok(node)
+}
+
+/// Remove a mapped device
+pub fn remove(name: &str) -> Result<(), Error> {
+    dm_ioctl(DM_DEV_REMOVE, &mut dm_ioctl_buf(name, "", 0, 0, &[])?)?;
+    fs::remove_file(Path::new(DM_DIR).join(name)).unwrap_or_default();
+
+    Ok(())
+}
+
+/// Returns true if the mapped device exists
+pub fn exists(name: &str) -> bool {
+    Path::new(DM_DIR).join(name).exists()
+}
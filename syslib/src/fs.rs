@@ -3,8 +3,12 @@
 //! This module is intended to do all the basic operations those are typically
 //! done by external utils, such as mount, umount, switch root etc.
 
-use nix::{mount::MsFlags, sys::statvfs, unistd};
-use std::{fs, io::Error};
+use nix::{
+    mount::MsFlags,
+    sys::{stat, statvfs},
+    unistd,
+};
+use std::{fs, io::Error, path::Path};
 use walkdir::WalkDir;
 
 /// Returns filesystem type
@@ -39,22 +43,89 @@ fn rmrf(sr: &str) -> Result<(), Error> {
     Ok(())
 }
 
-/// Mounts mountpoint
-pub fn mount(fstype: &str, dev: &str, dst: &str) -> Result<(), Error> {
-    if let Err(err) = nix::mount::mount(Some(dev), dst, Some(fstype), MsFlags::MS_NOATIME, Option::<&str>::None) {
+/// Options, which are only meaningful to userspace tools, such as fstab ones, and are never passed to the kernel
+const USERSPACE_OPTS: &[&str] = &["defaults", "auto", "noauto", "user", "nouser", "users", "owner", "group", "nofail", "_netdev"];
+
+/// Translate comma-separated mount options, e.g. "ro,nosuid,compress=zstd", into the mount flags
+/// and the filesystem data. Options of userspace tools ("noauto", "x-*" etc) are skipped.
+/// Unless any access time option is given, "noatime" is implied.
+pub fn parse_mount_options(opts: &str) -> (MsFlags, String) {
+    let mut flags = MsFlags::empty();
+    let mut data: Vec<&str> = Vec::default();
+    let mut atime = false;
+
+    for o in opts.split(',').map(|o| o.trim()).filter(|o| !o.is_empty()) {
+        let (set, clear) = match o {
+            "ro" => (MsFlags::MS_RDONLY, MsFlags::empty()),
+            "rw" => (MsFlags::empty(), MsFlags::MS_RDONLY),
+            "nosuid" => (MsFlags::MS_NOSUID, MsFlags::empty()),
+            "suid" => (MsFlags::empty(), MsFlags::MS_NOSUID),
+            "nodev" => (MsFlags::MS_NODEV, MsFlags::empty()),
+            "dev" => (MsFlags::empty(), MsFlags::MS_NODEV),
+            "noexec" => (MsFlags::MS_NOEXEC, MsFlags::empty()),
+            "exec" => (MsFlags::empty(), MsFlags::MS_NOEXEC),
+            "sync" => (MsFlags::MS_SYNCHRONOUS, MsFlags::empty()),
+            "async" => (MsFlags::empty(), MsFlags::MS_SYNCHRONOUS),
+            "dirsync" => (MsFlags::MS_DIRSYNC, MsFlags::empty()),
+            "mand" => (MsFlags::MS_MANDLOCK, MsFlags::empty()),
+            "nomand" => (MsFlags::empty(), MsFlags::MS_MANDLOCK),
+            "nodiratime" => (MsFlags::MS_NODIRATIME, MsFlags::empty()),
+            "diratime" => (MsFlags::empty(), MsFlags::MS_NODIRATIME),
+            "lazytime" => (MsFlags::MS_LAZYTIME, MsFlags::empty()),
+            "nolazytime" => (MsFlags::empty(), MsFlags::MS_LAZYTIME),
+            "silent" => (MsFlags::MS_SILENT, MsFlags::empty()),
+            "loud" => (MsFlags::empty(), MsFlags::MS_SILENT),
+            "noatime" | "atime" | "relatime" | "norelatime" | "strictatime" | "nostrictatime" => {
+                atime = true;
+                let atime_flags = MsFlags::MS_NOATIME | MsFlags::MS_RELATIME | MsFlags::MS_STRICTATIME;
+                match o {
+                    "noatime" => (MsFlags::MS_NOATIME, atime_flags),
+                    "relatime" => (MsFlags::MS_RELATIME, atime_flags),
+                    "strictatime" => (MsFlags::MS_STRICTATIME, atime_flags),
+                    _ => (MsFlags::empty(), atime_flags),
+                }
+            }
+            o if USERSPACE_OPTS.contains(&o) || o.starts_with("x-") || o.starts_with("comment=") => continue,
+            o => {
+                data.push(o);
+                continue;
+            }
+        };
+        flags.remove(clear);
+        flags.insert(set);
+    }
+
+    if !atime {
+        flags.insert(MsFlags::MS_NOATIME);
+    }
+
+    (flags, data.join(","))
+}
+
+/// Mounts mountpoint with the given mount options, as described in `parse_mount_options`
+pub fn mount(fstype: &str, dev: &str, dst: &str, opts: &str) -> Result<(), Error> {
+    let (flags, data) = parse_mount_options(opts);
+    if let Err(err) = nix::mount::mount(Some(dev), dst, Some(fstype), flags, Some(data.as_str()).filter(|d| !d.is_empty())) {
         return Err(Error::new(
             std::io::ErrorKind::NotConnected,
             format!("Failed to mount {} on {} as {}: {}", fstype, dev, dst, err),
         ));
     } else {
-        log::debug!("Mounted {} at {} as {}", dev, dst, fstype);
+        log::debug!("Mounted {} at {} as {} ({})", dev, dst, fstype, opts);
     }
 
     Ok(())
 }
 
+/// Returns true if the path is a mountpoint, i.e. it is on another device than its parent
+pub fn is_mountpoint(p: &str) -> bool {
+    match (stat::stat(p), stat::stat(&Path::new(p).join(".."))) {
+        (Ok(st), Ok(pst)) => st.st_dev != pst.st_dev || st.st_ino == pst.st_ino,
+        _ => false,
+    }
+}
+
 /// Un-mount a mountpoint.
-#[allow(dead_code)]
 pub fn umount(dst: &str) -> Result<(), Error> {
     Ok(nix::mount::umount(dst)?)
 }
@@ -71,3 +142,44 @@ pub fn pivot(temp: &str, fstype: &str) -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mount_flags() {
+        let (flags, data) = parse_mount_options("ro,nosuid,nodev,noexec");
+        assert_eq!(flags, MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC | MsFlags::MS_NOATIME);
+        assert_eq!(data, "");
+
+        // Later options take precedence
+        assert_eq!(parse_mount_options("ro,rw,exec,noexec").0, MsFlags::MS_NOEXEC | MsFlags::MS_NOATIME);
+    }
+
+    #[test]
+    fn mount_atime() {
+        assert_eq!(parse_mount_options("").0, MsFlags::MS_NOATIME);
+        assert_eq!(parse_mount_options("rw").0, MsFlags::MS_NOATIME);
+        assert_eq!(parse_mount_options("relatime").0, MsFlags::MS_RELATIME);
+        assert_eq!(parse_mount_options("strictatime").0, MsFlags::MS_STRICTATIME);
+        assert_eq!(parse_mount_options("atime").0, MsFlags::empty());
+        assert_eq!(parse_mount_options("noatime,relatime").0, MsFlags::MS_RELATIME);
+        assert_eq!(parse_mount_options("relatime,noatime").0, MsFlags::MS_NOATIME);
+    }
+
+    #[test]
+    fn mount_userspace_options() {
+        let (flags, data) =
+            parse_mount_options("defaults,noauto,nofail,_netdev,user,x-systemd.automount,x-initrd.mount,comment=x");
+        assert_eq!(flags, MsFlags::MS_NOATIME);
+        assert_eq!(data, "");
+    }
+
+    #[test]
+    fn mount_data() {
+        let (flags, data) = parse_mount_options("defaults, ro ,subvol=@,x-initrd.mount,compress=zstd,relatime");
+        assert_eq!(flags, MsFlags::MS_RDONLY | MsFlags::MS_RELATIME);
+        assert_eq!(data, "subvol=@,compress=zstd");
+    }
+}
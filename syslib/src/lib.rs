@@ -1,2 +1,19 @@
 pub mod blk;
+pub mod compress;
+pub mod dhcp;
+pub mod dm;
 pub mod fs;
+pub mod loopdev;
+pub mod luks;
+pub mod lvm;
+pub mod md;
+pub mod net;
+pub mod parttable;
+#[cfg(feature = "native-probe")]
+pub mod probe;
+pub mod verity;
+
+// The features are additive: with both of them, such as with "--all-features",
+// block devices are probed natively and libblkid is left unused.
+#[cfg(not(any(feature = "libblkid", feature = "native-probe")))]
+compile_error!("Either \"libblkid\" or \"native-probe\" feature is required to probe block devices");
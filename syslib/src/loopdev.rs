@@ -0,0 +1,144 @@
+//! Loop devices.
+//!
+//! A minimal replacement of "losetup": it attaches a file to a free loop device
+//! via loop ioctls, so a filesystem image can be mounted as a block device.
+//! A file, which is already attached, is found by the backing files in sysfs.
+
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    libc,
+    sys::stat::{self, Mode, SFlag},
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Error,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+static LOOP_CONTROL: &str = "/dev/loop-control";
+static SYS_BLOCK: &str = "/sys/block";
+
+/// Block device major number of the loop devices
+const LOOP_MAJOR: u64 = 7;
+
+// Size of struct loop_info64 and struct loop_config
+const LOOP_INFO64_SIZE: usize = 232;
+const LOOP_CONFIG_SIZE: usize = 8 + LOOP_INFO64_SIZE + 64;
+
+// Offsets of lo_flags and lo_file_name in struct loop_info64
+const LO_FLAGS_OFFSET: usize = 52;
+const LO_FILE_NAME_OFFSET: usize = 56;
+const LO_NAME_SIZE: usize = 64;
+
+// Commands
+const LOOP_SET_FD: u64 = 0x4c00;
+const LOOP_CLR_FD: u64 = 0x4c01;
+const LOOP_SET_STATUS64: u64 = 0x4c04;
+const LOOP_CONFIGURE: u64 = 0x4c0a;
+const LOOP_CTL_GET_FREE: u64 = 0x4c82;
+
+// Flags
+const LO_FLAGS_READ_ONLY: u32 = 1;
+
+/// Build struct loop_info64 for the file
+fn loop_info(p: &Path, readonly: bool) -> Vec<u8> {
+    let mut info = vec![0u8; LOOP_INFO64_SIZE];
+    let flags = if readonly { LO_FLAGS_READ_ONLY } else { 0 };
+    info[LO_FLAGS_OFFSET..LO_FLAGS_OFFSET + 4].copy_from_slice(&flags.to_ne_bytes());
+
+    let name = p.as_os_str().as_encoded_bytes();
+    let len = name.len().min(LO_NAME_SIZE - 1);
+    info[LO_FILE_NAME_OFFSET..LO_FILE_NAME_OFFSET + len].copy_from_slice(&name[..len]);
+
+    info
+}
+
+/// Find a loop device, which the file is already attached to.
+/// Returns path to the loop device.
+pub fn find(p: &Path) -> Option<PathBuf> {
+    find_in(Path::new(SYS_BLOCK), p)
+}
+
+/// Find a loop device of the file by the backing files of the loop devices in a sysfs block directory
+fn find_in(sys: &Path, p: &Path) -> Option<PathBuf> {
+    let p = fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf());
+    let mut found = fs::read_dir(sys)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|e| e.file_name().to_string_lossy().starts_with("loop"))
+        .filter(|e| {
+            fs::read_to_string(e.path().join("loop/backing_file")).is_ok_and(|f| Path::new(f.trim_end_matches('\n')) == p)
+        })
+        .map(|e| Path::new("/dev").join(e.file_name()))
+        .collect::<Vec<PathBuf>>();
+    found.sort();
+
+    found.into_iter().next()
+}
+
+/// Attach a file to a free loop device.
+/// Returns path to the loop device.
+pub fn attach(p: &Path, readonly: bool) -> Result<PathBuf, Error> {
+    let img = OpenOptions::new().read(true).write(!readonly).custom_flags(OFlag::O_CLOEXEC.bits()).open(p)?;
+    let ctl = File::open(LOOP_CONTROL)
+        .map_err(|err| Error::new(err.kind(), format!("Loop devices are not available at {}: {}", LOOP_CONTROL, err)))?;
+
+    let num = Errno::result(unsafe { libc::ioctl(ctl.as_raw_fd(), LOOP_CTL_GET_FREE as _) })?;
+    let node = PathBuf::from(format!("/dev/loop{}", num));
+    if !node.exists() {
+        stat::mknod(&node, SFlag::S_IFBLK, Mode::S_IRUSR | Mode::S_IWUSR, stat::makedev(LOOP_MAJOR, num as u64))?;
+    }
+    let dev = OpenOptions::new().read(true).write(!readonly).custom_flags(OFlag::O_CLOEXEC.bits()).open(&node)?;
+
+    // struct loop_config: fd, block_size, info and reserved
+    let info = loop_info(p, readonly);
+    let mut cfg = vec![0u8; LOOP_CONFIG_SIZE];
+    cfg[0..4].copy_from_slice(&(img.as_raw_fd() as u32).to_ne_bytes());
+    cfg[8..8 + LOOP_INFO64_SIZE].copy_from_slice(&info);
+
+    match Errno::result(unsafe { libc::ioctl(dev.as_raw_fd(), LOOP_CONFIGURE as _, cfg.as_ptr()) }) {
+        Ok(_) => {}
+        // LOOP_CONFIGURE is available since Linux 5.8
+        Err(Errno::EINVAL) | Err(Errno::ENOTTY) => {
+            Errno::result(unsafe { libc::ioctl(dev.as_raw_fd(), LOOP_SET_FD as _, img.as_raw_fd()) })?;
+            if let Err(err) = Errno::result(unsafe { libc::ioctl(dev.as_raw_fd(), LOOP_SET_STATUS64 as _, info.as_ptr()) }) {
+                unsafe { libc::ioctl(dev.as_raw_fd(), LOOP_CLR_FD as _) };
+                return Err(err.into());
+            }
+        }
+        Err(err) => return Err(Error::other(format!("Unable to attach {} to {}: {}", p.display(), node.display(), err))),
+    }
+
+    log::debug!("Attached {} to {}", p.display(), node.display());
+
+    Ok(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_attached() {
+        // Fake sysfs: two loop devices with their backing files and an unused one
+        let sys = std::env::temp_dir().join(format!("microhop-loop-{}", std::process::id()));
+        let img = sys.join("root.img");
+        for (dev, file) in [("loop3", img.to_str().unwrap()), ("loop1", "/run/other.img"), ("loop0", "")] {
+            fs::create_dir_all(sys.join(dev).join("loop")).unwrap();
+            if !file.is_empty() {
+                fs::write(sys.join(dev).join("loop/backing_file"), format!("{}\n", file)).unwrap();
+            }
+        }
+        fs::write(&img, "").unwrap();
+
+        assert_eq!(find_in(&sys, &img), Some(PathBuf::from("/dev/loop3")));
+        assert_eq!(find_in(&sys, &sys.join("loop1/../root.img")), Some(PathBuf::from("/dev/loop3")));
+        assert_eq!(find_in(&sys, Path::new("/run/other.img")), Some(PathBuf::from("/dev/loop1")));
+        assert_eq!(find_in(&sys, Path::new("/run/none.img")), None);
+
+        fs::remove_dir_all(&sys).unwrap();
+    }
+}
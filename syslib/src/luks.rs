@@ -0,0 +1,462 @@
+//! LUKS2 encrypted devices.
+//!
+//! A minimal replacement of "cryptsetup open": it reads the LUKS2 header,
+//! recovers the volume key from a keyslot with a passphrase or a keyfile
+//! and maps the decrypted device via dm-crypt.
+//!
+//! Supported are PBKDF2 and Argon2 keyslots and AES-XTS encryption,
+//! which are the cryptsetup defaults.
+
+use crate::{blk, dm};
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes128, Aes256,
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{Error, ErrorKind, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+/// LUKS magic
+const LUKS_MAGIC: &[u8] = b"LUKS\xba\xbe";
+
+/// Size of the binary header, the JSON area follows it
+const LUKS2_HDR_BIN_SIZE: usize = 4096;
+
+/// Sector size of the keyslot areas
+const SECTOR_SIZE: usize = 512;
+
+#[derive(Deserialize)]
+struct LuksMeta {
+    keyslots: BTreeMap<String, LuksKeyslot>,
+    segments: BTreeMap<String, LuksSegment>,
+    digests: BTreeMap<String, LuksDigest>,
+    config: Option<LuksConfig>,
+}
+
+#[derive(Deserialize)]
+struct LuksKeyslot {
+    #[serde(rename = "type")]
+    ktype: String,
+    key_size: usize,
+    af: LuksAf,
+    area: LuksArea,
+    kdf: LuksKdf,
+    priority: Option<u8>,
+}
+
+#[derive(Deserialize)]
+struct LuksAf {
+    stripes: usize,
+    hash: String,
+}
+
+#[derive(Deserialize)]
+struct LuksArea {
+    offset: String,
+    encryption: String,
+    key_size: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum LuksKdf {
+    Pbkdf2 { hash: String, iterations: u32, salt: String },
+    Argon2i { time: u32, memory: u32, cpus: u32, salt: String },
+    Argon2id { time: u32, memory: u32, cpus: u32, salt: String },
+}
+
+#[derive(Deserialize)]
+struct LuksSegment {
+    #[serde(rename = "type")]
+    stype: String,
+    offset: String,
+    size: String,
+    iv_tweak: String,
+    encryption: String,
+    sector_size: u64,
+}
+
+#[derive(Deserialize)]
+struct LuksDigest {
+    keyslots: Vec<String>,
+    segments: Vec<String>,
+    hash: String,
+    iterations: u32,
+    salt: String,
+    digest: String,
+}
+
+#[derive(Deserialize)]
+struct LuksConfig {
+    flags: Option<Vec<String>>,
+}
+
+/// LUKS2 device
+pub struct Luks2 {
+    path: PathBuf,
+    uuid: String,
+    meta: LuksMeta,
+}
+
+impl Luks2 {
+    /// Read LUKS2 header of a device
+    pub fn open(p: &Path) -> Result<Self, Error> {
+        let mut f = File::open(p)?;
+        let mut hdr = vec![0u8; LUKS2_HDR_BIN_SIZE];
+        f.read_exact(&mut hdr)?;
+
+        if !hdr.starts_with(LUKS_MAGIC) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} is not a LUKS device", p.display())));
+        }
+
+        let version = u16::from_be_bytes([hdr[6], hdr[7]]);
+        if version != 2 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("LUKS version {} of {} is not supported", version, p.display()),
+            ));
+        }
+
+        let hdr_size = u64::from_be_bytes(hdr[8..16].try_into().unwrap()) as usize;
+        if hdr_size <= LUKS2_HDR_BIN_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, format!("LUKS2 header of {} is corrupted", p.display())));
+        }
+
+        let mut json = vec![0u8; hdr_size - LUKS2_HDR_BIN_SIZE];
+        f.read_exact(&mut json)?;
+        let json = &json[..json.iter().position(|b| *b == 0).unwrap_or(json.len())];
+
+        Ok(Luks2 {
+            path: p.to_path_buf(),
+            uuid: String::from_utf8_lossy(&hdr[168..208]).trim_end_matches('\0').to_string(),
+            meta: serde_json::from_slice(json).map_err(|err| Error::new(ErrorKind::InvalidData, err))?,
+        })
+    }
+
+    /// Get UUID of the LUKS device
+    pub fn get_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    /// Recover the volume key with a passphrase or a keyfile content.
+    /// All keyslots are tried, except those with the "ignore" priority.
+    pub fn unlock(&self, passphrase: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut slots = self.meta.keyslots.iter().filter(|(_, ks)| ks.priority != Some(0)).collect::<Vec<_>>();
+        slots.sort_by_key(|(_, ks)| std::cmp::Reverse(ks.priority.unwrap_or(1)));
+
+        for (id, ks) in slots {
+            if ks.ktype != "luks2" {
+                log::debug!("Skipping keyslot {} of unsupported type {}", id, ks.ktype);
+                continue;
+            }
+
+            match self.unlock_keyslot(ks, passphrase) {
+                Ok(key) if self.verify_key(id, &key)? => {
+                    log::debug!("Unlocked {} with keyslot {}", self.path.display(), id);
+                    return Ok(key);
+                }
+                Ok(mut key) => key.fill(0),
+                Err(err) => log::debug!("Keyslot {} of {}: {}", id, self.path.display(), err),
+            }
+        }
+
+        Err(Error::new(ErrorKind::PermissionDenied, format!("No key available for {}", self.path.display())))
+    }
+
+    /// Decrypt keyslot material and merge it to a candidate volume key
+    fn unlock_keyslot(&self, ks: &LuksKeyslot, passphrase: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut kek = vec![0u8; ks.area.key_size];
+        match &ks.kdf {
+            LuksKdf::Pbkdf2 { hash, iterations, salt } => pbkdf2(hash, passphrase, &b64(salt)?, *iterations, &mut kek)?,
+            LuksKdf::Argon2i { time, memory, cpus, salt } => {
+                argon2(Algorithm::Argon2i, *time, *memory, *cpus, passphrase, &b64(salt)?, &mut kek)?
+            }
+            LuksKdf::Argon2id { time, memory, cpus, salt } => {
+                argon2(Algorithm::Argon2id, *time, *memory, *cpus, passphrase, &b64(salt)?, &mut kek)?
+            }
+        }
+
+        let af_size = ks.key_size * ks.af.stripes;
+        let mut material = vec![0u8; af_size.div_ceil(SECTOR_SIZE) * SECTOR_SIZE];
+        let mut f = File::open(&self.path)?;
+        f.seek(SeekFrom::Start(num(&ks.area.offset)?))?;
+        f.read_exact(&mut material)?;
+
+        let ret = decrypt(&ks.area.encryption, &kek, &mut material, SECTOR_SIZE, 0);
+        kek.fill(0);
+        ret?;
+
+        let key = af_merge(&ks.af.hash, &material[..af_size], ks.key_size, ks.af.stripes);
+        material.fill(0);
+        key
+    }
+
+    /// Check a candidate volume key against the digests of a keyslot
+    fn verify_key(&self, slot: &str, key: &[u8]) -> Result<bool, Error> {
+        for d in self.meta.digests.values().filter(|d| d.keyslots.iter().any(|k| k == slot)) {
+            let digest = b64(&d.digest)?;
+            let mut out = vec![0u8; digest.len()];
+            pbkdf2(&d.hash, key, &b64(&d.salt)?, d.iterations, &mut out)?;
+            if out == digest {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Map the decrypted device with the volume key.
+    /// Returns path to the device node in /dev/mapper.
+    pub fn activate(&self, name: &str, key: &[u8], readonly: bool) -> Result<PathBuf, Error> {
+        let Some((sid, seg)) = self.meta.segments.iter().find(|(_, s)| s.stype == "crypt") else {
+            return Err(Error::new(ErrorKind::InvalidData, format!("No encrypted segment on {}", self.path.display())));
+        };
+
+        // The volume key must be assigned to the segment
+        if !self.meta.digests.values().any(|d| d.segments.contains(sid)) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Segment {} of {} has no digest", sid, self.path.display())));
+        }
+
+        let offset = num(&seg.offset)? / SECTOR_SIZE as u64;
+        let length = match seg.size.as_str() {
+            "dynamic" => (blk::get_size(&self.path)? / SECTOR_SIZE as u64).saturating_sub(offset),
+            size => num(size)? / SECTOR_SIZE as u64,
+        };
+
+        let mut opts: Vec<String> = Vec::default();
+        for flag in self.meta.config.as_ref().and_then(|c| c.flags.as_ref()).into_iter().flatten() {
+            match flag.as_str() {
+                "allow-discards" => opts.push("allow_discards".to_string()),
+                "same-cpu-crypt" => opts.push("same_cpu_crypt".to_string()),
+                "submit-from-crypt-cpus" => opts.push("submit_from_crypt_cpus".to_string()),
+                "no-read-workqueue" => opts.push("no_read_workqueue".to_string()),
+                "no-write-workqueue" => opts.push("no_write_workqueue".to_string()),
+                _ => {}
+            }
+        }
+        if seg.sector_size != SECTOR_SIZE as u64 {
+            opts.push(format!("sector_size:{}", seg.sector_size));
+            opts.push("iv_large_sectors".to_string());
+        }
+
+        let mut params = format!(
+            "{} {} {} {} {}",
+            seg.encryption,
+            key.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+            seg.iv_tweak,
+            self.path.display(),
+            offset
+        );
+        if !opts.is_empty() {
+            params.push_str(&format!(" {} {}", opts.len(), opts.join(" ")));
+        }
+
+        let uuid = format!("CRYPT-LUKS2-{}-{}", self.uuid.replace('-', ""), name);
+        let ret = dm::create(name, &uuid, &[dm::DmTarget::new(0, length, "crypt", &params)], readonly);
+
+        // Do not leave the key in the memory
+        let mut params = params.into_bytes();
+        params.fill(0);
+
+        ret
+    }
+}
+
+/// Decode base64 value
+fn b64(v: &str) -> Result<Vec<u8>, Error> {
+    STANDARD.decode(v).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+/// Parse numeric value, which is a string in LUKS2 metadata
+fn num(v: &str) -> Result<u64, Error> {
+    v.parse::<u64>().map_err(|_| Error::new(ErrorKind::InvalidData, format!("Not a number: {}", v)))
+}
+
+/// Derive a key with PBKDF2
+fn pbkdf2(hash: &str, pwd: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) -> Result<(), Error> {
+    match hash {
+        "sha1" => pbkdf2::pbkdf2_hmac::<Sha1>(pwd, salt, iterations, out),
+        "sha256" => pbkdf2::pbkdf2_hmac::<Sha256>(pwd, salt, iterations, out),
+        "sha512" => pbkdf2::pbkdf2_hmac::<Sha512>(pwd, salt, iterations, out),
+        _ => return Err(Error::new(ErrorKind::Unsupported, format!("Hash {} is not supported", hash))),
+    }
+
+    Ok(())
+}
+
+/// Derive a key with Argon2
+fn argon2(alg: Algorithm, time: u32, memory: u32, cpus: u32, pwd: &[u8], salt: &[u8], out: &mut [u8]) -> Result<(), Error> {
+    let params =
+        Params::new(memory, time, cpus, Some(out.len())).map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?;
+    Argon2::new(alg, Version::V0x13, params).hash_password_into(pwd, salt, out).map_err(|err| Error::other(err.to_string()))
+}
+
+/// Decrypt data in place, sector by sector.
+/// Only AES in XTS mode with plain64 IVs is supported.
+fn decrypt(cipher: &str, key: &[u8], data: &mut [u8], sector_size: usize, iv_offset: u64) -> Result<(), Error> {
+    if cipher != "aes-xts-plain64" {
+        return Err(Error::new(ErrorKind::Unsupported, format!("Cipher {} is not supported", cipher)));
+    }
+
+    let (k1, k2) = key.split_at(key.len() / 2);
+    match key.len() {
+        32 => {
+            xts_decrypt(&Aes128::new_from_slice(k1).unwrap(), &Aes128::new_from_slice(k2).unwrap(), data, sector_size, iv_offset)
+        }
+        64 => {
+            xts_decrypt(&Aes256::new_from_slice(k1).unwrap(), &Aes256::new_from_slice(k2).unwrap(), data, sector_size, iv_offset)
+        }
+        n => return Err(Error::new(ErrorKind::Unsupported, format!("Key size {} is not supported", n * 8))),
+    }
+
+    Ok(())
+}
+
+/// XTS decryption (IEEE 1619), the tweak of each sector is its number
+fn xts_decrypt<C: BlockEncrypt + BlockDecrypt>(data_c: &C, tweak_c: &C, data: &mut [u8], sector_size: usize, iv_offset: u64) {
+    for (sector, chunk) in data.chunks_mut(sector_size).enumerate() {
+        let mut tweak = [0u8; 16];
+        tweak[..8].copy_from_slice(&(iv_offset + sector as u64).to_le_bytes());
+        tweak_c.encrypt_block(GenericArray::from_mut_slice(&mut tweak));
+
+        for block in chunk.chunks_exact_mut(16) {
+            block.iter_mut().zip(tweak.iter()).for_each(|(b, t)| *b ^= t);
+            data_c.decrypt_block(GenericArray::from_mut_slice(block));
+            block.iter_mut().zip(tweak.iter()).for_each(|(b, t)| *b ^= t);
+
+            // Multiply the tweak by x in GF(2^128)
+            let carry = tweak[15] >> 7;
+            for i in (1..16).rev() {
+                tweak[i] = (tweak[i] << 1) | (tweak[i - 1] >> 7);
+            }
+            tweak[0] = (tweak[0] << 1) ^ (0x87 * carry);
+        }
+    }
+}
+
+/// Merge anti-forensic split material back to the key
+fn af_merge(hash: &str, material: &[u8], key_size: usize, stripes: usize) -> Result<Vec<u8>, Error> {
+    let diffuse: fn(&mut [u8]) = match hash {
+        "sha1" => diffuse::<Sha1>,
+        "sha256" => diffuse::<Sha256>,
+        "sha512" => diffuse::<Sha512>,
+        _ => return Err(Error::new(ErrorKind::Unsupported, format!("Hash {} is not supported", hash))),
+    };
+
+    let mut key = vec![0u8; key_size];
+    for (i, stripe) in material.chunks_exact(key_size).take(stripes).enumerate() {
+        key.iter_mut().zip(stripe).for_each(|(k, s)| *k ^= s);
+        if i < stripes - 1 {
+            diffuse(&mut key);
+        }
+    }
+
+    Ok(key)
+}
+
+/// Diffuse a buffer with a hash, block by block
+fn diffuse<D: Digest>(buf: &mut [u8]) {
+    let size = <D as Digest>::output_size();
+    for (i, block) in buf.chunks_mut(size).enumerate() {
+        let mut d = D::new();
+        d.update((i as u32).to_be_bytes());
+        d.update(&*block);
+        let h = d.finalize();
+        block.copy_from_slice(&h[..block.len()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Generated by testdata/luks2/mkluks2.py
+    fn fixture() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/luks2/luks2.img")
+    }
+
+    const VOLUME_KEY: &str = "0c374c40983b83b0198cc638f469017e27e88fbe13d0f297778f232bd3afa06e";
+    const SEGMENT_OFFSET: u64 = 163840;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn xts_ieee1619_vector() {
+        // IEEE 1619 vector 2: data unit 0x3333333333
+        let mut data = vec![0x44u8; 32];
+        let enc = unhex("c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0");
+        let key = [[0x11u8; 16], [0x22u8; 16]].concat();
+
+        let mut buf = enc.clone();
+        decrypt("aes-xts-plain64", &key, &mut buf, SECTOR_SIZE, 0x3333333333).unwrap();
+        assert_eq!(buf, data);
+
+        data.truncate(16);
+        assert_eq!(decrypt("aes-cbc-essiv:sha256", &key, &mut data, SECTOR_SIZE, 0).unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(decrypt("aes-xts-plain64", &key[..24], &mut data, SECTOR_SIZE, 0).unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn pbkdf2_rfc6070_vector() {
+        let mut out = [0u8; 20];
+        pbkdf2("sha1", b"password", b"salt", 2, &mut out).unwrap();
+        assert_eq!(out.to_vec(), unhex("ea6c014dc72d6f8ccd1ed92ace1d41f0d8de8957"));
+        assert_eq!(pbkdf2("md5", b"password", b"salt", 2, &mut out).unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn af_merge_single_stripe() {
+        // With one stripe there is nothing to diffuse
+        assert_eq!(af_merge("sha256", &[1, 2, 3, 4], 4, 1).unwrap(), vec![1, 2, 3, 4]);
+
+        // Two stripes: the first one is diffused with SHA-256 of its block number and itself
+        let mut d = Sha256::new();
+        d.update(0u32.to_be_bytes());
+        d.update([0xaa; 4]);
+        let h = d.finalize();
+        let key = af_merge("sha256", &[[0xaa; 4], [0x55; 4]].concat(), 4, 2).unwrap();
+        assert_eq!(key, h[..4].iter().map(|b| b ^ 0x55).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn open_header() {
+        let luks = Luks2::open(&fixture()).unwrap();
+        assert_eq!(luks.get_uuid(), "5a3b0c2e-9d41-4f7e-8a6b-1c2d3e4f5a6b");
+
+        let ks = &luks.meta.keyslots["0"];
+        assert_eq!((ks.key_size, ks.af.stripes, ks.area.encryption.as_str()), (32, 4000, "aes-xts-plain64"));
+        assert!(matches!(ks.kdf, LuksKdf::Pbkdf2 { iterations: 1000, .. }));
+        assert_eq!(num(&luks.meta.segments["0"].offset).unwrap(), SEGMENT_OFFSET);
+
+        assert_eq!(Luks2::open(Path::new("/dev/null")).err().map(|e| e.kind()), Some(ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn unlock_keyslot() {
+        let luks = Luks2::open(&fixture()).unwrap();
+        assert_eq!(luks.unlock(b"microhop").unwrap(), unhex(VOLUME_KEY));
+        assert_eq!(luks.unlock(b"microhop\n").unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn decrypt_segment() {
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        let mut f = File::open(fixture()).unwrap();
+        f.seek(SeekFrom::Start(SEGMENT_OFFSET)).unwrap();
+        f.read_exact(&mut sector).unwrap();
+
+        decrypt("aes-xts-plain64", &unhex(VOLUME_KEY), &mut sector, SECTOR_SIZE, 0).unwrap();
+        assert!(sector.starts_with(b"Microhop LUKS2 test sector\n"));
+        assert!(sector[27..].iter().all(|b| *b == 0));
+    }
+}
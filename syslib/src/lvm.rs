@@ -0,0 +1,562 @@
+//! LVM2 logical volumes.
+//!
+//! A minimal replacement of "lvchange -ay": it reads labels and metadata of the
+//! physical volumes and maps the logical volumes with linear and striped
+//! device-mapper tables. Thin, RAID, mirrored, cached and snapshot volumes
+//! are not supported.
+
+use crate::dm::{self, DmTarget};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{Error, ErrorKind, Read, Seek, SeekFrom},
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+};
+
+/// Label of a physical volume is in one of the first four sectors
+const LABEL_SCAN_SECTORS: usize = 4;
+const SECTOR_SIZE: usize = 512;
+
+const LABEL_ID: &[u8] = b"LABELONE";
+const LABEL_TYPE: &[u8] = b"LVM2 001";
+const MDA_MAGIC: &[u8] = b" LVM2 x[5A%r0N*>";
+const MDA_HEADER_SIZE: u64 = 512;
+
+/// Value of the metadata text
+#[derive(Debug, Clone)]
+enum LvmValue {
+    Int(i64),
+    Str(String),
+    List(Vec<LvmValue>),
+    Section(Vec<(String, LvmValue)>),
+}
+
+impl LvmValue {
+    fn get(&self, key: &str) -> Option<&LvmValue> {
+        match self {
+            LvmValue::Section(items) => items.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn get_int(&self, key: &str) -> Option<i64> {
+        match self.get(key) {
+            Some(LvmValue::Int(v)) => Some(*v),
+            _ => None,
+        }
+    }
+
+    fn get_str(&self, key: &str) -> Option<&str> {
+        match self.get(key) {
+            Some(LvmValue::Str(v)) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn get_list(&self, key: &str) -> &[LvmValue] {
+        match self.get(key) {
+            Some(LvmValue::List(v)) => v,
+            _ => &[],
+        }
+    }
+
+    /// Return subsections
+    fn sections(&self) -> Vec<(&str, &LvmValue)> {
+        match self {
+            LvmValue::Section(items) => {
+                items.iter().filter(|(_, v)| matches!(v, LvmValue::Section(_))).map(|(k, v)| (k.as_str(), v)).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Returns true if a flag is in the "status" list
+    fn has_status(&self, flag: &str) -> bool {
+        self.get_list("status").iter().any(|s| matches!(s, LvmValue::Str(s) if s == flag))
+    }
+}
+
+/// Parser of the metadata text, which is a nested "key = value" and "name { ... }" configuration
+struct LvmParser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> LvmParser<'a> {
+    fn parse(text: &'a str) -> Result<LvmValue, Error> {
+        let mut p = LvmParser { data: text.as_bytes(), pos: 0 };
+        let root = p.section()?;
+        if p.peek().is_some() {
+            return Err(p.error("unexpected \"}\""));
+        }
+
+        Ok(root)
+    }
+
+    fn error(&self, msg: &str) -> Error {
+        Error::new(ErrorKind::InvalidData, format!("LVM metadata at {}: {}", self.pos, msg))
+    }
+
+    /// Skip whitespaces and comments, return the next character
+    fn peek(&mut self) -> Option<u8> {
+        while self.pos < self.data.len() {
+            match self.data[self.pos] {
+                b'#' => {
+                    while self.pos < self.data.len() && self.data[self.pos] != b'\n' {
+                        self.pos += 1;
+                    }
+                }
+                c if c.is_ascii_whitespace() || c == 0 => self.pos += 1,
+                c => return Some(c),
+            }
+        }
+
+        None
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), Error> {
+        if self.peek() != Some(c) {
+            return Err(self.error(&format!("expected \"{}\"", c as char)));
+        }
+        self.pos += 1;
+
+        Ok(())
+    }
+
+    fn ident(&mut self) -> Result<String, Error> {
+        self.peek();
+        let start = self.pos;
+        while self.pos < self.data.len()
+            && (self.data[self.pos].is_ascii_alphanumeric() || b"_.+-".contains(&self.data[self.pos]))
+        {
+            self.pos += 1;
+        }
+
+        if start == self.pos {
+            return Err(self.error("expected a name"));
+        }
+
+        Ok(String::from_utf8_lossy(&self.data[start..self.pos]).to_string())
+    }
+
+    /// Section content until "}" or the end
+    fn section(&mut self) -> Result<LvmValue, Error> {
+        let mut items: Vec<(String, LvmValue)> = Vec::default();
+        while !matches!(self.peek(), None | Some(b'}')) {
+            let key = self.ident()?;
+            match self.peek() {
+                Some(b'=') => {
+                    self.pos += 1;
+                    items.push((key, self.value()?));
+                }
+                Some(b'{') => {
+                    self.pos += 1;
+                    let section = self.section()?;
+                    self.expect(b'}')?;
+                    items.push((key, section));
+                }
+                _ => return Err(self.error("expected \"=\" or \"{\"")),
+            }
+        }
+
+        Ok(LvmValue::Section(items))
+    }
+
+    fn value(&mut self) -> Result<LvmValue, Error> {
+        match self.peek() {
+            Some(b'"') => {
+                self.pos += 1;
+                let mut s: Vec<u8> = Vec::default();
+                while self.pos < self.data.len() && self.data[self.pos] != b'"' {
+                    if self.data[self.pos] == b'\\' {
+                        self.pos += 1;
+                    }
+                    if let Some(c) = self.data.get(self.pos) {
+                        s.push(*c);
+                    }
+                    self.pos += 1;
+                }
+                self.expect(b'"')?;
+
+                Ok(LvmValue::Str(String::from_utf8_lossy(&s).to_string()))
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut list: Vec<LvmValue> = Vec::default();
+                while self.peek() != Some(b']') {
+                    list.push(self.value()?);
+                    if self.peek() == Some(b',') {
+                        self.pos += 1;
+                    }
+                }
+                self.expect(b']')?;
+
+                Ok(LvmValue::List(list))
+            }
+            Some(_) => {
+                let v = self.ident()?;
+                match v.parse::<i64>() {
+                    Ok(n) => Ok(LvmValue::Int(n)),
+                    Err(_) => Ok(LvmValue::Str(v)),
+                }
+            }
+            None => Err(self.error("expected a value")),
+        }
+    }
+}
+
+/// Physical volume with the metadata of its volume group
+pub struct LvmPv {
+    path: PathBuf,
+    uuid: String,
+    vgname: String,
+    seqno: i64,
+    vg: LvmValue,
+}
+
+impl LvmPv {
+    /// Read label and metadata of a physical volume
+    pub fn open(p: &Path) -> Result<Self, Error> {
+        let mut f = File::open(p)?;
+        let mut buf = vec![0u8; LABEL_SCAN_SECTORS * SECTOR_SIZE];
+        f.read_exact(&mut buf)?;
+
+        let Some(label) = buf.chunks_exact(SECTOR_SIZE).position(|s| s.starts_with(LABEL_ID) && &s[24..32] == LABEL_TYPE) else {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} is not an LVM2 physical volume", p.display())));
+        };
+
+        // PV header: UUID, device size, then lists of data and metadata areas, each terminated by a zero entry
+        let hdr = label * SECTOR_SIZE
+            + u32::from_le_bytes(buf[label * SECTOR_SIZE + 20..label * SECTOR_SIZE + 24].try_into().unwrap()) as usize;
+        let uuid = String::from_utf8_lossy(buf.get(hdr..hdr + 32).unwrap_or_default()).to_string();
+        let mut locns = buf
+            .get(hdr + 40..)
+            .unwrap_or_default()
+            .chunks_exact(16)
+            .map(|l| (u64::from_le_bytes(l[0..8].try_into().unwrap()), u64::from_le_bytes(l[8..16].try_into().unwrap())));
+        locns.by_ref().take_while(|(offset, _)| *offset != 0).for_each(drop);
+        let mdas = locns.take_while(|(offset, _)| *offset != 0).collect::<Vec<(u64, u64)>>();
+
+        for (offset, _) in mdas {
+            match Self::read_metadata(&mut f, offset) {
+                Ok(text) => {
+                    let meta = LvmParser::parse(&text)?;
+                    let Some((vgname, vg)) = meta.sections().into_iter().find(|(_, v)| v.get("physical_volumes").is_some())
+                    else {
+                        continue;
+                    };
+
+                    return Ok(LvmPv {
+                        path: p.to_path_buf(),
+                        uuid,
+                        vgname: vgname.to_string(),
+                        seqno: vg.get_int("seqno").unwrap_or_default(),
+                        vg: vg.clone(),
+                    });
+                }
+                Err(err) => log::debug!("Metadata area at {} of {}: {}", offset, p.display(), err),
+            }
+        }
+
+        Err(Error::new(ErrorKind::InvalidData, format!("No LVM2 metadata found on {}", p.display())))
+    }
+
+    /// Read the current metadata text from a metadata area, which is a circular buffer
+    fn read_metadata(f: &mut File, offset: u64) -> Result<String, Error> {
+        let mut hdr = [0u8; MDA_HEADER_SIZE as usize];
+        f.seek(SeekFrom::Start(offset))?;
+        f.read_exact(&mut hdr)?;
+
+        if &hdr[4..20] != MDA_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "wrong metadata area magic"));
+        }
+
+        let mda_size = u64::from_le_bytes(hdr[32..40].try_into().unwrap());
+        let raw_offset = u64::from_le_bytes(hdr[40..48].try_into().unwrap());
+        let raw_size = u64::from_le_bytes(hdr[48..56].try_into().unwrap());
+        if raw_offset == 0 || raw_size == 0 || raw_offset >= mda_size {
+            return Err(Error::new(ErrorKind::InvalidData, "no metadata"));
+        }
+
+        let first = raw_size.min(mda_size - raw_offset);
+        let mut text = vec![0u8; raw_size as usize];
+        f.seek(SeekFrom::Start(offset + raw_offset))?;
+        f.read_exact(&mut text[..first as usize])?;
+        if first < raw_size {
+            f.seek(SeekFrom::Start(offset + MDA_HEADER_SIZE))?;
+            f.read_exact(&mut text[first as usize..])?;
+        }
+
+        Ok(String::from_utf8_lossy(&text).trim_end_matches('\0').to_string())
+    }
+
+    /// Get path to the physical volume
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get name of the volume group
+    pub fn get_vgname(&self) -> &str {
+        &self.vgname
+    }
+}
+
+/// Returns true if the name is valid for a volume group or a logical volume,
+/// so e.g. "server:/export" is not a volume
+fn is_valid_name(n: &str) -> bool {
+    !n.is_empty()
+        && !n.starts_with('-')
+        && n != "."
+        && n != ".."
+        && n.chars().all(|c| c.is_ascii_alphanumeric() || "+_.-".contains(c))
+}
+
+/// Parse a reference to a logical volume: "vg/lv" or "/dev/mapper/vg-lv".
+/// Returns names of the volume group and the logical volume.
+pub fn parse_lv_ref(dev: &str) -> Option<(String, String)> {
+    if let Some(name) = dev.strip_prefix("/dev/mapper/") {
+        // Dashes in the names are doubled, a single one separates them. Other device-mapper names,
+        // such as "luks-<uuid>", do not map back to the same name and are not volumes.
+        let b = name.as_bytes();
+        let mut i = 0;
+        while i < b.len() {
+            if b[i] == b'-' {
+                if b.get(i + 1) == Some(&b'-') {
+                    i += 2;
+                    continue;
+                }
+                let (vg, lv) = (name[..i].replace("--", "-"), name[i + 1..].replace("--", "-"));
+                return (is_valid_name(&vg) && is_valid_name(&lv) && get_dm_name(&vg, &lv) == name).then_some((vg, lv));
+            }
+            i += 1;
+        }
+
+        return None;
+    }
+
+    match dev.split_once('/') {
+        Some((vg, lv)) if is_valid_name(vg) && is_valid_name(lv) => Some((vg.to_string(), lv.to_string())),
+        _ => None,
+    }
+}
+
+/// Get device-mapper name of a logical volume
+pub fn get_dm_name(vg: &str, lv: &str) -> String {
+    format!("{}-{}", vg.replace('-', "--"), lv.replace('-', "--"))
+}
+
+/// Activate a logical volume from the given physical volumes.
+/// Returns path to the device node in /dev/mapper.
+///
+/// Error kind is `NotFound` if the volume group, the volume or any of its physical volumes is missing,
+/// so it can be retried when more devices appear.
+pub fn activate(vgname: &str, lvname: &str, pvs: &[LvmPv]) -> Result<PathBuf, Error> {
+    let (targets, uuid, readonly) = get_table(vgname, lvname, pvs)?;
+    let node = dm::create(&get_dm_name(vgname, lvname), &uuid, &targets, readonly)?;
+
+    // Also /dev/<vg>/<lv>, as LVM does
+    let vgdir = Path::new("/dev").join(vgname);
+    fs::create_dir_all(&vgdir)?;
+    symlink(Path::new("../mapper").join(node.file_name().unwrap_or_default()), vgdir.join(lvname)).unwrap_or_default();
+
+    Ok(node)
+}
+
+/// Get the device-mapper table of a logical volume, its device-mapper UUID and whether it is read-only
+fn get_table(vgname: &str, lvname: &str, pvs: &[LvmPv]) -> Result<(Vec<DmTarget>, String, bool), Error> {
+    // The most recent metadata of the volume group
+    let Some(meta) = pvs.iter().filter(|pv| pv.vgname == vgname).max_by_key(|pv| pv.seqno).map(|pv| &pv.vg) else {
+        return Err(Error::new(ErrorKind::NotFound, format!("Volume group {} was not found", vgname)));
+    };
+
+    let Some(lv) = meta.get("logical_volumes").and_then(|lvs| lvs.get(lvname)) else {
+        return Err(Error::new(ErrorKind::NotFound, format!("Logical volume {}/{} was not found", vgname, lvname)));
+    };
+
+    let invalid = |what: &str| Error::new(ErrorKind::InvalidData, format!("Metadata of {}/{}: {}", vgname, lvname, what));
+    let extent_size = meta.get_int("extent_size").ok_or_else(|| invalid("no extent size"))? as u64;
+
+    // Physical volume name in the metadata -> device path and start of its extents
+    let devices = pvs.iter().map(|pv| (pv.uuid.as_str(), pv.path.as_path())).collect::<HashMap<&str, &Path>>();
+    let mut pvmap: HashMap<&str, (&Path, u64)> = HashMap::default();
+    for (name, pv) in meta.get("physical_volumes").map(|p| p.sections()).unwrap_or_default() {
+        let id = pv.get_str("id").unwrap_or_default().replace('-', "");
+        if let Some(dev) = devices.get(id.as_str()) {
+            pvmap.insert(name, (dev, pv.get_int("pe_start").unwrap_or_default() as u64));
+        }
+    }
+
+    let mut targets: Vec<DmTarget> = Vec::default();
+    let mut segments = lv.sections().into_iter().filter(|(k, _)| k.starts_with("segment")).map(|(_, s)| s).collect::<Vec<_>>();
+    segments.sort_by_key(|s| s.get_int("start_extent").unwrap_or_default());
+
+    for seg in segments {
+        let start = seg.get_int("start_extent").ok_or_else(|| invalid("no segment start"))? as u64 * extent_size;
+        let length = seg.get_int("extent_count").ok_or_else(|| invalid("no segment size"))? as u64 * extent_size;
+        let stype = seg.get_str("type").unwrap_or_default();
+        if stype != "striped" {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("Segment type {} of {}/{} is not supported", stype, vgname, lvname),
+            ));
+        }
+
+        // Stripes are pairs of a physical volume name and its first extent
+        let mut stripes: Vec<String> = Vec::default();
+        for pair in seg.get_list("stripes").chunks(2) {
+            let [LvmValue::Str(pv), LvmValue::Int(pe)] = pair else {
+                return Err(invalid("wrong stripes"));
+            };
+            let Some((dev, pe_start)) = pvmap.get(pv.as_str()) else {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("Physical volume {} of {}/{} is missing", pv, vgname, lvname),
+                ));
+            };
+            stripes.push(format!("{} {}", dev.display(), pe_start + *pe as u64 * extent_size));
+        }
+
+        match stripes.len() {
+            0 => return Err(invalid("no stripes")),
+            1 => targets.push(DmTarget::new(start, length, "linear", &stripes[0])),
+            n => {
+                let chunk = seg.get_int("stripe_size").ok_or_else(|| invalid("no stripe size"))?;
+                targets.push(DmTarget::new(start, length, "striped", &format!("{} {} {}", n, chunk, stripes.join(" "))));
+            }
+        }
+    }
+
+    let uuid = format!(
+        "LVM-{}{}",
+        meta.get_str("id").unwrap_or_default().replace('-', ""),
+        lv.get_str("id").unwrap_or_default().replace('-', "")
+    );
+
+    Ok((targets, uuid, !lv.has_status("WRITE")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Physical volume "pv0" of "vg0" from the fixture image
+    fn fixture() -> LvmPv {
+        let img = std::env::temp_dir().join(format!("microhop-lvm-{}.img", std::process::id()));
+        let data =
+            crate::compress::decompress(&Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/probe/lvm2.img.zst")).unwrap();
+        fs::write(&img, data).unwrap();
+        let pv = LvmPv::open(&img).unwrap();
+        fs::remove_file(&img).unwrap();
+
+        pv
+    }
+
+    fn table(targets: &[DmTarget]) -> Vec<(u64, u64, &str, &str)> {
+        targets.iter().map(|t| (t.start, t.length, t.ttype.as_str(), t.params.as_str())).collect()
+    }
+
+    #[test]
+    fn lv_refs() {
+        let lv = |vg: &str, lv: &str| Some((vg.to_string(), lv.to_string()));
+        assert_eq!(parse_lv_ref("vg0/root"), lv("vg0", "root"));
+        assert_eq!(parse_lv_ref("/dev/mapper/vg0-root"), lv("vg0", "root"));
+        assert_eq!(parse_lv_ref("/dev/mapper/my--vg-my--root"), lv("my-vg", "my-root"));
+        assert_eq!(parse_lv_ref("/dev/mapper/vg-lv--"), lv("vg", "lv-"));
+        assert_eq!(parse_lv_ref("/dev/mapper/vg---lv"), lv("vg-", "lv"));
+
+        // Names, which do not map back to the same device-mapper name
+        assert_eq!(parse_lv_ref("/dev/mapper/luks-0f7a8e3c-1234-5678"), None);
+        assert_eq!(parse_lv_ref("/dev/mapper/vg-lv-"), None);
+        assert_eq!(parse_lv_ref("/dev/mapper/root"), None);
+
+        // Not volumes at all
+        assert_eq!(parse_lv_ref("server:/export"), None);
+        assert_eq!(parse_lv_ref("/dev/vda2"), None);
+        assert_eq!(parse_lv_ref("vg/lv/x"), None);
+        assert_eq!(parse_lv_ref("./lv"), None);
+        assert_eq!(parse_lv_ref("vg/-lv"), None);
+        assert_eq!(parse_lv_ref("vg/"), None);
+
+        for (vg, lv) in [("vg0", "root"), ("my-vg", "lv--x"), ("a.b", "c+d")] {
+            assert_eq!(parse_lv_ref(&format!("/dev/mapper/{}", get_dm_name(vg, lv))), Some((vg.to_string(), lv.to_string())));
+        }
+    }
+
+    #[test]
+    fn parse_metadata() {
+        let meta = LvmParser::parse(
+            "# comment\nvg {\nid = \"a-b\"\nseqno = 3 # trailing\nneg = -1\nflags = []\n\
+             status = [\"READ\", \"WRITE\"]\nstripes = [\n\"pv0\", 0,\n\"pv1\", 10\n]\n\
+             desc = \"say \\\"hi\\\"\"\nsub {\nx = 1\n}\n}\ncontents = \"Text\"\n",
+        )
+        .unwrap();
+
+        let vg = meta.get("vg").unwrap();
+        assert_eq!(vg.get_str("id"), Some("a-b"));
+        assert_eq!(vg.get_int("seqno"), Some(3));
+        assert_eq!(vg.get_int("neg"), Some(-1));
+        assert!(vg.get_list("flags").is_empty());
+        assert!(vg.has_status("WRITE") && !vg.has_status("VISIBLE"));
+        assert_eq!(vg.get_list("stripes").len(), 4);
+        assert_eq!(vg.get_str("desc"), Some("say \"hi\""));
+        assert_eq!(vg.sections().iter().map(|(k, _)| *k).collect::<Vec<&str>>(), vec!["sub"]);
+        assert_eq!(meta.get_str("contents"), Some("Text"));
+
+        for broken in ["vg {\nx = 1\n", "vg }", "x 1", "x = \"open", "x = [1, 2"] {
+            assert_eq!(LvmParser::parse(broken).unwrap_err().kind(), ErrorKind::InvalidData, "{}", broken);
+        }
+    }
+
+    #[test]
+    fn open_pv() {
+        let pv = fixture();
+        assert_eq!(pv.get_vgname(), "vg0");
+        assert_eq!(pv.uuid, "abcdefGHIJklmnOPQRstuvWXYZ012345");
+        assert_eq!(pv.seqno, 3);
+        assert_eq!(pv.vg.get_int("extent_size"), Some(8192));
+    }
+
+    #[test]
+    fn linear_table() {
+        let pv = fixture();
+        let dev = pv.get_path().display().to_string();
+        let (targets, uuid, readonly) = get_table("vg0", "root", &[pv]).unwrap();
+
+        assert_eq!(
+            table(&targets),
+            vec![
+                (0, 81920, "linear", format!("{} 2048", dev).as_str()),
+                (81920, 40960, "linear", format!("{} 165888", dev).as_str())
+            ]
+        );
+        assert_eq!(uuid, "LVM-Vg0Vg0Vg0Vg0Vg0Vg0Vg0Vg0Vg0Vg0VgRootRootRootRootRootRootRootRoot");
+        assert!(!readonly);
+    }
+
+    #[test]
+    fn striped_table() {
+        let pv0 = fixture();
+        let dev = pv0.get_path().display().to_string();
+        assert_eq!(get_table("vg0", "data", std::slice::from_ref(&pv0)).err().unwrap().kind(), ErrorKind::NotFound);
+
+        let pv1 = LvmPv {
+            path: PathBuf::from("/dev/vdb2"),
+            uuid: "zyxwvuTSRQponmLKJIhgfeDCBA987654".to_string(),
+            vgname: "vg0".to_string(),
+            seqno: 2,
+            vg: pv0.vg.clone(),
+        };
+        let (targets, _, readonly) = get_table("vg0", "data", &[pv0, pv1]).unwrap();
+        assert_eq!(table(&targets), vec![(0, 65536, "striped", format!("2 128 {} 247808 /dev/vdb2 2048", dev).as_str())]);
+        assert!(readonly);
+    }
+
+    #[test]
+    fn missing_volumes() {
+        let pvs = [fixture()];
+        assert_eq!(get_table("vg1", "root", &pvs).err().unwrap().kind(), ErrorKind::NotFound);
+        assert_eq!(get_table("vg0", "home", &pvs).err().unwrap().kind(), ErrorKind::NotFound);
+        assert_eq!(get_table("vg0", "pool", &pvs).err().unwrap().kind(), ErrorKind::Unsupported);
+    }
+}
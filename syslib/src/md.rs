@@ -0,0 +1,325 @@
+//! Linux software RAID (md).
+//!
+//! A minimal replacement of "mdadm --assemble": it reads the superblocks
+//! (versions 0.90 and 1.x) of the member devices and assembles the arrays
+//! via md ioctls. The kernel does the rest, reading the superblocks itself.
+
+use crate::blk;
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    libc,
+    sys::stat::{self, Mode, SFlag},
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind, Read, Seek, SeekFrom},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{symlink, MetadataExt, OpenOptionsExt},
+    },
+    path::{Path, PathBuf},
+};
+
+static MD_DIR: &str = "/dev/md";
+
+/// Block device major number of the md devices
+const MD_MAJOR: u64 = 9;
+
+/// Arrays without a fixed minor are created from this one downwards, as mdadm does
+const MD_MINOR_AUTO: u64 = 127;
+
+/// Kernel modules of the RAID levels (personalities)
+pub const MD_PERSONALITIES: &[&str] = &["linear", "raid0", "raid1", "raid456", "raid10"];
+
+const MD_SB_MAGIC: u32 = 0xa92b4efc;
+
+/// Roles of the version 1.x members above this one are spare (0xffff) or faulty (0xfffe)
+const MD_ROLE_MAX: u16 = 0xff00;
+
+/// State bits of a version 0.90 member: faulty and in sync with the array
+const MD_DISK_FAULTY: u32 = 1 << 0;
+const MD_DISK_SYNC: u32 = 1 << 2;
+
+// Size of mdu_array_info_t, mdu_disk_info_t and mdu_param_t
+const MDU_ARRAY_INFO_SIZE: usize = 18 * 4;
+const MDU_DISK_INFO_SIZE: usize = 5 * 4;
+const MDU_PARAM_SIZE: usize = 3 * 4;
+
+/// Member device of an array
+pub struct MdMember {
+    path: PathBuf,
+    uuid: String,
+    name: String,
+    version: (u32, u32),
+    level: i32,
+    raid_disks: u32,
+    events: u64,
+    active: bool,
+}
+
+impl MdMember {
+    /// Read superblock of a member device.
+    /// Versions 1.2, 1.1, 1.0 and 0.90 are tried in this order.
+    pub fn open(p: &Path) -> Result<Self, Error> {
+        let size = blk::get_size(p)?;
+        let mut f = File::open(p)?;
+
+        for (minor, offset) in [(2, 4096), (1, 0), (0, (size.saturating_sub(8192)) & !4095)] {
+            if let Ok(sb) = Self::read_sb(&mut f, offset, 256) {
+                if u32::from_le_bytes(sb[0..4].try_into().unwrap()) == MD_SB_MAGIC
+                    && u32::from_le_bytes(sb[4..8].try_into().unwrap()) == 1
+                {
+                    let dev_number = u32::from_le_bytes(sb[160..164].try_into().unwrap()) as usize;
+                    let role = Self::read_sb(&mut f, offset + 256 + dev_number as u64 * 2, 2)
+                        .map(|r| u16::from_le_bytes(r[0..2].try_into().unwrap()))
+                        .unwrap_or(u16::MAX);
+
+                    return Ok(MdMember {
+                        path: p.to_path_buf(),
+                        uuid: sb[16..32].iter().map(|b| format!("{:02x}", b)).collect(),
+                        name: String::from_utf8_lossy(&sb[32..64]).trim_end_matches('\0').to_string(),
+                        version: (1, minor),
+                        level: i32::from_le_bytes(sb[72..76].try_into().unwrap()),
+                        raid_disks: u32::from_le_bytes(sb[92..96].try_into().unwrap()),
+                        events: u64::from_le_bytes(sb[200..208].try_into().unwrap()),
+                        active: role < MD_ROLE_MAX,
+                    });
+                }
+            }
+        }
+
+        // Version 0.90 is in the last 64K aligned block, in the host byte order
+        if let Ok(sb) = Self::read_sb(&mut f, (size & !0xffff).saturating_sub(0x10000), 4096) {
+            let word = |i: usize| u32::from_ne_bytes(sb[i * 4..i * 4 + 4].try_into().unwrap());
+            if word(0) == MD_SB_MAGIC && word(1) == 0 {
+                return Ok(MdMember {
+                    path: p.to_path_buf(),
+                    uuid: [word(5), word(13), word(14), word(15)].iter().map(|w| format!("{:08x}", w)).collect(),
+                    name: String::default(),
+                    version: (0, 90),
+                    level: word(7) as i32,
+                    raid_disks: word(10),
+                    events: ((word(39) as u64) << 32) | word(40) as u64,
+                    // Descriptor of this member: number, major, minor, raid_disk, state
+                    active: word(992 + 3) < word(10) && word(992 + 4) & (MD_DISK_FAULTY | MD_DISK_SYNC) == MD_DISK_SYNC,
+                });
+            }
+        }
+
+        Err(Error::new(ErrorKind::InvalidData, format!("No md superblock found on {}", p.display())))
+    }
+
+    fn read_sb(f: &mut File, offset: u64, size: usize) -> Result<Vec<u8>, Error> {
+        let mut sb = vec![0u8; size];
+        f.seek(SeekFrom::Start(offset))?;
+        f.read_exact(&mut sb)?;
+
+        Ok(sb)
+    }
+
+    /// Get path to the member device
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get UUID of the array as 32 hex digits
+    pub fn get_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    /// Get name of the array (version 1.x only), such as "host:root"
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get number of devices in the complete array
+    pub fn get_raid_disks(&self) -> usize {
+        self.raid_disks as usize
+    }
+
+    /// Get event counter, which is the highest on the most recent members
+    pub fn get_events(&self) -> u64 {
+        self.events
+    }
+
+    /// Returns true if the member holds data of the array, i.e. it is neither a spare nor faulty
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Get kernel module of the RAID level (personality)
+    pub fn get_personality(&self) -> Option<&str> {
+        match self.level {
+            -1 => Some("linear"),
+            0 => Some("raid0"),
+            1 => Some("raid1"),
+            4..=6 => Some("raid456"),
+            10 => Some("raid10"),
+            _ => None,
+        }
+    }
+}
+
+/// Get the members, which are up to date: those with the highest event counter.
+/// Members, which missed the last updates, would bring stale data into the array.
+pub fn get_recent<'a>(members: &[&'a MdMember]) -> Vec<&'a MdMember> {
+    let events = members.iter().map(|m| m.events).max().unwrap_or_default();
+    members.iter().filter(|m| m.events == events).copied().collect()
+}
+
+/// Returns true if the members are enough to assemble the array complete.
+/// Only active members count, spares and faulty ones do not hold data of the array.
+pub fn is_complete(members: &[&MdMember]) -> bool {
+    members.first().is_some_and(|m| members.iter().filter(|m| m.active).count() >= m.get_raid_disks())
+}
+
+/// Normalise an array UUID to 32 hex digits, as it can be written by mdadm ("a:b:c:d") or blkid (with dashes)
+pub fn normalise_uuid(uuid: &str) -> String {
+    uuid.chars().filter(|c| c.is_ascii_hexdigit()).collect::<String>().to_lowercase()
+}
+
+/// Call an md ioctl on the array
+fn md_ioctl(md: &File, req: nix::sys::ioctl::ioctl_num_type, arg: Option<&[u8]>) -> Result<(), Error> {
+    let ptr = arg.map(|a| a.as_ptr()).unwrap_or(std::ptr::null());
+    Errno::result(unsafe { libc::ioctl(md.as_raw_fd(), req as _, ptr) })?;
+
+    Ok(())
+}
+
+/// Assemble an array from its members as /dev/md/<name>.
+/// Returns path to the array device.
+pub fn assemble(name: &str, members: &[&MdMember]) -> Result<PathBuf, Error> {
+    let Some(first) = members.first() else {
+        return Err(Error::new(ErrorKind::NotFound, format!("No members of the array {}", name)));
+    };
+
+    let Some(minor) = (0..=MD_MINOR_AUTO).rev().find(|n| !Path::new(&format!("/sys/block/md{}", n)).exists()) else {
+        return Err(Error::other("No free md devices"));
+    };
+
+    // The array is created by the kernel on opening its device node
+    let node = PathBuf::from(format!("/dev/md{}", minor));
+    if !node.exists() {
+        stat::mknod(&node, SFlag::S_IFBLK, Mode::S_IRUSR | Mode::S_IWUSR, stat::makedev(MD_MAJOR, minor))?;
+    }
+    let md = OpenOptions::new().read(true).write(true).custom_flags(OFlag::O_CLOEXEC.bits()).open(&node)?;
+
+    let run = || -> Result<(), Error> {
+        // SET_ARRAY_INFO: only the superblock version, the rest is read by the kernel from the members
+        let mut info = vec![0u8; MDU_ARRAY_INFO_SIZE];
+        info[0..4].copy_from_slice(&first.version.0.to_ne_bytes());
+        info[4..8].copy_from_slice(&first.version.1.to_ne_bytes());
+        md_ioctl(&md, nix::request_code_write!(MD_MAJOR, 0x23, MDU_ARRAY_INFO_SIZE), Some(&info))?;
+
+        // ADD_NEW_DISK: number, major, minor, raid_disk, state
+        for m in members {
+            let rdev = fs::metadata(&m.path)?.rdev();
+            let mut disk = vec![0u8; MDU_DISK_INFO_SIZE];
+            disk[4..8].copy_from_slice(&(stat::major(rdev) as u32).to_ne_bytes());
+            disk[8..12].copy_from_slice(&(stat::minor(rdev) as u32).to_ne_bytes());
+            md_ioctl(&md, nix::request_code_write!(MD_MAJOR, 0x21, MDU_DISK_INFO_SIZE), Some(&disk))
+                .map_err(|err| Error::new(err.kind(), format!("Unable to add {}: {}", m.path.display(), err)))?;
+        }
+
+        // RUN_ARRAY without parameters
+        md_ioctl(&md, nix::request_code_write!(MD_MAJOR, 0x30, MDU_PARAM_SIZE), None)
+    };
+
+    if let Err(err) = run() {
+        md_ioctl(&md, nix::request_code_none!(MD_MAJOR, 0x32), None).unwrap_or_default(); // STOP_ARRAY
+        return Err(Error::new(err.kind(), format!("Unable to assemble the array {}: {}", name, err)));
+    }
+
+    fs::create_dir_all(MD_DIR)?;
+    symlink(Path::new("..").join(node.file_name().unwrap_or_default()), Path::new(MD_DIR).join(name)).unwrap_or_default();
+    log::debug!("Assembled {} from {} devices", node.display(), members.len());
+
+    Ok(node)
+}
+
+/// Returns true if the array is assembled
+pub fn exists(name: &str) -> bool {
+    Path::new(MD_DIR).join(name).exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Member from a fixture image
+    fn fixture(name: &str) -> MdMember {
+        let img = std::env::temp_dir().join(format!("microhop-{}-{}.img", name, std::process::id()));
+        let data =
+            crate::compress::decompress(&Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("testdata/probe/{}.img.zst", name)))
+                .unwrap();
+        fs::write(&img, data).unwrap();
+        let m = MdMember::open(&img);
+        fs::remove_file(&img).unwrap();
+
+        m.unwrap()
+    }
+
+    fn member(events: u64, active: bool) -> MdMember {
+        MdMember {
+            path: PathBuf::default(),
+            uuid: String::default(),
+            name: String::default(),
+            version: (1, 2),
+            level: 1,
+            raid_disks: 2,
+            events,
+            active,
+        }
+    }
+
+    #[test]
+    fn superblock_1_2() {
+        let m = fixture("md12");
+        assert_eq!(m.get_uuid(), "0123abcd456789abcdef0123456789ab");
+        assert_eq!(m.get_uuid(), normalise_uuid("0123abcd-4567-89ab-cdef-0123456789ab"));
+        assert_eq!(m.get_name(), "host:root");
+        assert_eq!(m.version, (1, 2));
+        assert_eq!(m.get_raid_disks(), 2);
+        assert_eq!(m.get_events(), 5);
+        assert_eq!(m.get_personality(), Some("raid1"));
+        assert!(m.is_active());
+    }
+
+    #[test]
+    fn superblock_0_90() {
+        let m = fixture("md090");
+        assert_eq!(m.get_uuid(), normalise_uuid("cdab2301-ab89-6745-2301-efcdab896745"));
+        assert_eq!(m.get_name(), "");
+        assert_eq!(m.version, (0, 90));
+        assert_eq!(m.get_raid_disks(), 2);
+        assert_eq!(m.get_events(), 5);
+        assert_eq!(m.get_personality(), Some("raid1"));
+        assert!(m.is_active());
+    }
+
+    #[test]
+    fn no_superblock() {
+        let img = std::env::temp_dir().join(format!("microhop-md-none-{}.img", std::process::id()));
+        fs::write(&img, vec![0u8; 0x20000]).unwrap();
+        let m = MdMember::open(&img);
+        fs::remove_file(&img).unwrap();
+
+        assert_eq!(m.err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn complete() {
+        let (a, b, spare, stale) = (member(7, true), member(7, true), member(7, false), member(6, true));
+        assert!(is_complete(&[&a, &b]));
+        assert!(is_complete(&[&a, &b, &spare]));
+        assert!(!is_complete(&[&a, &spare]));
+        assert!(!is_complete(&[]));
+
+        // A stale member does not complete the array
+        assert!(is_complete(&[&a, &stale]));
+        assert_eq!(get_recent(&[&stale, &a, &spare]).iter().map(|m| m.get_events()).collect::<Vec<u64>>(), vec![7, 7]);
+        assert!(!is_complete(&get_recent(&[&a, &stale])));
+        assert!(get_recent(&[]).is_empty());
+    }
+}
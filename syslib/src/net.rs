@@ -0,0 +1,213 @@
+//! Network interfaces.
+//!
+//! A minimal replacement of "ip link/addr/route": it brings the interfaces
+//! up and down and assigns IPv4 addresses and routes via rtnetlink.
+//! The interfaces themselves are listed from sysfs.
+
+use nix::{
+    libc,
+    sys::socket::{self, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType},
+};
+use std::{
+    fs,
+    io::{Error, ErrorKind},
+    net::Ipv4Addr,
+    os::fd::{AsRawFd, OwnedFd},
+    path::Path,
+};
+
+static SYS_NET: &str = "/sys/class/net";
+
+/// ARPHRD_ETHER: type of the Ethernet interfaces in sysfs
+const ARPHRD_ETHER: u32 = 1;
+
+// Size of struct nlmsghdr
+const NLMSG_HDR_SIZE: usize = 16;
+
+// Message types
+const NLMSG_ERROR: u16 = 2;
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+const RTM_NEWROUTE: u16 = 24;
+
+// Message flags
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_REPLACE: u16 = 0x100;
+const NLM_F_CREATE: u16 = 0x400;
+
+// Attributes
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFA_BROADCAST: u16 = 4;
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+
+const IFF_UP: u32 = 0x1;
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RTN_UNICAST: u8 = 1;
+
+/// Get names of the Ethernet interfaces
+pub fn get_interfaces() -> Vec<String> {
+    let mut ifaces = fs::read_dir(SYS_NET)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .filter(|n| read_attr(n, "type").is_ok_and(|t| t.parse::<u32>() == Ok(ARPHRD_ETHER)))
+        .collect::<Vec<String>>();
+    ifaces.sort();
+
+    ifaces
+}
+
+/// Read an attribute of the interface from sysfs
+fn read_attr(iface: &str, attr: &str) -> Result<String, Error> {
+    Ok(fs::read_to_string(Path::new(SYS_NET).join(iface).join(attr))?.trim().to_string())
+}
+
+/// Get index of the interface
+pub fn get_index(iface: &str) -> Result<u32, Error> {
+    read_attr(iface, "ifindex")?
+        .parse::<u32>()
+        .map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid index of the interface {}", iface)))
+}
+
+/// Get hardware address of the interface
+pub fn get_mac(iface: &str) -> Result<[u8; 6], Error> {
+    let mut mac = [0u8; 6];
+    let addr = read_attr(iface, "address")?;
+    for (i, b) in addr.split(':').enumerate() {
+        if i >= mac.len() {
+            break;
+        }
+        mac[i] =
+            u8::from_str_radix(b, 16).map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid address {}", addr)))?;
+    }
+
+    Ok(mac)
+}
+
+/// Returns true if the interface is connected
+pub fn has_carrier(iface: &str) -> bool {
+    read_attr(iface, "carrier").is_ok_and(|c| c == "1")
+}
+
+/// Get netmask length of the IPv4 netmask
+pub fn get_prefix(netmask: Ipv4Addr) -> u8 {
+    u32::from(netmask).leading_ones() as u8
+}
+
+/// Route netlink socket
+pub struct RtNetlink {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl RtNetlink {
+    pub fn new() -> Result<Self, Error> {
+        let fd = socket::socket(AddressFamily::Netlink, SockType::Raw, SockFlag::SOCK_CLOEXEC, SockProtocol::NetlinkRoute)?;
+        socket::bind(fd.as_raw_fd(), &NetlinkAddr::new(0, 0))?;
+
+        Ok(RtNetlink { fd, seq: 0 })
+    }
+
+    /// Send a request and wait for its acknowledgement
+    fn request(&mut self, mtype: u16, flags: u16, payload: &[u8]) -> Result<(), Error> {
+        self.seq += 1;
+        let mut msg = Vec::with_capacity(NLMSG_HDR_SIZE + payload.len());
+        msg.extend(((NLMSG_HDR_SIZE + payload.len()) as u32).to_ne_bytes());
+        msg.extend(mtype.to_ne_bytes());
+        msg.extend((flags | NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+        msg.extend(self.seq.to_ne_bytes());
+        msg.extend(0u32.to_ne_bytes());
+        msg.extend(payload);
+        socket::sendto(self.fd.as_raw_fd(), &msg, &NetlinkAddr::new(0, 0), MsgFlags::empty())?;
+
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let len = socket::recv(self.fd.as_raw_fd(), &mut buf, MsgFlags::empty())?;
+            let mut off = 0;
+            while off + NLMSG_HDR_SIZE <= len {
+                let mlen = u32::from_ne_bytes(buf[off..off + 4].try_into().unwrap()) as usize;
+                let mtype = u16::from_ne_bytes(buf[off + 4..off + 6].try_into().unwrap());
+                let seq = u32::from_ne_bytes(buf[off + 8..off + 12].try_into().unwrap());
+                if mlen < NLMSG_HDR_SIZE {
+                    break;
+                }
+
+                if mtype == NLMSG_ERROR && seq == self.seq && off + NLMSG_HDR_SIZE + 4 <= len {
+                    let errno = i32::from_ne_bytes(buf[off + NLMSG_HDR_SIZE..off + NLMSG_HDR_SIZE + 4].try_into().unwrap());
+                    return match errno {
+                        0 => Ok(()),
+                        e => Err(Error::from_raw_os_error(-e)),
+                    };
+                }
+                off += (mlen + 3) & !3;
+            }
+        }
+    }
+
+    /// Bring the interface up or down
+    pub fn set_up(&mut self, index: u32, up: bool) -> Result<(), Error> {
+        // struct ifinfomsg: family, type, index, flags, change
+        let mut msg = vec![0u8; 16];
+        msg[4..8].copy_from_slice(&(index as i32).to_ne_bytes());
+        msg[8..12].copy_from_slice(&(if up { IFF_UP } else { 0 }).to_ne_bytes());
+        msg[12..16].copy_from_slice(&IFF_UP.to_ne_bytes());
+
+        self.request(RTM_NEWLINK, 0, &msg)
+    }
+
+    /// Build struct ifaddrmsg with the address attributes
+    fn ifaddr_msg(index: u32, addr: Ipv4Addr, prefix: u8) -> Vec<u8> {
+        let mut msg = vec![libc::AF_INET as u8, prefix, 0, RT_SCOPE_UNIVERSE];
+        msg.extend(index.to_ne_bytes());
+        add_attr(&mut msg, IFA_LOCAL, &addr.octets());
+        add_attr(&mut msg, IFA_ADDRESS, &addr.octets());
+        if prefix < 31 {
+            let brd = u32::from(addr) | (u32::MAX >> prefix);
+            add_attr(&mut msg, IFA_BROADCAST, &brd.to_be_bytes());
+        }
+
+        msg
+    }
+
+    /// Assign an address to the interface
+    pub fn add_address(&mut self, index: u32, addr: Ipv4Addr, prefix: u8) -> Result<(), Error> {
+        self.request(RTM_NEWADDR, NLM_F_CREATE | NLM_F_REPLACE, &Self::ifaddr_msg(index, addr, prefix))
+    }
+
+    /// Remove an address from the interface
+    pub fn del_address(&mut self, index: u32, addr: Ipv4Addr, prefix: u8) -> Result<(), Error> {
+        self.request(RTM_DELADDR, 0, &Self::ifaddr_msg(index, addr, prefix))
+    }
+
+    /// Add a route via the interface and the gateway, if any. Prefix 0 is the default route.
+    pub fn add_route(&mut self, index: u32, dst: Ipv4Addr, prefix: u8, gateway: Option<Ipv4Addr>) -> Result<(), Error> {
+        // struct rtmsg: family, dst_len, src_len, tos, table, protocol, scope, type, flags
+        let mut msg = vec![libc::AF_INET as u8, prefix, 0, 0, RT_TABLE_MAIN, RTPROT_BOOT, RT_SCOPE_UNIVERSE, RTN_UNICAST];
+        msg.extend(0u32.to_ne_bytes());
+        if prefix > 0 {
+            add_attr(&mut msg, RTA_DST, &dst.octets());
+        }
+        if let Some(gw) = gateway {
+            add_attr(&mut msg, RTA_GATEWAY, &gw.octets());
+        }
+        add_attr(&mut msg, RTA_OIF, &index.to_ne_bytes());
+
+        self.request(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_REPLACE, &msg)
+    }
+}
+
+/// Append struct rtattr with its data, aligned to 4 bytes
+fn add_attr(msg: &mut Vec<u8>, atype: u16, data: &[u8]) {
+    msg.extend(((4 + data.len()) as u16).to_ne_bytes());
+    msg.extend(atype.to_ne_bytes());
+    msg.extend(data);
+    msg.resize((msg.len() + 3) & !3, 0);
+}
@@ -0,0 +1,249 @@
+//! Partition tables.
+//!
+//! Reads GPT and MBR partition tables of a disk to identify its partitions
+//! by their unique IDs (PARTUUID) and names (PARTLABEL), as the kernel does for "root=PARTUUID=".
+//! For MBR, the ID is the disk signature with the partition number, and there are no names.
+
+use std::{
+    fs::File,
+    io::{Error, ErrorKind, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+// MBR layout
+const MBR_SIZE: usize = 512;
+const MBR_SIGNATURE_OFFSET: usize = 440;
+const MBR_PARTITIONS_OFFSET: usize = 446;
+const MBR_BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+/// Partition type of the protective MBR in front of the GPT
+const MBR_TYPE_GPT: u8 = 0xee;
+
+// GPT header layout
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const GPT_ENTRIES_LBA_OFFSET: usize = 72;
+const GPT_ENTRIES_NUM_OFFSET: usize = 80;
+const GPT_ENTRY_SIZE_OFFSET: usize = 84;
+
+// GPT entry layout
+const GPT_ENTRY_UUID_OFFSET: usize = 16;
+const GPT_ENTRY_NAME_OFFSET: usize = 56;
+const GPT_ENTRY_NAME_SIZE: usize = 72;
+
+/// Upper limit of the GPT entries, which are read
+const GPT_MAX_ENTRIES: usize = 1024;
+
+/// Partition of a disk
+#[derive(Debug, Clone)]
+pub struct PartEntry {
+    number: u32,
+    uuid: String,
+    name: String,
+}
+
+impl PartEntry {
+    /// Get partition number, starting from 1
+    pub fn get_number(&self) -> u32 {
+        self.number
+    }
+
+    /// Get unique ID of the partition
+    pub fn get_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    /// Get name of the partition, which is empty on MBR
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Partition table of a disk
+#[derive(Debug, Clone, Default)]
+pub struct PartTable {
+    // Disk signature of MBR
+    signature: Option<u32>,
+
+    // Partitions of GPT
+    entries: Vec<PartEntry>,
+}
+
+impl PartTable {
+    /// Get partition by its number. Any partition of MBR is known by the disk signature, including logical ones.
+    pub fn get_entry(&self, number: u32) -> Option<PartEntry> {
+        if let Some(sig) = self.signature {
+            return Some(PartEntry { number, uuid: format!("{:08x}-{:02x}", sig, number), name: String::new() });
+        }
+
+        self.entries.iter().find(|e| e.number == number).cloned()
+    }
+
+    /// Get partitions of GPT
+    pub fn get_entries(&self) -> &[PartEntry] {
+        &self.entries
+    }
+}
+
+/// Format a GUID, which is stored in the mixed endian
+fn fmt_guid(g: &[u8]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{}-{}",
+        u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+        u16::from_le_bytes([g[4], g[5]]),
+        u16::from_le_bytes([g[6], g[7]]),
+        g[8..10].iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+        g[10..16].iter().map(|b| format!("{:02x}", b)).collect::<String>()
+    )
+}
+
+/// Read the partition table of the disk with the given logical sector size.
+/// Returns an empty table, if there is none.
+pub fn read(disk: &Path, sector_size: u64) -> Result<PartTable, Error> {
+    let mut f = File::open(disk)?;
+    let mut mbr = [0u8; MBR_SIZE];
+    f.read_exact(&mut mbr)?;
+    if mbr[MBR_SIZE - 2..] != MBR_BOOT_SIGNATURE {
+        return Ok(PartTable::default());
+    }
+
+    if (0..4).any(|i| mbr[MBR_PARTITIONS_OFFSET + i * 16 + 4] == MBR_TYPE_GPT) {
+        return Ok(PartTable { signature: None, entries: read_gpt(&mut f, sector_size)? });
+    }
+
+    let sig = u32::from_le_bytes(mbr[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 4].try_into().unwrap());
+    Ok(PartTable { signature: Some(sig), entries: Vec::default() })
+}
+
+/// Read the GPT, which header is at LBA 1
+fn read_gpt(f: &mut File, sector_size: u64) -> Result<Vec<PartEntry>, Error> {
+    let mut hdr = vec![0u8; sector_size as usize];
+    f.seek(SeekFrom::Start(sector_size))?;
+    f.read_exact(&mut hdr)?;
+    if !hdr.starts_with(GPT_SIGNATURE) {
+        return Err(Error::new(ErrorKind::InvalidData, "Protective MBR without the GPT header"));
+    }
+
+    let lba = u64::from_le_bytes(hdr[GPT_ENTRIES_LBA_OFFSET..GPT_ENTRIES_LBA_OFFSET + 8].try_into().unwrap());
+    let num = u32::from_le_bytes(hdr[GPT_ENTRIES_NUM_OFFSET..GPT_ENTRIES_NUM_OFFSET + 4].try_into().unwrap()) as usize;
+    let size = u32::from_le_bytes(hdr[GPT_ENTRY_SIZE_OFFSET..GPT_ENTRY_SIZE_OFFSET + 4].try_into().unwrap()) as usize;
+    if size < GPT_ENTRY_NAME_OFFSET + GPT_ENTRY_NAME_SIZE || num > GPT_MAX_ENTRIES {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Unsupported GPT entries: {} of {} bytes", num, size)));
+    }
+
+    let mut entries = vec![0u8; num * size];
+    f.seek(SeekFrom::Start(lba * sector_size))?;
+    f.read_exact(&mut entries)?;
+
+    let mut parts: Vec<PartEntry> = Vec::default();
+    for (i, e) in entries.chunks_exact(size).enumerate() {
+        // Unused entries have no type
+        if e[..GPT_ENTRY_UUID_OFFSET].iter().all(|b| *b == 0) {
+            continue;
+        }
+
+        let name = e[GPT_ENTRY_NAME_OFFSET..GPT_ENTRY_NAME_OFFSET + GPT_ENTRY_NAME_SIZE]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect::<Vec<u16>>();
+
+        parts.push(PartEntry {
+            number: i as u32 + 1,
+            uuid: fmt_guid(&e[GPT_ENTRY_UUID_OFFSET..GPT_ENTRY_UUID_OFFSET + 16]),
+            name: String::from_utf16_lossy(&name),
+        });
+    }
+
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::PathBuf};
+
+    /// Unpack a fixture disk image
+    fn fixture(name: &str) -> PathBuf {
+        let img = std::env::temp_dir().join(format!("microhop-parttable-{}-{}.img", std::process::id(), name));
+        let data =
+            crate::compress::decompress(&Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("testdata/probe/{}.img.zst", name)))
+                .unwrap();
+        fs::write(&img, data).unwrap();
+
+        img
+    }
+
+    fn read_fixture(name: &str) -> Result<PartTable, Error> {
+        let img = fixture(name);
+        let pt = read(&img, 512);
+        fs::remove_file(&img).unwrap();
+
+        pt
+    }
+
+    #[test]
+    fn gpt() {
+        let pt = read_fixture("gpt").unwrap();
+        assert_eq!(pt.get_entries().iter().map(|e| e.get_number()).collect::<Vec<u32>>(), vec![1, 3]);
+
+        let root = pt.get_entry(1).unwrap();
+        assert_eq!(root.get_uuid(), "0123abcd-4567-89ab-cdef-0123456789ab");
+        assert_eq!(root.get_name(), "root");
+        assert!(pt.get_entry(2).is_none());
+        assert!(pt.get_entry(4).is_none());
+    }
+
+    #[test]
+    fn gpt_utf16_name() {
+        let pt = read_fixture("gpt").unwrap();
+        let data = pt.get_entry(3).unwrap();
+        assert_eq!(data.get_uuid(), "fedcba98-7654-3210-0123-456789abcdef");
+        assert_eq!(data.get_name(), "Données \u{1F680}");
+    }
+
+    #[test]
+    fn gpt_without_header() {
+        // Protective MBR only
+        let img = fixture("gpt");
+        let mut data = fs::read(&img).unwrap();
+        data[512..1024].fill(0);
+        fs::write(&img, data).unwrap();
+        let pt = read(&img, 512);
+        fs::remove_file(&img).unwrap();
+
+        assert_eq!(pt.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn mbr() {
+        let pt = read_fixture("dos").unwrap();
+        assert!(pt.get_entries().is_empty());
+
+        // Disk signature is the PTUUID, as blkid reports it
+        let blkid = fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/probe/dos.blkid")).unwrap();
+        let ptuuid = blkid.lines().find_map(|l| l.strip_prefix("PTUUID=")).unwrap();
+        assert_eq!(pt.get_entry(1).unwrap().get_uuid(), format!("{}-01", ptuuid));
+        assert_eq!(pt.get_entry(1).unwrap().get_uuid(), "1234abcd-01");
+        assert_eq!(pt.get_entry(1).unwrap().get_name(), "");
+    }
+
+    #[test]
+    fn mbr_extended() {
+        let pt = read_fixture("dos").unwrap();
+        assert_eq!(pt.get_entry(2).unwrap().get_uuid(), "1234abcd-02");
+        assert_eq!(pt.get_entry(5).unwrap().get_uuid(), "1234abcd-05");
+        assert_eq!(pt.get_entry(6).unwrap().get_uuid(), "1234abcd-06");
+        assert_eq!(pt.get_entry(6).unwrap().get_number(), 6);
+    }
+
+    #[test]
+    fn no_table() {
+        let img = std::env::temp_dir().join(format!("microhop-parttable-{}-zero.img", std::process::id()));
+        fs::write(&img, vec![0u8; 4096]).unwrap();
+        let pt = read(&img, 512).unwrap();
+        fs::remove_file(&img).unwrap();
+
+        assert!(pt.get_entry(1).is_none());
+        assert!(pt.get_entries().is_empty());
+    }
+}
@@ -0,0 +1,355 @@
+//! Superblock probing.
+//!
+//! A minimal replacement of libblkid: it reads the superblocks of the filesystems
+//! and of the other known contents (swap, LUKS, LVM2, md) of a device and reports
+//! its type, UUID and label with the same names and in the same notation as blkid.
+
+use crate::md::MdMember;
+use std::{
+    fs::File,
+    io::{Error, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// Size of the device head, which is read: the btrfs superblock is the farthest one
+const PROBE_SIZE: u64 = 0x10000 + 0x1000;
+
+// ext2/3/4
+const EXT_SB_OFFSET: usize = 1024;
+const EXT_MAGIC: u16 = 0xef53;
+const EXT_COMPAT_HAS_JOURNAL: u32 = 0x4;
+const EXT_INCOMPAT_JOURNAL_DEV: u32 = 0x8;
+
+// Features, which are still ext3. Anything beyond is ext4.
+const EXT3_INCOMPAT_SUPP: u32 = 0x2 | 0x4 | 0x10;
+const EXT3_RO_COMPAT_SUPP: u32 = 0x1 | 0x2 | 0x4;
+
+// btrfs
+const BTRFS_SB_OFFSET: usize = 0x10000;
+const BTRFS_MAGIC: &[u8] = b"_BHRfS_M";
+
+// f2fs and erofs
+const F2FS_SB_OFFSET: usize = 1024;
+const F2FS_MAGIC: u32 = 0xf2f52010;
+const EROFS_SB_OFFSET: usize = 1024;
+const EROFS_MAGIC: u32 = 0xe0f5e1e2;
+
+// swap, which signature is at the end of the first page
+const SWAP_PAGE_SIZES: &[usize] = &[0x1000, 0x2000, 0x4000, 0x10000];
+const SWAP_HDR_OFFSET: usize = 1024;
+
+/// Upper limit of the FAT root directory, which is searched for the label
+const VFAT_ROOT_DIR_MAX: u64 = 0x10000;
+const VFAT_EBR_SIG: u8 = 0x29;
+const VFAT_EBR_SIG_OLD: u8 = 0x28;
+
+const XFS_MAGIC: &[u8] = b"XFSB";
+const SQUASHFS_MAGIC: &[u8] = b"hsqs";
+const LUKS_MAGIC: &[u8] = b"LUKS\xba\xbe";
+
+// LVM2 label in one of the first four sectors
+const LVM_LABEL_ID: &[u8] = b"LABELONE";
+const LVM_LABEL_TYPE: &[u8] = b"LVM2 001";
+const LVM_LABEL_SECTORS: usize = 4;
+
+// Types as blkid names them
+const TYPE_SWAP: &str = "swap";
+const TYPE_LUKS: &str = "crypto_LUKS";
+const TYPE_LVM2: &str = "LVM2_member";
+const TYPE_MD: &str = "linux_raid_member";
+const TYPE_VFAT: &str = "vfat";
+
+/// Type, UUID and label of a device content
+#[derive(Debug, Clone, Default)]
+pub struct Superblock {
+    fstype: String,
+    uuid: String,
+    label: String,
+}
+
+impl Superblock {
+    fn new(fstype: &str, uuid: String, label: String) -> Self {
+        Superblock { fstype: fstype.to_string(), uuid, label }
+    }
+
+    /// Get type, such as "ext4" or "crypto_LUKS"
+    pub fn get_fstype(&self) -> &str {
+        &self.fstype
+    }
+
+    /// Get UUID, which is empty if the type has none
+    pub fn get_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    /// Get label, which is empty if the type has none
+    pub fn get_label(&self) -> &str {
+        &self.label
+    }
+}
+
+/// Format a UUID, which is stored as it is written
+fn fmt_uuid(u: &[u8]) -> String {
+    let hex = u.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Get a NUL-terminated string
+fn cstr(b: &[u8]) -> String {
+    String::from_utf8_lossy(&b[..b.iter().position(|c| *c == 0).unwrap_or(b.len())]).to_string()
+}
+
+fn le16(buf: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(buf.get(off..off + 2)?.try_into().ok()?))
+}
+
+fn le32(buf: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(buf.get(off..off + 4)?.try_into().ok()?))
+}
+
+fn ext(buf: &[u8]) -> Option<Superblock> {
+    let sb = buf.get(EXT_SB_OFFSET..EXT_SB_OFFSET + 1024)?;
+    if le16(sb, 0x38)? != EXT_MAGIC {
+        return None;
+    }
+
+    let (compat, incompat, ro_compat) = (le32(sb, 0x5c)?, le32(sb, 0x60)?, le32(sb, 0x64)?);
+    let fstype = if incompat & EXT_INCOMPAT_JOURNAL_DEV != 0 {
+        "jbd"
+    } else if incompat & !EXT3_INCOMPAT_SUPP != 0 || ro_compat & !EXT3_RO_COMPAT_SUPP != 0 {
+        "ext4"
+    } else if compat & EXT_COMPAT_HAS_JOURNAL != 0 {
+        "ext3"
+    } else {
+        "ext2"
+    };
+
+    Some(Superblock::new(fstype, fmt_uuid(&sb[0x68..0x78]), cstr(&sb[0x78..0x88])))
+}
+
+fn xfs(buf: &[u8]) -> Option<Superblock> {
+    if !buf.starts_with(XFS_MAGIC) {
+        return None;
+    }
+
+    Some(Superblock::new("xfs", fmt_uuid(buf.get(32..48)?), cstr(buf.get(108..120)?)))
+}
+
+fn btrfs(buf: &[u8]) -> Option<Superblock> {
+    let sb = buf.get(BTRFS_SB_OFFSET..BTRFS_SB_OFFSET + 0x22b)?;
+    if &sb[0x40..0x48] != BTRFS_MAGIC {
+        return None;
+    }
+
+    Some(Superblock::new("btrfs", fmt_uuid(&sb[0x20..0x30]), cstr(&sb[0x12b..])))
+}
+
+fn f2fs(buf: &[u8]) -> Option<Superblock> {
+    let sb = buf.get(F2FS_SB_OFFSET..F2FS_SB_OFFSET + 124 + 1024)?;
+    if le32(sb, 0)? != F2FS_MAGIC {
+        return None;
+    }
+
+    // Volume name is in UTF-16
+    let name = sb[124..].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).take_while(|c| *c != 0).collect::<Vec<u16>>();
+    Some(Superblock::new("f2fs", fmt_uuid(&sb[108..124]), String::from_utf16_lossy(&name)))
+}
+
+fn erofs(buf: &[u8]) -> Option<Superblock> {
+    let sb = buf.get(EROFS_SB_OFFSET..EROFS_SB_OFFSET + 80)?;
+    if le32(sb, 0)? != EROFS_MAGIC {
+        return None;
+    }
+
+    Some(Superblock::new("erofs", fmt_uuid(&sb[48..64]), cstr(&sb[64..80])))
+}
+
+fn squashfs(buf: &[u8]) -> Option<Superblock> {
+    // Version 4, earlier ones are "squashfs3" and not mountable anymore
+    if !buf.starts_with(SQUASHFS_MAGIC) || le16(buf, 28)? != 4 {
+        return None;
+    }
+
+    Some(Superblock::new("squashfs", String::default(), String::default()))
+}
+
+fn vfat(buf: &[u8]) -> Option<Superblock> {
+    // Boot sector with a jump instruction and a sane sector size
+    if buf.get(510..512)? != [0x55, 0xaa] || ![0xeb, 0xe9].contains(&buf[0]) || ![512, 1024, 2048, 4096].contains(&le16(buf, 11)?)
+    {
+        return None;
+    }
+
+    // FAT32 has the extended boot record further. The volume ID is there only with its signature.
+    let ebr = if buf[82..90].starts_with(b"FAT32") {
+        64
+    } else if buf[54..62].starts_with(b"FAT") {
+        36
+    } else {
+        return None;
+    };
+    let uuid = match buf[ebr + 2] {
+        VFAT_EBR_SIG | VFAT_EBR_SIG_OLD => le32(buf, ebr + 3).map(|id| format!("{:04X}-{:04X}", id >> 16, id & 0xffff))?,
+        _ => String::default(),
+    };
+
+    // Label is in the root directory, the one in the boot sector is often stale
+    Some(Superblock::new(TYPE_VFAT, uuid, String::default()))
+}
+
+/// Read FAT volume label from the root directory. On FAT32, only its first cluster is read.
+fn vfat_label(f: &mut File, buf: &[u8]) -> Option<String> {
+    let (bps, spc, reserved, fats) = (le16(buf, 11)? as u64, buf[13] as u64, le16(buf, 14)? as u64, buf[16] as u64);
+    let (offset, size) = match le16(buf, 22)? as u64 {
+        0 => {
+            let cluster = (le32(buf, 44)? as u64).checked_sub(2)?;
+            ((reserved + fats * le32(buf, 36)? as u64 + cluster * spc) * bps, spc * bps)
+        }
+        fatsz => ((reserved + fats * fatsz) * bps, le16(buf, 17)? as u64 * 32),
+    };
+
+    let mut dir = vec![0u8; size.min(VFAT_ROOT_DIR_MAX) as usize];
+    f.seek(SeekFrom::Start(offset)).ok()?;
+    f.read_exact(&mut dir).ok()?;
+
+    // Volume ID entry, which is neither deleted nor a part of a long name
+    let entry =
+        dir.chunks_exact(32).take_while(|e| e[0] != 0).find(|e| e[0] != 0xe5 && e[11] & 0x3f != 0x0f && e[11] & 0x18 == 0x08)?;
+    let label = String::from_utf8_lossy(&entry[..11]).trim_end().to_string();
+
+    (label != "NO NAME").then_some(label)
+}
+
+fn swap(buf: &[u8]) -> Option<Superblock> {
+    let sig = SWAP_PAGE_SIZES.iter().find_map(|p| buf.get(p - 10..*p).filter(|s| *s == b"SWAPSPACE2" || *s == b"SWAP-SPACE"))?;
+
+    // Old version has no header
+    if sig == b"SWAP-SPACE" {
+        return Some(Superblock::new(TYPE_SWAP, String::default(), String::default()));
+    }
+
+    // Header: version, last page, number of bad pages, UUID, label
+    let hdr = buf.get(SWAP_HDR_OFFSET..SWAP_HDR_OFFSET + 44)?;
+    Some(Superblock::new(TYPE_SWAP, fmt_uuid(&hdr[12..28]), cstr(&hdr[28..44])))
+}
+
+fn luks(buf: &[u8]) -> Option<Superblock> {
+    if !buf.starts_with(LUKS_MAGIC) {
+        return None;
+    }
+
+    // Only LUKS2 has a label
+    let label = if u16::from_be_bytes([buf[6], buf[7]]) == 2 { cstr(buf.get(24..72)?) } else { String::default() };
+    Some(Superblock::new(TYPE_LUKS, cstr(buf.get(168..208)?), label))
+}
+
+fn lvm2(buf: &[u8]) -> Option<Superblock> {
+    let (n, label) = buf
+        .chunks_exact(512)
+        .take(LVM_LABEL_SECTORS)
+        .enumerate()
+        .find(|(_, s)| s.starts_with(LVM_LABEL_ID) && &s[24..32] == LVM_LABEL_TYPE)?;
+
+    // PV UUID is written with dashes as 6-4-4-4-4-4-6
+    let hdr = n * 512 + le32(label, 20)? as usize;
+    let id = cstr(buf.get(hdr..hdr + 32)?);
+    let mut uuid = String::default();
+    for (i, c) in id.chars().enumerate() {
+        if [6, 10, 14, 18, 22, 26].contains(&i) {
+            uuid.push('-');
+        }
+        uuid.push(c);
+    }
+
+    Some(Superblock::new(TYPE_LVM2, uuid, String::default()))
+}
+
+/// Reads a superblock from the device head
+type Prober = fn(&[u8]) -> Option<Superblock>;
+
+/// Probers in the order of trying. FAT is the last, as its boot sector is the least specific.
+const PROBES: &[Prober] = &[luks, lvm2, swap, xfs, btrfs, ext, f2fs, erofs, squashfs, vfat];
+
+/// Probe a device or an image. Returns None if its content is unknown.
+pub fn probe(p: &Path) -> Result<Option<Superblock>, Error> {
+    // RAID members go first: mirrors with the superblock at the end also look like the filesystem they hold
+    if let Ok(md) = MdMember::open(p) {
+        let uuid = md.get_uuid();
+        let uuid = format!("{}-{}-{}-{}-{}", &uuid[..8], &uuid[8..12], &uuid[12..16], &uuid[16..20], &uuid[20..]);
+        return Ok(Some(Superblock::new(TYPE_MD, uuid, md.get_name().to_string())));
+    }
+
+    let mut f = File::open(p)?;
+    let mut buf: Vec<u8> = Vec::default();
+    f.by_ref().take(PROBE_SIZE).read_to_end(&mut buf)?;
+
+    Ok(PROBES.iter().find_map(|probe| probe(&buf)).map(|mut sb| {
+        if sb.fstype == TYPE_VFAT {
+            sb.label = vfat_label(&mut f, &buf).unwrap_or_default();
+        }
+        sb
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::PathBuf};
+
+    /// Unpack a fixture image and read its TYPE, UUID and LABEL, as blkid reported them
+    fn fixture(name: &str) -> (PathBuf, (String, String, String)) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/probe");
+        let img = std::env::temp_dir().join(format!("microhop-probe-{}-{}.img", std::process::id(), name));
+        fs::write(&img, crate::compress::decompress(&dir.join(format!("{}.img.zst", name))).unwrap()).unwrap();
+
+        let blkid = fs::read_to_string(dir.join(format!("{}.blkid", name))).unwrap();
+        let tag = |t: &str| blkid.lines().find_map(|l| l.strip_prefix(t)).unwrap_or_default().replace("\\ ", " ");
+
+        (img, (tag("TYPE="), tag("UUID="), tag("LABEL=")))
+    }
+
+    fn assert_blkid(name: &str) {
+        let (img, expected) = fixture(name);
+        let sb = probe(&img).unwrap().unwrap_or_else(|| panic!("{} is not recognised", name));
+        fs::remove_file(&img).unwrap();
+
+        assert!(!expected.0.is_empty());
+        assert_eq!((sb.get_fstype().to_string(), sb.get_uuid().to_string(), sb.get_label().to_string()), expected, "{}", name);
+    }
+
+    #[test]
+    fn probe_filesystems() {
+        for name in ["ext2", "ext3", "ext4", "xfs", "btrfs", "f2fs", "squashfs", "erofs"] {
+            assert_blkid(name);
+        }
+    }
+
+    #[test]
+    fn probe_vfat() {
+        for name in ["fat16", "fat16-noserial", "fat32"] {
+            assert_blkid(name);
+        }
+    }
+
+    #[test]
+    fn probe_other() {
+        for name in ["swap", "luks2", "lvm2"] {
+            assert_blkid(name);
+        }
+    }
+
+    #[test]
+    fn probe_md() {
+        for name in ["md12", "md090"] {
+            assert_blkid(name);
+        }
+    }
+
+    #[test]
+    fn probe_unknown() {
+        let img = std::env::temp_dir().join(format!("microhop-probe-{}-zero.img", std::process::id()));
+        fs::write(&img, vec![0u8; 0x20000]).unwrap();
+        assert!(probe(&img).unwrap().is_none());
+        fs::remove_file(&img).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+//! dm-verity verified read-only devices.
+//!
+//! A minimal replacement of "veritysetup open": it reads the verity superblock
+//! from the hash device, checks the top of the hash tree against the root hash
+//! and maps the verified device via dm-verity. The kernel verifies each data
+//! block on reading it and fails the I/O of the modified ones.
+
+use crate::{blk, dm};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    fs::File,
+    io::{Error, ErrorKind, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+static VERITY_SIGNATURE: &[u8] = b"verity\0\0";
+
+/// Size of struct verity_sb
+const VERITY_SB_SIZE: u64 = 512;
+
+/// Defaults of veritysetup, used for the devices without a superblock
+const VERITY_BLOCK_SIZE: u32 = 4096;
+static VERITY_ALGORITHM: &str = "sha256";
+
+/// Verity device: the data device with its hash tree
+pub struct Verity {
+    data: PathBuf,
+    hash: PathBuf,
+    hash_offset: u64,
+    superblock: bool,
+    uuid: String,
+    hash_type: u32,
+    algorithm: String,
+    data_block_size: u32,
+    hash_block_size: u32,
+    data_blocks: u64,
+    salt: Vec<u8>,
+}
+
+impl Verity {
+    /// Read the verity superblock at the offset (in bytes) of the hash device.
+    /// The hash tree can be also appended to the data device, then both are the same.
+    ///
+    /// If the salt is given, the hash tree has no superblock ("veritysetup format --no-superblock"),
+    /// and the veritysetup defaults are used: SHA256, 4K blocks, the data device up to the hash tree.
+    /// Salt is a hex string or "-" for none.
+    pub fn open(data: &Path, hash: &Path, hash_offset: u64, salt: Option<&str>) -> Result<Self, Error> {
+        if let Some(salt) = salt {
+            let data_size = if data == hash && hash_offset > 0 { hash_offset } else { blk::get_size(data)? };
+            return Ok(Verity {
+                data: data.to_path_buf(),
+                hash: hash.to_path_buf(),
+                hash_offset,
+                superblock: false,
+                uuid: String::default(),
+                hash_type: 1,
+                algorithm: VERITY_ALGORITHM.to_string(),
+                data_block_size: VERITY_BLOCK_SIZE,
+                hash_block_size: VERITY_BLOCK_SIZE,
+                data_blocks: data_size / VERITY_BLOCK_SIZE as u64,
+                salt: unhex(if salt == "-" { "" } else { salt })?,
+            });
+        }
+
+        let mut sb = vec![0u8; VERITY_SB_SIZE as usize];
+        let mut f = File::open(hash)?;
+        f.seek(SeekFrom::Start(hash_offset))?;
+        f.read_exact(&mut sb)?;
+
+        if &sb[0..8] != VERITY_SIGNATURE {
+            return Err(Error::new(ErrorKind::InvalidData, format!("No verity superblock on {}", hash.display())));
+        }
+
+        let u32_at = |o: usize| u32::from_le_bytes(sb[o..o + 4].try_into().unwrap());
+        if u32_at(8) != 1 {
+            return Err(Error::new(ErrorKind::Unsupported, format!("Verity superblock version {} is not supported", u32_at(8))));
+        }
+
+        let uuid = sb[16..32].iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        let salt_size = u16::from_le_bytes(sb[80..82].try_into().unwrap()) as usize;
+        let vt = Verity {
+            data: data.to_path_buf(),
+            hash: hash.to_path_buf(),
+            hash_offset,
+            superblock: true,
+            uuid,
+            hash_type: u32_at(12),
+            algorithm: String::from_utf8_lossy(&sb[32..64]).trim_end_matches('\0').to_string(),
+            data_block_size: u32_at(64),
+            hash_block_size: u32_at(68),
+            data_blocks: u64::from_le_bytes(sb[72..80].try_into().unwrap()),
+            salt: sb[88..88 + salt_size.min(256)].to_vec(),
+        };
+
+        if !vt.data_block_size.is_power_of_two() || !vt.hash_block_size.is_power_of_two() || vt.hash_block_size < 512 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Invalid verity block sizes on {}", hash.display())));
+        }
+
+        Ok(vt)
+    }
+
+    /// Get UUID of the hash tree, if it has a superblock
+    pub fn get_uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    /// Get the hash tree start on the hash device in hash blocks, after the superblock if any
+    fn get_hash_start(&self) -> u64 {
+        let hbs = self.hash_block_size as u64;
+        if self.superblock {
+            (self.hash_offset + VERITY_SB_SIZE).div_ceil(hbs)
+        } else {
+            self.hash_offset / hbs
+        }
+    }
+
+    /// Compute the digest of a block with the salt
+    fn digest(&self, block: &[u8]) -> Result<Vec<u8>, Error> {
+        // Version 0 (Chrome OS) appends the salt, version 1 prepends it
+        let data = match self.hash_type {
+            0 => [block, self.salt.as_slice()].concat(),
+            _ => [self.salt.as_slice(), block].concat(),
+        };
+
+        Ok(match self.algorithm.as_str() {
+            "sha1" => Sha1::digest(&data).to_vec(),
+            "sha256" => Sha256::digest(&data).to_vec(),
+            "sha512" => Sha512::digest(&data).to_vec(),
+            alg => return Err(Error::new(ErrorKind::Unsupported, format!("Verity hash {} is not supported", alg))),
+        })
+    }
+
+    /// Check the top block of the hash tree against the root hash.
+    /// Returns PermissionDenied error, if they do not match.
+    pub fn verify(&self, roothash: &str) -> Result<(), Error> {
+        let expected = unhex(roothash)?;
+
+        // Hashes per block are rounded down to the power of two
+        let digest_size = self.digest(&[])?.len() as u64;
+        let per_block_bits = (self.hash_block_size as u64 / digest_size).ilog2();
+        let mut levels = 0;
+        while per_block_bits * levels < 64 && (self.data_blocks.saturating_sub(1) >> (per_block_bits * levels)) != 0 {
+            levels += 1;
+        }
+
+        // The top level is always one block, stored first. A single data block is hashed directly.
+        let (p, offset, size) = if levels == 0 {
+            (&self.data, 0, self.data_block_size)
+        } else {
+            (&self.hash, self.get_hash_start() * self.hash_block_size as u64, self.hash_block_size)
+        };
+
+        let mut block = vec![0u8; size as usize];
+        let mut f = File::open(p)?;
+        f.seek(SeekFrom::Start(offset))?;
+        f.read_exact(&mut block)?;
+
+        if self.digest(&block)? != expected {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("Root hash of {} does not match the hash tree on {}", self.data.display(), self.hash.display()),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Verify the root hash and map the verified device read-only.
+    /// Returns path to the device node in /dev/mapper.
+    pub fn activate(&self, name: &str, roothash: &str) -> Result<PathBuf, Error> {
+        self.verify(roothash)?;
+
+        let length = self.data_blocks * self.data_block_size as u64;
+        if blk::get_size(&self.data)? < length {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Data device {} is too small", self.data.display())));
+        }
+
+        let params = format!(
+            "{} {} {} {} {} {} {} {} {} {}",
+            self.hash_type,
+            self.data.display(),
+            self.hash.display(),
+            self.data_block_size,
+            self.hash_block_size,
+            self.data_blocks,
+            self.get_hash_start(),
+            self.algorithm,
+            roothash.to_lowercase(),
+            if self.salt.is_empty() { "-".to_string() } else { self.salt.iter().map(|b| format!("{:02x}", b)).collect() }
+        );
+
+        let uuid = format!("CRYPT-VERITY-{}-{}", self.uuid, name);
+        dm::create(name, &uuid, &[dm::DmTarget::new(0, length / 512, "verity", &params)], true)
+    }
+}
+
+/// Decode a hex string
+fn unhex(v: &str) -> Result<Vec<u8>, Error> {
+    if !v.len().is_multiple_of(2) || !v.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Not a hex string: {}", v)));
+    }
+
+    Ok((0..v.len()).step_by(2).map(|i| u8::from_str_radix(&v[i..i + 2], 16).unwrap()).collect())
+}
@@ -0,0 +1,106 @@
+#!/usr/bin/env python3
+"""
+Generate luks2.img: a LUKS2 device as "cryptsetup luksFormat --type luks2 --pbkdf pbkdf2
+--key-size 256" would lay it out, with a fixed passphrase, volume key and salts,
+followed by one encrypted data sector. It is written after the LUKS2 on-disk format
+specification, independently of syslib, and needs the "cryptography" package.
+"""
+
+import base64
+import hashlib
+import json
+import random
+import struct
+import sys
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+
+PASSPHRASE = b"microhop"
+UUID = "5a3b0c2e-9d41-4f7e-8a6b-1c2d3e4f5a6b"
+LABEL = b"mhtest"
+KEY_SIZE = 32
+STRIPES = 4000
+ITERATIONS = 1000
+SECTOR = 512
+HDR_SIZE = 16384
+KEYSLOT_OFFSET = 2 * HDR_SIZE
+KEYSLOT_SIZE = 131072
+SEGMENT_OFFSET = KEYSLOT_OFFSET + KEYSLOT_SIZE
+PLAINTEXT = b"Microhop LUKS2 test sector\n".ljust(SECTOR, b"\0")
+
+rnd = random.Random(1619)
+volume_key = bytes(rnd.getrandbits(8) for _ in range(KEY_SIZE))
+ks_salt = bytes(rnd.getrandbits(8) for _ in range(32))
+dg_salt = bytes(rnd.getrandbits(8) for _ in range(32))
+
+
+def diffuse(buf):
+    ds = hashlib.sha256().digest_size
+    out = b""
+    for i in range(0, len(buf), ds):
+        out += hashlib.sha256(struct.pack(">I", i // ds) + buf[i:i + ds]).digest()[:len(buf[i:i + ds])]
+    return out
+
+
+def af_split(key):
+    block, stripes = bytes(len(key)), []
+    for _ in range(STRIPES - 1):
+        s = bytes(rnd.getrandbits(8) for _ in range(len(key)))
+        stripes.append(s)
+        block = diffuse(bytes(a ^ b for a, b in zip(block, s)))
+    stripes.append(bytes(a ^ b for a, b in zip(block, key)))
+    return b"".join(stripes)
+
+
+def xts_encrypt(key, data, sector0=0):
+    out = b""
+    for n in range(0, len(data), SECTOR):
+        enc = Cipher(algorithms.AES(key), modes.XTS(struct.pack("<QQ", sector0 + n // SECTOR, 0))).encryptor()
+        out += enc.update(data[n:n + SECTOR]) + enc.finalize()
+    return out
+
+
+b64 = lambda b: base64.b64encode(b).decode()
+kek = hashlib.pbkdf2_hmac("sha256", PASSPHRASE, ks_salt, ITERATIONS, KEY_SIZE)
+material = af_split(volume_key)
+material += bytes(-len(material) % SECTOR)
+digest = hashlib.pbkdf2_hmac("sha256", volume_key, dg_salt, ITERATIONS, 32)
+
+meta = {
+    "keyslots": {"0": {
+        "type": "luks2", "key_size": KEY_SIZE,
+        "af": {"type": "luks1", "stripes": STRIPES, "hash": "sha256"},
+        "area": {"type": "raw", "offset": str(KEYSLOT_OFFSET), "size": str(KEYSLOT_SIZE),
+                 "encryption": "aes-xts-plain64", "key_size": KEY_SIZE},
+        "kdf": {"type": "pbkdf2", "hash": "sha256", "iterations": ITERATIONS, "salt": b64(ks_salt)},
+    }},
+    "tokens": {},
+    "segments": {"0": {
+        "type": "crypt", "offset": str(SEGMENT_OFFSET), "size": "dynamic", "iv_tweak": "0",
+        "encryption": "aes-xts-plain64", "sector_size": SECTOR,
+    }},
+    "digests": {"0": {
+        "type": "pbkdf2", "keyslots": ["0"], "segments": ["0"], "hash": "sha256",
+        "iterations": ITERATIONS, "salt": b64(dg_salt), "digest": b64(digest),
+    }},
+    "config": {"json_size": str(HDR_SIZE - 4096), "keyslots_size": str(KEYSLOT_SIZE)},
+}
+
+
+def header(offset, seqid=1):
+    hdr = bytearray(4096)
+    hdr[0:6] = b"LUKS\xba\xbe" if offset == 0 else b"SKUL\xba\xbe"
+    struct.pack_into(">HQQ", hdr, 6, 2, HDR_SIZE, seqid)
+    hdr[24:24 + len(LABEL)] = LABEL
+    hdr[72:78] = b"sha256"
+    hdr[168:168 + len(UUID)] = UUID.encode()
+    struct.pack_into(">Q", hdr, 256, offset)
+    area = hdr + json.dumps(meta).encode().ljust(HDR_SIZE - 4096, b"\0")
+    area[448:480] = hashlib.sha256(area).digest()
+    return bytes(area)
+
+
+img = header(0) + header(HDR_SIZE)
+img += xts_encrypt(kek, material).ljust(KEYSLOT_SIZE, b"\0")
+img += xts_encrypt(volume_key, PLAINTEXT)
+open(sys.argv[1] if len(sys.argv) > 1 else "luks2.img", "wb").write(img)
+print("volume key:", volume_key.hex())
@@ -0,0 +1,383 @@
+#!/usr/bin/env python3
+"""
+Generate the probe fixtures: small images with a known superblock each, compressed
+with zstd, and the "blkid -p -o export" output of every image, the tests compare to.
+
+ext2/3/4 and swap are made by mke2fs and mkswap. The others are synthetic: only
+the superblock fields, which blkid checks, are written after the on-disk formats,
+as their tools are too heavy for a fixture. Needs util-linux, e2fsprogs and zstd.
+RAID and LVM2 images are larger than a floppy, as blkid does not look for them on smaller devices.
+GPT and MBR images are whole disks with partition tables only, without any filesystems.
+"""
+
+import os
+import struct
+import subprocess
+import uuid
+import zlib
+
+U = uuid.UUID("0123abcd-4567-89ab-cdef-0123456789ab")
+SUB = uuid.UUID("fedcba98-7654-3210-0123-456789abcdef")
+MiB = 1 << 20
+
+
+def crc32c(data):
+    crc = 0xFFFFFFFF
+    for b in data:
+        crc ^= b
+        for _ in range(8):
+            crc = (crc >> 1) ^ (0x82F63B78 if crc & 1 else 0)
+    return crc ^ 0xFFFFFFFF
+
+
+def md_csum(data):
+    s = sum(struct.unpack("<%dI" % (len(data) // 4), data))
+    return ((s & 0xFFFFFFFF) + (s >> 32)) & 0xFFFFFFFF
+
+
+def img(name, size, writes):
+    b = bytearray(size)
+    for off, data in writes:
+        b[off : off + len(data)] = data
+    with open(name, "wb") as f:
+        f.write(b)
+
+
+def fat16(name, sig):
+    bs = bytearray(512)
+    bs[0:3] = b"\xeb\x3c\x90"
+    bs[3:11] = b"MSWIN4.1"
+    struct.pack_into("<HBHBHHBHHHII", bs, 11, 512, 4, 4, 2, 512, 16384, 0xF8, 16, 32, 2, 0, 0)
+    bs[36] = 0x80
+    bs[38] = sig
+    struct.pack_into("<I", bs, 39, 0x1234ABCD)
+    bs[43:54] = b"BOOTFAT    "
+    bs[54:62] = b"FAT16   "
+    bs[510:512] = b"\x55\xaa"
+    fat = b"\xf8\xff\xff\xff"
+    img(name, 8 * MiB, [(0, bs), (4 * 512, fat), (20 * 512, fat), (36 * 512, b"ROOTFAT    \x08")])
+
+
+def fat32():
+    bs = bytearray(512)
+    bs[0:3] = b"\xeb\x58\x90"
+    bs[3:11] = b"mkfs.fat"
+    struct.pack_into("<HBHBHHBHHHII", bs, 11, 512, 1, 32, 2, 0, 0, 0xF8, 0, 32, 2, 0, 80000)
+    struct.pack_into("<IHHIHH", bs, 36, 620, 0, 0, 2, 1, 6)
+    bs[64] = 0x80
+    bs[66] = 0x29
+    struct.pack_into("<I", bs, 67, 0xDEADBEEF)
+    bs[71:82] = b"NO NAME    "
+    bs[82:90] = b"FAT32   "
+    bs[510:512] = b"\x55\xaa"
+    fat = b"\xf8\xff\xff\x0f\xff\xff\xff\x0f\xff\xff\xff\x0f"
+    img("fat32.img", 40 * MiB, [(0, bs), (32 * 512, fat), (1272 * 512, b"EFI        \x08")])
+
+
+def xfs():
+    sb = bytearray(512)
+    sb[0:4] = b"XFSB"
+    struct.pack_into(">IQQQ", sb, 4, 4096, 2048, 0, 0)
+    sb[32:48] = U.bytes
+    struct.pack_into(">QQQQ", sb, 48, 1040, 128, 129, 130)
+    struct.pack_into(">IIIII", sb, 80, 1, 1024, 2, 0, 512)
+    struct.pack_into(">HHHH", sb, 100, 0x3084, 512, 256, 16)
+    sb[108:120] = b"xfslabel\0\0\0\0"
+    sb[120:126] = bytes([12, 9, 8, 4, 10, 0])
+    img("xfs.img", 8 * MiB, [(0, sb)])
+
+
+def btrfs():
+    sb = bytearray(4096)
+    sb[0x20:0x30] = U.bytes
+    struct.pack_into("<Q", sb, 0x30, 65536)
+    sb[0x40:0x48] = b"_BHRfS_M"
+    struct.pack_into("<III", sb, 0x90, 4096, 16384, 16384)
+    sb[0x12B : 0x12B + 8] = b"btrfsvol"
+    struct.pack_into("<I", sb, 0, crc32c(sb[0x20:]))
+    img("btrfs.img", 8 * MiB, [(65536, sb)])
+
+
+def f2fs():
+    sb = bytearray(3072)
+    struct.pack_into("<IHH", sb, 0, 0xF2F52010, 1, 15)
+    sb[108:124] = U.bytes
+    name = "f2fsvol".encode("utf-16-le")
+    sb[124 : 124 + len(name)] = name
+    img("f2fs.img", 8 * MiB, [(1024, sb)])
+
+
+def erofs():
+    sb = bytearray(128)
+    struct.pack_into("<I", sb, 0, 0xE0F5E1E2)
+    sb[12] = 12
+    sb[48:64] = U.bytes
+    sb[64:70] = b"erolab"
+    img("erofs.img", MiB, [(1024, sb)])
+
+
+def squashfs():
+    sb = bytearray(96)
+    sb[0:4] = b"hsqs"
+    struct.pack_into("<IIIIHHHHH", sb, 4, 1, 0, 131072, 1, 1, 17, 0, 0, 4)
+    img("squashfs.img", MiB, [(0, sb)])
+
+
+def luks2():
+    hdr = bytearray(4096)
+    hdr[0:6] = b"LUKS\xba\xbe"
+    struct.pack_into(">HQQ", hdr, 6, 2, 16384, 1)
+    hdr[24:32] = b"cryptlbl"
+    hdr[72:78] = b"sha256"
+    hdr[168:204] = str(U).encode()
+    img("luks2.img", MiB, [(0, hdr)])
+
+
+LVM2_META = """\
+vg0 {
+id = "Vg0Vg0-Vg0V-g0Vg-0Vg0-Vg0V-g0Vg-0Vg0Vg"
+seqno = 3
+format = "lvm2"			# informational
+status = ["RESIZEABLE", "READ", "WRITE"]
+flags = []
+extent_size = 8192		# 4 Megabytes
+max_lv = 0
+max_pv = 0
+metadata_copies = 0
+
+physical_volumes {
+
+pv0 {
+id = "abcdef-GHIJ-klmn-OPQR-stuv-WXYZ-012345"
+device = "/dev/vda2"	# Hint only
+
+status = ["ALLOCATABLE"]
+flags = []
+dev_size = 4096		# 2 Megabytes
+pe_start = 2048
+pe_count = 0
+}
+
+pv1 {
+id = "zyxwvu-TSRQ-ponm-LKJI-hgfe-DCBA-987654"
+device = "/dev/vdb2"	# Hint only
+
+status = ["ALLOCATABLE"]
+flags = []
+dev_size = 4096
+pe_start = 2048
+pe_count = 0
+}
+}
+
+logical_volumes {
+
+root {
+id = "RootRo-otRo-otRo-otRo-otRo-otRo-otRoot"
+status = ["READ", "WRITE", "VISIBLE"]
+flags = []
+creation_time = 1700000000	# 2023-11-14 22:13:20 +0000
+creation_host = "host"
+segment_count = 2
+
+segment1 {
+start_extent = 0
+extent_count = 10	# 40 Megabytes
+
+type = "striped"
+stripe_count = 1	# linear
+
+stripes = [
+"pv0", 0
+]
+}
+segment2 {
+start_extent = 10
+extent_count = 5	# 20 Megabytes
+
+type = "striped"
+stripe_count = 1	# linear
+
+stripes = [
+"pv0", 20
+]
+}
+}
+
+data {
+id = "DataDa-taDa-taDa-taDa-taDa-taDa-taData"
+status = ["READ", "VISIBLE"]
+flags = []
+segment_count = 1
+
+segment1 {
+start_extent = 0
+extent_count = 8	# 32 Megabytes
+
+type = "striped"
+stripe_count = 2
+stripe_size = 128	# 64 Kilobytes
+
+stripes = [
+"pv0", 30,
+"pv1", 0
+]
+}
+}
+
+pool {
+id = "PoolPo-olPo-olPo-olPo-olPo-olPo-olPool"
+status = ["READ", "WRITE", "VISIBLE"]
+flags = []
+segment_count = 1
+
+segment1 {
+start_extent = 0
+extent_count = 1
+
+type = "thin-pool"
+}
+}
+}
+}
+# Generated by LVM2 version 2.03.16(2) (2022-05-18): Tue Nov 14 22:13:20 2023
+
+contents = "Text Format Volume Group"
+version = 1
+
+description = "Written by \\"mkimages.py\\""
+
+creation_host = "host"	# Linux host 6.1.0 #1 SMP x86_64
+creation_time = 1700000000	# Tue Nov 14 22:13:20 2023
+"""
+
+
+def lvm_crc(data):
+    return ~zlib.crc32(bytes(data), ~0xF597A6CF & 0xFFFFFFFF) & 0xFFFFFFFF
+
+
+def lvm2():
+    # Physical volume of "vg0" with one metadata area at 4K: the label, its header and the metadata text
+    mda, text = 4096, LVM2_META.encode() + b"\0"
+    lab = bytearray(512)
+    lab[0:8] = b"LABELONE"
+    struct.pack_into("<QII", lab, 8, 1, 0, 32)
+    lab[24:32] = b"LVM2 001"
+    lab[32:64] = b"abcdefGHIJklmnOPQRstuvWXYZ012345"
+    struct.pack_into("<Q4Q4Q", lab, 64, 2 * MiB, MiB, 0, 0, 0, mda, MiB - mda, 0, 0)
+    struct.pack_into("<I", lab, 16, lvm_crc(lab[20:]))
+
+    hdr = bytearray(512)
+    hdr[4:20] = b" LVM2 x[5A%r0N*>"
+    struct.pack_into("<IQQQQII", hdr, 20, 1, mda, MiB - mda, 512, len(text), lvm_crc(text), 0)
+    struct.pack_into("<I", hdr, 0, lvm_crc(hdr[4:]))
+    img("lvm2.img", 2 * MiB, [(512, lab), (mda, hdr), (mda + 512, text)])
+
+
+def md12():
+    # Member of a RAID1, superblock 1.2 at 4K
+    sb = bytearray(256 + 2 * 2)
+    struct.pack_into("<III", sb, 0, 0xA92B4EFC, 1, 0)
+    sb[16:32] = U.bytes
+    sb[32:41] = b"host:root"
+    struct.pack_into("<iIQII", sb, 72, 1, 0, 2040, 0, 2)
+    struct.pack_into("<QQQ", sb, 128, 8, 2040, 8)
+    sb[168:184] = SUB.bytes
+    struct.pack_into("<Q", sb, 200, 5)
+    struct.pack_into("<I", sb, 220, 2)
+    struct.pack_into("<HH", sb, 256, 0, 1)
+    struct.pack_into("<I", sb, 216, md_csum(sb))
+    img("md12.img", 2 * MiB, [(4096, sb)])
+
+
+def md090():
+    # Member of a RAID1, superblock 0.90 in the last 64K aligned block
+    sb = bytearray(4096)
+    w = list(struct.unpack("<4I", U.bytes))
+    struct.pack_into("<16I", sb, 0, 0xA92B4EFC, 0, 90, 0, 0, w[0], 0, 1, 960, 2, 2, 0, 0, w[1], w[2], w[3])
+    struct.pack_into("<II", sb, 39 * 4, 0, 5)
+    # This member: number, major, minor, raid_disk, state (active, in sync)
+    struct.pack_into("<5I", sb, 992 * 4, 0, 0, 0, 0, 6)
+    struct.pack_into("<I", sb, 38 * 4, md_csum(sb))
+    img("md090.img", 2 * MiB, [(2 * MiB - 0x10000, sb)])
+
+
+def mbr_entry(ptype, start, size):
+    return struct.pack("<B3sB3sII", 0, b"\0" * 3, ptype, b"\0" * 3, start, size)
+
+
+def gpt():
+    # 1M disk of 512 byte sectors: protective MBR, GPT with the entries 1 and 3 used and its backup at the end
+    last = MiB // 512 - 1
+    mbr = bytearray(512)
+    mbr[446:462] = mbr_entry(0xEE, 1, last)
+    mbr[510:512] = b"\x55\xaa"
+
+    linux = uuid.UUID("0fc63daf-8483-4772-8e79-3d69d8477de4")
+    entries = bytearray(128 * 128)
+    for i, puuid, first, name in [(0, U, 34, "root"), (2, SUB, 1024, "Données \U0001F680")]:
+        struct.pack_into("<16s16sQQQ", entries, i * 128, linux.bytes_le, puuid.bytes_le, first, first + 511, 0)
+        label = name.encode("utf-16-le")
+        entries[i * 128 + 56 : i * 128 + 56 + len(label)] = label
+
+    def hdr(lba, alt, entries_lba):
+        h = bytearray(92)
+        h[0:8] = b"EFI PART"
+        struct.pack_into("<IIIIQQQQ16sQIII", h, 8, 0x10000, 92, 0, 0, lba, alt, 34, last - 33,
+                         uuid.UUID("00112233-4455-6677-8899-aabbccddeeff").bytes_le, entries_lba, 128, 128, zlib.crc32(entries))
+        struct.pack_into("<I", h, 16, zlib.crc32(h))
+        return h
+
+    img("gpt.img", MiB, [(0, mbr), (512, hdr(1, last, 2)), (1024, entries),
+                         ((last - 32) * 512, entries), (last * 512, hdr(last, 1, last - 32))])
+
+
+def dos():
+    # 1M disk of 512 byte sectors: a primary partition 1 and an extended one with the logical partitions 5 and 6
+    mbr = bytearray(512)
+    struct.pack_into("<I", mbr, 440, 0x1234ABCD)
+    mbr[446:462] = mbr_entry(0x83, 1, 1023)
+    mbr[462:478] = mbr_entry(0x05, 1024, 1024)
+    mbr[510:512] = b"\x55\xaa"
+
+    # Each EBR has its logical partition, relative to the EBR, and a link to the next EBR, relative to the extended one
+    ebr5, ebr6 = bytearray(512), bytearray(512)
+    ebr5[446:462] = mbr_entry(0x83, 1, 511)
+    ebr5[462:478] = mbr_entry(0x05, 512, 512)
+    ebr6[446:462] = mbr_entry(0x83, 1, 511)
+    ebr5[510:512] = ebr6[510:512] = b"\x55\xaa"
+    img("dos.img", MiB, [(0, mbr), (1024 * 512, ebr5), (1536 * 512, ebr6)])
+
+
+def mkfs():
+    for fs, label in [("ext2", ""), ("ext3", "data3"), ("ext4", "root4")]:
+        img("%s.img" % fs, 4 * MiB, [])
+        subprocess.run(["mke2fs", "-q", "-F", "-t", fs, "-U", str(U), "-L", label, "-E", "nodiscard", "%s.img" % fs], check=True)
+    img("swap.img", MiB, [])
+    subprocess.run(["mkswap", "-q", "-U", str(U), "-L", "swaplbl", "swap.img"], check=True)
+
+
+if __name__ == "__main__":
+    os.chdir(os.path.dirname(os.path.abspath(__file__)))
+    os.environ["E2FSPROGS_FAKE_TIME"] = "1700000000"
+    mkfs()
+    fat16("fat16.img", 0x29)
+    fat16("fat16-noserial.img", 0)
+    fat32()
+    xfs()
+    btrfs()
+    f2fs()
+    erofs()
+    squashfs()
+    luks2()
+    lvm2()
+    md12()
+    md090()
+    gpt()
+    dos()
+
+    for name in sorted(n for n in os.listdir(".") if n.endswith(".img")):
+        out = subprocess.run(["blkid", "-p", "-o", "export", name], capture_output=True, text=True).stdout
+        with open(name[:-4] + ".blkid", "w") as f:
+            f.write("".join(l + "\n" for l in out.splitlines() if not l.startswith("DEVNAME=")))
+        subprocess.run(["zstd", "-q", "-f", "-19", "--rm", name], check=True)